@@ -0,0 +1,25 @@
+//! Lining GTFS data up against what's already mapped in OSM, which is most of the manual work
+//! in keeping Sydney's public transport mapping current.
+
+use std::collections::{BTreeMap, HashMap};
+use crate::gtfs::gtfs_types::GtfsID;
+use crate::osm::osm_change::OsmChange;
+use crate::osm::osm_elements::OsmElementType;
+
+pub mod stops;
+pub mod stop_changes;
+pub mod routes;
+pub mod route_validation;
+pub mod shape_matching;
+
+/// Changes we'd like to make to OSM, along with why each one was made
+#[derive(Debug, Clone, Default)]
+pub struct ProposedChanges {
+    pub change: OsmChange,
+    /// A sentence or two on the GTFS evidence for each changed element, for reviewers
+    pub evidence: BTreeMap<(OsmElementType, i64), String>,
+    /// The pieces each way we've split is now in, in order along the original way
+    pub split_ways: BTreeMap<i64, Vec<i64>>,
+    /// The placeholder ID of the node created for each GTFS stop that wasn't in OSM
+    pub created_stops: HashMap<GtfsID, i64>,
+}
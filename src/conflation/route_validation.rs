@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use geo::{Centroid, HaversineDistance};
+use geo_types::Point;
+use serde::Serialize;
+use crate::conflation::routes::{trip_patterns, TripPattern};
+use crate::conflation::stops::StopConflation;
+use crate::gtfs::gtfs_feed::GtfsFeed;
+use crate::gtfs::gtfs_filter::GtfsLocated;
+use crate::gtfs::gtfs_types::GtfsID;
+use crate::osm::osm_elements::{OsmData, OsmElementType, OsmMember, OsmRelation};
+use crate::target_area::TargetArea;
+
+/// A platform in a route relation that isn't on the GTFS pattern it was compared against
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExtraPlatform {
+    pub kind: OsmElementType,
+    pub id: i64,
+    /// The GTFS stop the platform was matched to, if any
+    pub stop_id: Option<GtfsID>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "issue", rename_all = "snake_case")]
+pub enum RouteIssue {
+    /// No GTFS bus route has the relation's ref any more
+    NotInGtfs,
+    /// Stops on the GTFS pattern that the relation has no platform for
+    MissingStops { stop_ids: Vec<GtfsID> },
+    ExtraStops { platforms: Vec<ExtraPlatform> },
+    /// The stops both have in common, in the order each has them
+    WrongOrder { expected: Vec<GtfsID>, actual: Vec<GtfsID> },
+}
+
+/// How one OSM route relation compares to GTFS
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteValidation {
+    pub relation_id: i64,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub name: Option<String>,
+    /// The GTFS route the relation was compared against, and a trip running the closest pattern
+    pub route_id: Option<GtfsID>,
+    pub trip_id: Option<GtfsID>,
+    pub issues: Vec<RouteIssue>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RouteValidationReport {
+    pub routes: Vec<RouteValidation>,
+}
+
+impl RouteValidationReport {
+    /// The relations that have at least one issue
+    pub fn stale(&self) -> impl Iterator<Item=&RouteValidation> {
+        self.routes.iter().filter(|route| !route.issues.is_empty())
+    }
+}
+
+fn join_ids<T: Display>(ids: &[T]) -> String {
+    ids.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
+impl Display for RouteValidationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Checked {} bus route relations; {} don't match GTFS", self.routes.len(), self.stale().count())?;
+
+        for route in self.stale() {
+            write!(f, "relation {} ({}", route.relation_id, route.reference)?;
+            if let Some(name) = &route.name {
+                write!(f, ", {name}")?;
+            }
+            write!(f, ")")?;
+            if let (Some(route_id), Some(trip_id)) = (&route.route_id, &route.trip_id) {
+                write!(f, " compared to trip {trip_id} of route {route_id}")?;
+            }
+            writeln!(f)?;
+
+            for issue in &route.issues {
+                match issue {
+                    RouteIssue::NotInGtfs => writeln!(f, "  no longer in GTFS")?,
+                    RouteIssue::MissingStops { stop_ids } => writeln!(f, "  missing stops: {}", join_ids(stop_ids))?,
+                    RouteIssue::ExtraStops { platforms } => {
+                        let platforms: Vec<String> = platforms.iter()
+                            .map(|platform| match &platform.stop_id {
+                                Some(stop_id) => format!("{}/{} (stop {stop_id})", platform.kind, platform.id),
+                                None => format!("{}/{}", platform.kind, platform.id),
+                            })
+                            .collect();
+                        writeln!(f, "  extra platforms: {}", platforms.join(", "))?
+                    }
+                    RouteIssue::WrongOrder { expected, actual } =>
+                        writeln!(f, "  wrong order: expected {}, but found {}", join_ids(expected), join_ids(actual))?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How a relation's platforms differ from one GTFS pattern
+fn compare_pattern(platforms: &[(OsmElementType, i64, Option<&GtfsID>)], pattern: &TripPattern) -> Vec<RouteIssue> {
+    let pattern_stops: HashSet<&GtfsID> = pattern.stop_ids.iter().collect();
+    let relation_stops: HashSet<&GtfsID> = platforms.iter().filter_map(|(_, _, stop_id)| *stop_id).collect();
+
+    let mut issues = Vec::new();
+
+    let missing: Vec<GtfsID> = pattern.stop_ids.iter()
+        .filter(|stop_id| !relation_stops.contains(stop_id))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        issues.push(RouteIssue::MissingStops { stop_ids: missing });
+    }
+
+    let extra: Vec<ExtraPlatform> = platforms.iter()
+        .filter(|(_, _, stop_id)| stop_id.is_none_or(|stop_id| !pattern_stops.contains(stop_id)))
+        .map(|(kind, id, stop_id)| ExtraPlatform { kind: *kind, id: *id, stop_id: stop_id.cloned() })
+        .collect();
+    if !extra.is_empty() {
+        issues.push(RouteIssue::ExtraStops { platforms: extra });
+    }
+
+    let expected: Vec<GtfsID> = pattern.stop_ids.iter()
+        .filter(|stop_id| relation_stops.contains(stop_id))
+        .cloned()
+        .collect();
+    let actual: Vec<GtfsID> = platforms.iter()
+        .filter_map(|(_, _, stop_id)| stop_id.filter(|stop_id| pattern_stops.contains(stop_id)))
+        .cloned()
+        .collect();
+    if expected != actual {
+        issues.push(RouteIssue::WrongOrder { expected, actual });
+    }
+
+    issues
+}
+
+/// A rough measure of how far off a comparison is, for picking the closest pattern
+fn issue_count(issues: &[RouteIssue]) -> usize {
+    issues.iter()
+        .map(|issue| match issue {
+            RouteIssue::NotInGtfs | RouteIssue::WrongOrder { .. } => 1,
+            RouteIssue::MissingStops { stop_ids } => stop_ids.len(),
+            RouteIssue::ExtraStops { platforms } => platforms.len(),
+        })
+        .sum()
+}
+
+/// Whether we can see that `member` is inside `area`; members we didn't fetch are assumed to be
+/// outside it
+fn member_in_area(member: &OsmMember, osm: &OsmData, area: &TargetArea) -> bool {
+    match member.kind {
+        OsmElementType::Node => osm.node(member.reference).is_some_and(|node| area.contains(&node.point())),
+        OsmElementType::Way => osm.way(member.reference).is_some_and(|way| {
+            way.nodes.iter().filter_map(|id| osm.node(*id)).any(|node| area.contains(&node.point()))
+        }),
+        OsmElementType::Relation => false,
+    }
+}
+
+/// How far the middle of a platform mapped as a way can be from a GTFS stop, in metres, for it to
+/// count as that stop
+const MAX_PLATFORM_WAY_DISTANCE: f64 = 30.0;
+
+/// Ties relation members to the GTFS stops they stand for
+struct MemberStops<'a> {
+    /// Nodes `conflation` matched to a stop
+    by_node: HashMap<i64, &'a GtfsID>,
+    /// Every stop `conflation` looked at, for platforms mapped as ways, which it doesn't match
+    stops: Vec<(&'a GtfsID, Point<f64>)>,
+}
+
+impl<'a> MemberStops<'a> {
+    fn new(conflation: &StopConflation<'a>) -> MemberStops<'a> {
+        let by_node = conflation.matched.iter()
+            .map(|stop_match| (stop_match.node.id, &stop_match.stop.stop_id))
+            .collect();
+        let stops = conflation.matched.iter().map(|stop_match| stop_match.stop)
+            .chain(conflation.gtfs_only.iter().copied())
+            .chain(conflation.ambiguous.iter().map(|ambiguous| ambiguous.stop))
+            .filter_map(|stop| Some((&stop.stop_id, stop.location()?)))
+            .collect();
+
+        MemberStops { by_node, stops }
+    }
+
+    fn stop_for(&self, member: &OsmMember, osm: &OsmData) -> Option<&'a GtfsID> {
+        match member.kind {
+            OsmElementType::Node => self.by_node.get(&member.reference).copied(),
+            OsmElementType::Way => {
+                let middle = osm.way_line(member.reference)?.centroid()?;
+                self.stops.iter()
+                    .map(|(stop_id, location)| (*stop_id, middle.haversine_distance(location)))
+                    .filter(|(_, distance)| *distance <= MAX_PLATFORM_WAY_DISTANCE)
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|(stop_id, _)| stop_id)
+            }
+            OsmElementType::Relation => None,
+        }
+    }
+}
+
+fn validate_relation(relation: &OsmRelation, reference: &str, patterns: &[(&GtfsID, TripPattern)], member_stops: &MemberStops, osm: &OsmData, area: &TargetArea) -> Option<RouteValidation> {
+    // Relations mapped with only stop positions are compared by those instead
+    let role = match relation.members.iter().any(|member| member.role.starts_with("platform")) {
+        true => "platform",
+        false => "stop",
+    };
+    let platforms: Vec<(OsmElementType, i64, Option<&GtfsID>)> = relation.members.iter()
+        .filter(|member| member.role.starts_with(role) && member_in_area(member, osm, area))
+        .map(|member| (member.kind, member.reference, member_stops.stop_for(member, osm)))
+        .collect();
+
+    let mut validation = RouteValidation {
+        relation_id: relation.id,
+        reference: reference.to_string(),
+        name: relation.tags.get("name").cloned(),
+        route_id: None,
+        trip_id: None,
+        issues: Vec::new(),
+    };
+    if patterns.is_empty() {
+        // A relation that only passes through the area has nothing here to compare
+        if platforms.is_empty() {
+            return None;
+        }
+
+        validation.issues.push(RouteIssue::NotInGtfs);
+        return Some(validation);
+    }
+
+    // The relation is probably meant to be whichever pattern it's closest to
+    let (route_id, pattern, issues) = patterns.iter()
+        .map(|(route_id, pattern)| (route_id, pattern, compare_pattern(&platforms, pattern)))
+        .min_by_key(|(_, _, issues)| issue_count(issues))
+        .expect("patterns isn't empty");
+
+    validation.route_id = Some((*route_id).clone());
+    validation.trip_id = pattern.trip_ids.first().cloned();
+    validation.issues = issues;
+    Some(validation)
+}
+
+/// Checks every `route=bus` relation in `osm` against the GTFS trips of the bus routes with the
+/// same `ref`, to find relations left stale by network changes.
+///
+/// Only the part of each route inside `area` is compared, since that's all `osm` and
+/// `conflation` cover; stops and platforms outside it are left out on both sides. Platform nodes
+/// are tied to GTFS stops by `conflation`, and platform ways to the nearest stop, so a platform
+/// that doesn't match any stop is reported as extra. Relations without platforms are compared by
+/// their stop positions instead. Each relation is compared to the trip pattern it's closest to,
+/// as a relation only covers one direction or variant of a route. Relations without a `ref`, and
+/// ones GTFS doesn't run inside the area that have no platforms there either, are skipped.
+pub fn validate_route_relations(feed: &GtfsFeed, osm: &OsmData, conflation: &StopConflation, area: &TargetArea) -> RouteValidationReport {
+    let member_stops = MemberStops::new(conflation);
+
+    let mut patterns_by_ref: HashMap<&str, Vec<(&GtfsID, TripPattern)>> = HashMap::new();
+    let mut routes: Vec<_> = feed.routes.values().filter(|route| route.route_type.is_bus()).collect();
+    routes.sort_by(|a, b| a.route_id.0.cmp(&b.route_id.0));
+    for route in routes {
+        let Some(short_name) = &route.route_short_name else { continue };
+        let patterns = trip_patterns(feed, &route.route_id).into_iter()
+            .map(|mut pattern| {
+                pattern.stop_ids.retain(|stop_id| feed.stop(stop_id).and_then(|stop| stop.location()).is_some_and(|location| area.contains(&location)));
+                pattern
+            })
+            .filter(|pattern| !pattern.stop_ids.is_empty());
+        patterns_by_ref.entry(short_name.as_str())
+            .or_default()
+            .extend(patterns.map(|pattern| (&route.route_id, pattern)));
+    }
+
+    let routes = osm.relations.values()
+        .filter(|relation| relation.tags.get("type").map(String::as_str) == Some("route")
+            && relation.tags.get("route").map(String::as_str) == Some("bus"))
+        .filter_map(|relation| {
+            let reference = relation.tags.get("ref")?;
+            let patterns = patterns_by_ref.get(reference.as_str()).map(Vec::as_slice).unwrap_or_default();
+            validate_relation(relation, reference, patterns, &member_stops, osm, area)
+        })
+        .collect();
+
+    RouteValidationReport { routes }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conflation::route_validation::{validate_route_relations, ExtraPlatform, RouteIssue};
+    use crate::conflation::stops::{conflate_stops, StopConflationOptions};
+    use crate::gtfs::gtfs_feed::GtfsFeed;
+    use crate::gtfs::gtfs_feed::tests::{archive_from, TEST_FILES};
+    use geo_types::{coord, MultiPolygon, Rect};
+    use crate::gtfs::gtfs_types::GtfsID;
+    use crate::osm::osm_elements::{OsmElementType, OsmMember, OsmMeta, OsmNode, OsmWay};
+    use crate::osm::osm_xml::parse_osm_xml;
+    use crate::target_area::TargetArea;
+
+    fn area(name: &str, min: (f64, f64), max: (f64, f64)) -> TargetArea {
+        TargetArea {
+            name: name.to_string(),
+            area: MultiPolygon::new(vec![Rect::new(coord! { x: min.0, y: min.1 }, coord! { x: max.0, y: max.1 }).to_polygon()]),
+        }
+    }
+
+    #[test]
+    fn test_validate_route_relations() {
+        let mut files = TEST_FILES.to_vec();
+        files[4] = ("stop_times.txt", "trip_id,stop_id,stop_sequence\n\
+            trip_a,2000322,1\ntrip_a,2000323,2\ntrip_a,2010100,3\n\
+            trip_b,2000323,1");
+        let feed = GtfsFeed::from_zip(&mut archive_from(&files)).unwrap();
+
+        let mut osm = parse_osm_xml(include_str!("../../fixtures/osm/central.osm").as_bytes()).unwrap();
+        let platform = |reference| OsmMember { kind: OsmElementType::Node, reference, role: "platform".to_string() };
+
+        // The same route mapped backwards and through a stop nobody's matched
+        let mut backwards = osm.relations[&6000001].clone();
+        backwards.id = 6000002;
+        backwards.members = vec![platform(4000002), platform(4000003), platform(4000001)];
+        osm.relations.insert(backwards.id, backwards);
+
+        // A route TfNSW has since dropped
+        let mut dropped = osm.relations[&6000001].clone();
+        dropped.id = 6000003;
+        dropped.tags.insert("ref".to_string(), "M31".to_string());
+        osm.relations.insert(dropped.id, dropped);
+
+        let conflation = conflate_stops(feed.stops.values(), &osm, &StopConflationOptions::default());
+        let report = validate_route_relations(&feed, &osm, &conflation, &area("Inner Sydney", (151.19, -33.90), (151.22, -33.87)));
+        assert_eq!(report.routes.len(), 3);
+
+        let id = |id: &str| GtfsID(id.to_string());
+        let m30 = &report.routes[0];
+        assert_eq!(m30.route_id, Some(id("2441_M30")));
+        assert_eq!(m30.trip_id, Some(id("trip_a")));
+        assert_eq!(m30.issues, vec![RouteIssue::MissingStops { stop_ids: vec![id("2010100")] }]);
+
+        assert_eq!(report.routes[1].issues, vec![
+            RouteIssue::MissingStops { stop_ids: vec![id("2010100")] },
+            RouteIssue::ExtraStops { platforms: vec![ExtraPlatform { kind: OsmElementType::Node, id: 4000003, stop_id: None }] },
+            RouteIssue::WrongOrder { expected: vec![id("2000322"), id("2000323")], actual: vec![id("2000323"), id("2000322")] },
+        ]);
+        assert_eq!(report.routes[2].issues, vec![RouteIssue::NotInGtfs]);
+
+        let text = report.to_string();
+        assert!(text.starts_with("Checked 3 bus route relations; 3 don't match GTFS\n"));
+        assert!(text.contains("  extra platforms: node/4000003\n"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["routes"][0]["ref"], "M30");
+        assert_eq!(json["routes"][1]["issues"][1]["platforms"][0]["kind"], "node");
+        assert_eq!(json["routes"][2]["issues"][0]["issue"], "not_in_gtfs");
+    }
+
+    #[test]
+    fn test_validate_inside_area() {
+        let mut files = TEST_FILES.to_vec();
+        files[4] = ("stop_times.txt", "trip_id,stop_id,stop_sequence\n\
+            trip_a,2000322,1\ntrip_a,2000323,2\ntrip_a,2010100,3\n\
+            trip_b,2000323,1");
+        let feed = GtfsFeed::from_zip(&mut archive_from(&files)).unwrap();
+
+        let mut osm = parse_osm_xml(include_str!("../../fixtures/osm/central.osm").as_bytes()).unwrap();
+        // A platform further along the route, which wasn't fetched along with Central
+        osm.relations.get_mut(&6000001).unwrap().members.push(OsmMember { kind: OsmElementType::Node, reference: 4000099, role: "platform".to_string() });
+
+        // Redfern is outside Central, so it's not missing, and the platform we can't see isn't extra
+        let central = area("Central", (151.2055, -33.8845), (151.2075, -33.8830));
+        let conflation = conflate_stops(feed.stops.values().filter(|stop| stop.stop_id.0 != "2010100"), &osm, &StopConflationOptions::default());
+        let report = validate_route_relations(&feed, &osm, &conflation, &central);
+        assert_eq!(report.routes.len(), 1);
+        assert!(report.routes[0].issues.is_empty());
+
+        // Nowhere near any of the route's stops or platforms
+        let botany = area("Botany", (151.19, -33.96), (151.21, -33.94));
+        assert!(validate_route_relations(&feed, &osm, &conflation, &botany).routes.is_empty());
+    }
+
+    #[test]
+    fn test_platform_ways_and_stop_positions() {
+        let mut files = TEST_FILES.to_vec();
+        files[4] = ("stop_times.txt", "trip_id,stop_id,stop_sequence\n\
+            trip_a,2000322,1\ntrip_a,2000323,2\ntrip_a,2010100,3\n\
+            trip_b,2000323,1");
+        let feed = GtfsFeed::from_zip(&mut archive_from(&files)).unwrap();
+
+        let mut osm = parse_osm_xml(include_str!("../../fixtures/osm/central.osm").as_bytes()).unwrap();
+        let member = |kind, reference, role: &str| OsmMember { kind, reference, role: role.to_string() };
+
+        // Stand B's platform drawn as a small area around the stop
+        for (id, lat, lon) in [(4000011, -33.88385, 151.20655), (4000012, -33.88385, 151.20665), (4000013, -33.88375, 151.20665), (4000014, -33.88375, 151.20655)] {
+            osm.nodes.insert(id, OsmNode { id, lat, lon, tags: Default::default(), meta: OsmMeta::default() });
+        }
+        let tags = [("public_transport".to_string(), "platform".to_string())].into_iter().collect();
+        osm.ways.insert(5000010, OsmWay { id: 5000010, nodes: vec![4000011, 4000012, 4000013, 4000014, 4000011], tags, meta: OsmMeta::default() });
+        osm.relations.get_mut(&6000001).unwrap().members = vec![member(OsmElementType::Node, 4000001, "platform"), member(OsmElementType::Way, 5000010, "platform")];
+
+        // The same route mapped with only its stop positions
+        let mut stops_only = osm.relations[&6000001].clone();
+        stops_only.id = 6000002;
+        stops_only.members = vec![member(OsmElementType::Node, 4000001, "stop"), member(OsmElementType::Node, 4000002, "stop_exit_only")];
+        osm.relations.insert(stops_only.id, stops_only);
+
+        let central = area("Central", (151.2055, -33.8845), (151.2075, -33.8830));
+        let conflation = conflate_stops(feed.stops.values().filter(|stop| stop.stop_id.0 != "2010100"), &osm, &StopConflationOptions::default());
+        let report = validate_route_relations(&feed, &osm, &conflation, &central);
+
+        assert_eq!(report.routes.len(), 2);
+        assert!(report.routes[0].issues.is_empty(), "{:?}", report.routes[0].issues);
+        assert!(report.routes[1].issues.is_empty(), "{:?}", report.routes[1].issues);
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::{BTreeSet, HashMap};
+use anyhow::anyhow;
+use crate::conflation::ProposedChanges;
+use crate::conflation::shape_matching::{apply_shape_match, match_shape, ShapeMatchOptions};
+use crate::conflation::stop_changes::stop_operators;
+use crate::conflation::stops::{OsmStopKind, StopConflation};
+use crate::gtfs::gtfs_feed::GtfsFeed;
+use crate::gtfs::gtfs_schedule::{GtfsDirection, GtfsScheduleRoute};
+use crate::gtfs::gtfs_types::GtfsID;
+use crate::osm::osm_elements::{OsmData, OsmElementType, OsmMember, OsmMeta, OsmRelation, OsmTags};
+
+#[derive(Debug, Clone)]
+pub struct RouteRelationOptions {
+    pub network: Option<String>,
+    /// Patterns other than the most common one in each direction are only kept if at least this
+    /// share of that direction's trips run them, so that one-off short workings are left out
+    pub min_pattern_share: f64,
+    pub shape_matching: ShapeMatchOptions,
+    /// How confident a shape's match onto OSM roads has to be for its ways to be used
+    pub min_shape_confidence: f64,
+}
+
+impl Default for RouteRelationOptions {
+    fn default() -> Self {
+        RouteRelationOptions { network: None, min_pattern_share: 0.3, shape_matching: ShapeMatchOptions::default(), min_shape_confidence: 0.8 }
+    }
+}
+
+/// What [propose_route_relations] made of a route
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteProposal {
+    /// New relations, grouped by the route master with this placeholder ID
+    Proposed(i64),
+    /// These route relations are already in OSM, so nothing new was proposed
+    AlreadyMapped(Vec<i64>),
+    /// The route's mode isn't one we map, or it has no trips
+    Skipped,
+    /// None of the route's patterns had a stop or way in OSM to point at, so there was nothing to
+    /// make relations of
+    NothingInOsm,
+}
+
+/// A distinct sequence of stops that some of a route's trips run, in one direction
+#[derive(Debug, Clone, PartialEq)]
+pub struct TripPattern {
+    pub direction: Option<GtfsDirection>,
+    pub stop_ids: Vec<GtfsID>,
+    /// Every trip that runs this pattern, sorted
+    pub trip_ids: Vec<GtfsID>,
+    /// The shape of the pattern's first trip
+    pub shape_id: Option<GtfsID>,
+}
+
+/// Every stop pattern run by trips on `route_id`, most common first
+pub fn trip_patterns(feed: &GtfsFeed, route_id: &GtfsID) -> Vec<TripPattern> {
+    let mut patterns: HashMap<(Option<u8>, Vec<GtfsID>), TripPattern> = HashMap::new();
+    for trip in feed.trips_for_route(route_id) {
+        let stop_ids: Vec<GtfsID> = feed.stop_times_for_trip(&trip.trip_id)
+            .filter_map(|stop_time| stop_time.stop_id.clone())
+            .collect();
+        if stop_ids.len() < 2 {
+            continue;
+        }
+
+        patterns.entry((trip.direction_id.map(|direction| direction as u8), stop_ids.clone()))
+            .or_insert_with(|| TripPattern { direction: trip.direction_id, stop_ids, trip_ids: Vec::new(), shape_id: None })
+            .trip_ids
+            .push(trip.trip_id.clone());
+    }
+
+    let mut patterns: Vec<TripPattern> = patterns.into_values().collect();
+    for pattern in &mut patterns {
+        pattern.trip_ids.sort_by(|a, b| a.0.cmp(&b.0));
+        pattern.shape_id = feed.trips.get(&pattern.trip_ids[0]).and_then(|trip| trip.shape_id.clone());
+    }
+    patterns.sort_by(|a, b| b.trip_ids.len().cmp(&a.trip_ids.len()).then_with(|| a.trip_ids[0].0.cmp(&b.trip_ids[0].0)));
+
+    patterns
+}
+
+/// The patterns worth mapping: the most common in each direction, and any others that enough
+/// trips run. `patterns` should be most common first, as [trip_patterns] gives them.
+pub fn representative_patterns(patterns: &[TripPattern], min_share: f64) -> Vec<&TripPattern> {
+    let mut trips_by_direction: HashMap<Option<u8>, usize> = HashMap::new();
+    for pattern in patterns {
+        *trips_by_direction.entry(pattern.direction.map(|direction| direction as u8)).or_default() += pattern.trip_ids.len();
+    }
+
+    let mut seen_directions = BTreeSet::new();
+    patterns.iter()
+        .filter(|pattern| {
+            let direction = pattern.direction.map(|direction| direction as u8);
+            let share = pattern.trip_ids.len() as f64 / trips_by_direction[&direction] as f64;
+            seen_directions.insert(direction) || share >= min_share
+        })
+        .collect()
+}
+
+/// "Bus", "Light Rail" and so on, for relation names
+fn mode_name(route: &str) -> String {
+    route.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map_or(String::new(), |first| first.to_uppercase().chain(chars).collect())
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Tags shared by a route's relations and its route master
+fn common_route_tags(feed: &GtfsFeed, route: &GtfsScheduleRoute, options: &RouteRelationOptions) -> OsmTags {
+    let mut tags = OsmTags::new();
+    if let Some(short_name) = &route.route_short_name {
+        tags.insert("ref".to_string(), short_name.clone());
+    }
+    if let Some(network) = &options.network {
+        tags.insert("network".to_string(), network.clone());
+    }
+    if let Some(colour) = &route.route_colour {
+        tags.insert("colour".to_string(), format!("#{colour}"));
+    }
+
+    // Agency IDs are optional when there's only one agency, so fall back to whoever runs the
+    // route's first stop
+    let operator = match &route.agency_id {
+        Some(agency_id) => feed.agencies.iter()
+            .find(|agency| agency.agency_id.as_ref() == Some(agency_id))
+            .map(|agency| agency.agency_name.clone()),
+        None => feed.trips_for_route(&route.route_id)
+            .flat_map(|trip| feed.stop_times_for_trip(&trip.trip_id).take(1))
+            .filter_map(|stop_time| stop_time.stop_id.as_ref())
+            .map(|stop_id| stop_operators(feed, stop_id))
+            .find(|operators| operators.len() == 1)
+            .and_then(|operators| operators.into_iter().next().map(str::to_string)),
+    };
+    if let Some(operator) = operator {
+        tags.insert("operator".to_string(), operator);
+    }
+
+    tags
+}
+
+fn stop_name(feed: &GtfsFeed, stop_id: &GtfsID) -> String {
+    feed.stop(stop_id)
+        .and_then(|stop| stop.stop_name.clone())
+        .unwrap_or_else(|| stop_id.to_string())
+}
+
+/// Route relations in `osm` for the same mode and `ref`, and the same network if both say which
+/// they're in
+fn existing_route_relations(osm: &OsmData, osm_route: &str, reference: &str, network: Option<&str>) -> Vec<i64> {
+    osm.relations.values()
+        .filter(|relation| {
+            let tag = |key: &str| relation.tags.get(key).map(String::as_str);
+            tag("type") == Some("route") && tag("route") == Some(osm_route) && tag("ref") == Some(reference)
+                && network.zip(tag("network")).is_none_or(|(ours, theirs)| ours == theirs)
+        })
+        .map(|relation| relation.id)
+        .collect()
+}
+
+/// Proposes Public Transport v2 relations for `route_id`: a `type=route` relation for each of
+/// its representative trip patterns, and a `route_master` to group them. Nothing is proposed if
+/// `osm` already has a route relation with the same `ref`, since it'd just be a duplicate.
+///
+/// Route relations list the OSM platforms (or stop positions) matched to each stop in order,
+/// or the nodes `changes` creates for stops that aren't mapped yet. Any other stops are left out
+/// and called out in the evidence. If the pattern has a shape that can be followed along the
+/// roads in `osm`, its ways come after the stops, with any ways it only uses part of split.
+/// Patterns that end up with no members at all are left out, and so is the route master if
+/// that's all of them.
+pub fn propose_route_relations(
+    feed: &GtfsFeed,
+    route_id: &GtfsID,
+    conflation: &StopConflation,
+    osm: &OsmData,
+    options: &RouteRelationOptions,
+    changes: &mut ProposedChanges,
+) -> anyhow::Result<RouteProposal> {
+    let route = feed.route(route_id).ok_or_else(|| anyhow!("Route {route_id} isn't in the feed"))?;
+    let Some(osm_route) = route.route_type.osm_route() else { return Ok(RouteProposal::Skipped) };
+
+    let reference = route.route_short_name.as_deref().unwrap_or(route_id.as_ref());
+    let existing = existing_route_relations(osm, osm_route, reference, options.network.as_deref());
+    if !existing.is_empty() {
+        return Ok(RouteProposal::AlreadyMapped(existing));
+    }
+
+    let patterns = trip_patterns(feed, route_id);
+    let representative = representative_patterns(&patterns, options.min_pattern_share);
+    if representative.is_empty() {
+        return Ok(RouteProposal::Skipped);
+    }
+
+    let common_tags = common_route_tags(feed, route, options);
+    let total_trips: usize = patterns.iter().map(|pattern| pattern.trip_ids.len()).sum();
+
+    let mut relation_ids = Vec::new();
+    let mut left_out = Vec::new();
+    for pattern in representative {
+        let (from, to) = (stop_name(feed, &pattern.stop_ids[0]), stop_name(feed, &pattern.stop_ids[pattern.stop_ids.len() - 1]));
+
+        let mut members = Vec::new();
+        let mut unmatched = Vec::new();
+        for stop_id in &pattern.stop_ids {
+            let stop_match = conflation.matched.iter().find(|stop_match| &stop_match.stop.stop_id == stop_id);
+            match (stop_match, changes.created_stops.get(stop_id)) {
+                (Some(stop_match), _) => members.push(OsmMember {
+                    kind: OsmElementType::Node,
+                    reference: stop_match.node.id,
+                    role: match stop_match.kind {
+                        OsmStopKind::StopPosition => "stop",
+                        _ => "platform",
+                    }.to_string(),
+                }),
+                (None, Some(created)) => members.push(OsmMember { kind: OsmElementType::Node, reference: *created, role: "platform".to_string() }),
+                (None, None) => unmatched.push(format!("{stop_id} ({})", stop_name(feed, stop_id))),
+            }
+        }
+
+        let shape = pattern.shape_id.as_ref().and_then(|shape_id| feed.shapes.get(shape_id));
+        let shape_match = shape.and_then(|shape| match_shape(&shape.line, osm, &options.shape_matching));
+        let shape_evidence = match (shape, &shape_match) {
+            (Some(shape), Some(shape_match)) if shape_match.confidence >= options.min_shape_confidence => {
+                let (way_members, unsplit) = apply_shape_match(shape_match, osm, changes);
+                members.extend(way_members);
+
+                let mut evidence = format!(" Ways follow shape {} ({:.0}% confidence).", shape.shape_id, shape_match.confidence * 100.0);
+                if !unsplit.is_empty() {
+                    let unsplit: Vec<String> = unsplit.iter().map(|(way, relation)| format!("{way} (in relation {relation})")).collect();
+                    evidence.push_str(&format!(" The route only uses part of ways {}, but they're used whole, as splitting them would break those relations.", unsplit.join(", ")));
+                }
+                Some(evidence)
+            }
+            (Some(shape), Some(shape_match)) =>
+                Some(format!(" Shape {} only matched OSM roads with {:.0}% confidence, so there are no ways yet.", shape.shape_id, shape_match.confidence * 100.0)),
+            (Some(shape), None) => Some(format!(" Shape {} couldn't be followed along OSM roads, so there are no ways yet.", shape.shape_id)),
+            (None, _) => None,
+        };
+
+        // An empty relation would only be clutter for someone to clean up
+        if members.is_empty() {
+            left_out.push(pattern.trip_ids[0].to_string());
+            continue;
+        }
+
+        let mut tags = common_tags.clone();
+        tags.insert("type".to_string(), "route".to_string());
+        tags.insert("route".to_string(), osm_route.to_string());
+        tags.insert("public_transport:version".to_string(), "2".to_string());
+        tags.insert("name".to_string(), format!("{} {reference}: {from} => {to}", mode_name(osm_route)));
+        tags.insert("from".to_string(), from);
+        tags.insert("to".to_string(), to);
+
+        let id = changes.change.next_placeholder_id();
+        let mut evidence = format!("Stops of {} of {total_trips} trips on GTFS route {route_id}, such as {}.",
+            pattern.trip_ids.len(), pattern.trip_ids[0]);
+        if !unmatched.is_empty() {
+            evidence.push_str(&format!(" No OSM stop for {}.", unmatched.join(", ")));
+        }
+        evidence.extend(shape_evidence);
+
+        changes.evidence.insert((OsmElementType::Relation, id), evidence);
+        changes.change.create.relations.insert(id, OsmRelation { id, members, tags, meta: OsmMeta::default() });
+        relation_ids.push(id);
+    }
+
+    if relation_ids.is_empty() {
+        return Ok(RouteProposal::NothingInOsm);
+    }
+
+    let mut tags = common_tags;
+    tags.insert("type".to_string(), "route_master".to_string());
+    tags.insert("route_master".to_string(), osm_route.to_string());
+    tags.insert("name".to_string(), format!("{} {reference}", mode_name(osm_route)));
+
+    let members = relation_ids.iter()
+        .map(|id| OsmMember { kind: OsmElementType::Relation, reference: *id, role: String::new() })
+        .collect();
+
+    let id = changes.change.next_placeholder_id();
+    let mut evidence = format!("Groups the routes generated for GTFS route {route_id}.");
+    if !left_out.is_empty() {
+        evidence.push_str(&format!(" The patterns of trips {} are left out, as none of their stops or ways are in OSM yet.", left_out.join(", ")));
+    }
+    changes.evidence.insert((OsmElementType::Relation, id), evidence);
+    changes.change.create.relations.insert(id, OsmRelation { id, members, tags, meta: OsmMeta::default() });
+
+    Ok(RouteProposal::Proposed(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conflation::ProposedChanges;
+    use crate::conflation::routes::{propose_route_relations, representative_patterns, trip_patterns, RouteProposal, RouteRelationOptions};
+    use crate::conflation::stop_changes::{propose_stop_changes, StopChangeOptions};
+    use crate::conflation::stops::{conflate_stops, StopConflationOptions};
+    use crate::gtfs::gtfs_feed::GtfsFeed;
+    use crate::gtfs::gtfs_feed::tests::{archive_from, TEST_FILES};
+    use crate::gtfs::gtfs_types::GtfsID;
+    use crate::osm::osm_elements::OsmElementType;
+    use crate::osm::osm_xml::parse_osm_xml;
+
+    /// The M30 running three full trips and a short working towards Redfern, and two back
+    fn test_feed() -> GtfsFeed {
+        let mut files = TEST_FILES.to_vec();
+        files[2] = ("routes.txt", "route_id,agency_id,route_short_name,route_long_name,route_type,route_color\n\
+            2441_M30,2441,M30,Mosman to Sydenham,700,00B5EF");
+        files[3] = ("trips.txt", "route_id,service_id,trip_id,direction_id\n\
+            2441_M30,weekday,t1,0\n\
+            2441_M30,weekday,t2,0\n\
+            2441_M30,weekday,t3,0\n\
+            2441_M30,weekday,t4,0\n\
+            2441_M30,weekday,t5,1\n\
+            2441_M30,weekday,t6,1");
+        files[4] = ("stop_times.txt", "trip_id,stop_id,stop_sequence\n\
+            t1,2000322,1\nt1,2000323,2\nt1,2010100,3\n\
+            t2,2000322,1\nt2,2000323,2\nt2,2010100,3\n\
+            t3,2000322,1\nt3,2000323,2\nt3,2010100,3\n\
+            t4,2000322,1\nt4,2000323,2\n\
+            t5,2010100,1\nt5,2000323,2\nt5,2000322,3\n\
+            t6,2010100,1\nt6,2000323,2\nt6,2000322,3");
+
+        GtfsFeed::from_zip(&mut archive_from(&files)).unwrap()
+    }
+
+    #[test]
+    fn test_representative_patterns() {
+        let feed = test_feed();
+        let patterns = trip_patterns(&feed, &GtfsID("2441_M30".to_string()));
+        assert_eq!(patterns.len(), 3);
+        assert_eq!(patterns[0].trip_ids.len(), 3);
+
+        // The short working is only a quarter of the trips towards Redfern
+        assert_eq!(representative_patterns(&patterns, 0.3).len(), 2);
+        assert_eq!(representative_patterns(&patterns, 0.25).len(), 3);
+    }
+
+    #[test]
+    fn test_propose_route_relations() {
+        let feed = test_feed();
+        let mut osm = parse_osm_xml(include_str!("../../fixtures/osm/central.osm").as_bytes()).unwrap();
+        // The fixture already has the M30 mapped
+        osm.relations.remove(&6000001);
+        let conflation = conflate_stops(feed.stops.values(), &osm, &StopConflationOptions::default());
+
+        let mut changes = ProposedChanges::default();
+        let options = RouteRelationOptions { network: Some("Sydney Buses".to_string()), ..Default::default() };
+        let master = propose_route_relations(&feed, &GtfsID("2441_M30".to_string()), &conflation, &osm, &options, &mut changes).unwrap();
+        assert_eq!(master, RouteProposal::Proposed(-3));
+
+        let outbound = &changes.change.create.relations[&-1];
+        assert_eq!(outbound.tags.get("name").map(String::as_str), Some("Bus M30: Central Station Stand A => Redfern Station"));
+        assert_eq!(outbound.tags.get("colour").map(String::as_str), Some("#00B5EF"));
+        assert_eq!(outbound.tags.get("operator").map(String::as_str), Some("Transit Systems"));
+        assert_eq!(outbound.members.iter().map(|member| member.reference).collect::<Vec<_>>(), vec![4000001, 4000002]);
+        assert!(outbound.members.iter().all(|member| member.role == "platform"));
+        assert!(changes.evidence[&(OsmElementType::Relation, -1)].ends_with("No OSM stop for 2010100 (Redfern Station)."));
+
+        let inbound = &changes.change.create.relations[&-2];
+        assert_eq!(inbound.members.iter().map(|member| member.reference).collect::<Vec<_>>(), vec![4000002, 4000001]);
+
+        let route_master = &changes.change.create.relations[&-3];
+        assert_eq!(route_master.tags.get("route_master").map(String::as_str), Some("bus"));
+        assert_eq!(route_master.tags.get("name").map(String::as_str), Some("Bus M30"));
+        assert_eq!(route_master.members.len(), 2);
+    }
+
+    #[test]
+    fn test_route_already_mapped() {
+        let feed = test_feed();
+        let osm = parse_osm_xml(include_str!("../../fixtures/osm/central.osm").as_bytes()).unwrap();
+        let conflation = conflate_stops(feed.stops.values(), &osm, &StopConflationOptions::default());
+
+        let mut changes = ProposedChanges::default();
+        let options = RouteRelationOptions { network: Some("Sydney Buses".to_string()), ..Default::default() };
+        let proposal = propose_route_relations(&feed, &GtfsID("2441_M30".to_string()), &conflation, &osm, &options, &mut changes).unwrap();
+        assert_eq!(proposal, RouteProposal::AlreadyMapped(vec![6000001]));
+        assert!(changes.change.is_empty());
+
+        // An M30 in some other network is a different route
+        let options = RouteRelationOptions { network: Some("Canberra Buses".to_string()), ..Default::default() };
+        let mut osm = osm.clone();
+        osm.relations.get_mut(&6000001).unwrap().tags.insert("network".to_string(), "Sydney Buses".to_string());
+        let proposal = propose_route_relations(&feed, &GtfsID("2441_M30".to_string()), &conflation, &osm, &options, &mut changes).unwrap();
+        assert!(matches!(proposal, RouteProposal::Proposed(_)));
+    }
+
+    #[test]
+    fn test_route_uses_created_stops() {
+        let feed = test_feed();
+        let mut osm = parse_osm_xml(include_str!("../../fixtures/osm/central.osm").as_bytes()).unwrap();
+        osm.relations.remove(&6000001);
+        let conflation = conflate_stops(feed.stops.values(), &osm, &StopConflationOptions::default());
+
+        // Redfern isn't in OSM, so it gets a new node that the relations can use straight away
+        let mut changes = propose_stop_changes(&conflation, &feed, &StopChangeOptions::default());
+        let redfern = changes.created_stops[&GtfsID("2010100".to_string())];
+
+        propose_route_relations(&feed, &GtfsID("2441_M30".to_string()), &conflation, &osm, &RouteRelationOptions::default(), &mut changes).unwrap();
+        assert_eq!(redfern, -1);
+        let outbound = &changes.change.create.relations[&-2];
+        assert_eq!(outbound.members.iter().map(|member| member.reference).collect::<Vec<_>>(), vec![4000001, 4000002, redfern]);
+        assert!(!changes.evidence[&(OsmElementType::Relation, outbound.id)].contains("No OSM stop"));
+    }
+
+    #[test]
+    fn test_route_with_nothing_in_osm() {
+        let feed = test_feed();
+        let mut osm = parse_osm_xml(include_str!("../../fixtures/osm/central.osm").as_bytes()).unwrap();
+        osm.relations.remove(&6000001);
+        // Nobody's mapped the stops yet, and there are no shapes to find ways from
+        osm.nodes.retain(|id, _| ![4000001, 4000002].contains(id));
+        let conflation = conflate_stops(feed.stops.values(), &osm, &StopConflationOptions::default());
+
+        let mut changes = ProposedChanges::default();
+        let proposal = propose_route_relations(&feed, &GtfsID("2441_M30".to_string()), &conflation, &osm, &RouteRelationOptions::default(), &mut changes).unwrap();
+        assert_eq!(proposal, RouteProposal::NothingInOsm);
+        assert!(changes.change.is_empty());
+    }
+}
@@ -0,0 +1,483 @@
+//! Following GTFS shapes along OSM roads, to give route relations their ways.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, BTreeMap, BTreeSet, HashMap};
+use geo::{Densify, EuclideanDistance, EuclideanLength};
+use geo_types::{Coord, LineString, Point};
+use crate::conflation::ProposedChanges;
+use crate::conflation::stops::METRES_PER_DEGREE;
+use crate::osm::osm_elements::{is_bus_road, OsmData, OsmElementType, OsmMember, OsmMeta, OsmRelation, OsmTags, OsmWay};
+
+/// Relation types that [update_parent_relations] can keep right when one of their ways is split.
+/// Anything else, like a turn restriction whose `from` and `to` must each be a single way, stops
+/// the way being split.
+const SPLITTABLE_PARENT_TYPES: [&str; 4] = ["route", "multipolygon", "boundary", "public_transport"];
+
+#[derive(Debug, Clone)]
+pub struct ShapeMatchOptions {
+    /// How far a shape can stray from the road it's following, in metres
+    pub max_distance: f64,
+    /// Roughly how far apart the points the shape is routed between are, in metres. Shorter is
+    /// slower, but copes better with shapes that double back on themselves.
+    pub anchor_spacing: f64,
+}
+
+impl Default for ShapeMatchOptions {
+    fn default() -> Self {
+        ShapeMatchOptions { max_distance: 25.0, anchor_spacing: 250.0 }
+    }
+}
+
+/// Part or all of a way that a shape follows
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedWay {
+    pub way_id: i64,
+    /// The way's nodes in the order the route passes them
+    pub nodes: Vec<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapeMatch {
+    /// The ways the shape follows, in order
+    pub ways: Vec<MatchedWay>,
+    /// From 0 to 1; the share of the shape that's close to the matched roads, scaled down if the
+    /// matched roads are much longer or shorter than the shape
+    pub confidence: f64,
+}
+
+/// Flattens coordinates onto a plane in metres, which is close enough over a city
+#[derive(Debug, Clone, Copy)]
+struct Projection {
+    metres_per_lon: f64,
+}
+
+impl Projection {
+    fn new(lat: f64) -> Projection {
+        Projection { metres_per_lon: METRES_PER_DEGREE * lat.to_radians().cos() }
+    }
+
+    fn project(&self, coord: Coord<f64>) -> Coord<f64> {
+        Coord { x: coord.x * self.metres_per_lon, y: coord.y * METRES_PER_DEGREE }
+    }
+}
+
+struct RoadEdge {
+    to: i64,
+    way_id: i64,
+    length: f64,
+    midpoint: Point<f64>,
+}
+
+/// One step along a way from one of its nodes to the next
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RoadStep {
+    from: i64,
+    to: i64,
+    way_id: i64,
+}
+
+/// Whether buses can go forwards and backwards along a way
+fn bus_directions(tags: &OsmTags) -> (bool, bool) {
+    let tag = |key: &str| tags.get(key).map(String::as_str);
+    if tag("oneway:bus") == Some("no") || tag("oneway:psv") == Some("no") {
+        return (true, true);
+    }
+
+    match tag("oneway") {
+        Some("yes" | "true" | "1") => (true, false),
+        Some("-1" | "reverse") => (false, true),
+        Some("no") => (true, true),
+        _ if matches!(tag("junction"), Some("roundabout" | "circular")) || tag("highway") == Some("motorway") => (true, false),
+        _ => (true, true),
+    }
+}
+
+/// The bus roads in some OSM data, with node positions in metres
+struct RoadGraph {
+    nodes: HashMap<i64, Coord<f64>>,
+    edges: HashMap<i64, Vec<RoadEdge>>,
+}
+
+impl RoadGraph {
+    fn new(osm: &OsmData, projection: Projection) -> RoadGraph {
+        let mut graph = RoadGraph { nodes: HashMap::new(), edges: HashMap::new() };
+
+        for way in osm.ways.values().filter(|way| is_bus_road(&way.tags)) {
+            let (forwards, backwards) = bus_directions(&way.tags);
+            for pair in way.nodes.windows(2) {
+                let (Some(a), Some(b)) = (osm.node(pair[0]), osm.node(pair[1])) else { continue };
+                let (a_coord, b_coord) = (projection.project(a.point().0), projection.project(b.point().0));
+                graph.nodes.insert(a.id, a_coord);
+                graph.nodes.insert(b.id, b_coord);
+
+                let length = Point::from(a_coord).euclidean_distance(&Point::from(b_coord));
+                let midpoint = Point::from((a_coord + b_coord) / 2.0);
+                if forwards {
+                    graph.edges.entry(a.id).or_default().push(RoadEdge { to: b.id, way_id: way.id, length, midpoint });
+                }
+                if backwards {
+                    graph.edges.entry(b.id).or_default().push(RoadEdge { to: a.id, way_id: way.id, length, midpoint });
+                }
+            }
+        }
+
+        graph
+    }
+
+    fn nearest_node(&self, point: Coord<f64>, max_distance: f64) -> Option<i64> {
+        self.nodes.iter()
+            .map(|(id, coord)| (*id, Point::from(*coord).euclidean_distance(&Point::from(point))))
+            .filter(|(_, distance)| *distance <= max_distance)
+            .min_by(|(a_id, a), (b_id, b)| a.total_cmp(b).then(a_id.cmp(b_id)))
+            .map(|(id, _)| id)
+    }
+
+    /// The cheapest way from `from` to `to`, where roads cost more the further they are from
+    /// `guide`, and roads too far from it aren't considered at all
+    fn route(&self, from: i64, to: i64, guide: &LineString<f64>, options: &ShapeMatchOptions) -> Option<Vec<RoadStep>> {
+        let mut costs: HashMap<i64, f64> = HashMap::from([(from, 0.0)]);
+        let mut previous: HashMap<i64, RoadStep> = HashMap::new();
+        // Costs are never negative, so their bits sort the same way they do
+        let mut queue = BinaryHeap::from([Reverse((0f64.to_bits(), from))]);
+
+        while let Some(Reverse((cost, node))) = queue.pop() {
+            let cost = f64::from_bits(cost);
+            if node == to {
+                break;
+            }
+            if costs.get(&node).is_some_and(|best| *best < cost) {
+                continue;
+            }
+
+            for edge in self.edges.get(&node).into_iter().flatten() {
+                let distance = edge.midpoint.euclidean_distance(guide);
+                if distance > options.anchor_spacing {
+                    continue;
+                }
+
+                let next_cost = cost + edge.length * (1.0 + (distance / options.max_distance).powi(2));
+                if costs.get(&edge.to).is_none_or(|best| next_cost < *best) {
+                    costs.insert(edge.to, next_cost);
+                    previous.insert(edge.to, RoadStep { from: node, to: edge.to, way_id: edge.way_id });
+                    queue.push(Reverse((next_cost.to_bits(), edge.to)));
+                }
+            }
+        }
+
+        let mut steps = Vec::new();
+        let mut node = to;
+        while node != from {
+            let step = *previous.get(&node)?;
+            steps.push(step);
+            node = step.from;
+        }
+        steps.reverse();
+
+        Some(steps)
+    }
+}
+
+/// Indices of the shape's points to route between; the first and last, and then about every
+/// `spacing` metres
+fn anchor_indices(line: &LineString<f64>, spacing: f64) -> Vec<usize> {
+    let mut anchors = vec![0];
+    let mut since_anchor = 0.0;
+    for (index, segment) in line.lines().enumerate() {
+        since_anchor += segment.euclidean_length();
+        if since_anchor >= spacing && index + 1 < line.0.len() - 1 {
+            anchors.push(index + 1);
+            since_anchor = 0.0;
+        }
+    }
+    anchors.push(line.0.len() - 1);
+
+    anchors
+}
+
+/// Follows `shape` (in longitude and latitude, as [crate::gtfs::gtfs_shapes::GtfsShape] has it)
+/// along the bus roads in `osm`.
+///
+/// The shape is split into short stretches, and each is routed along whichever roads stay
+/// closest to it. Spurs where the route goes out and straight back along the same road are
+/// dropped, as they come from stretches ending just off the route. Returns `None` if the shape
+/// can't be followed at all; a poor match still comes back, with a low confidence.
+pub fn match_shape(shape: &LineString<f64>, osm: &OsmData, options: &ShapeMatchOptions) -> Option<ShapeMatch> {
+    let projection = Projection::new(shape.0.first()?.y);
+    let shape = LineString::new(shape.0.iter().map(|coord| projection.project(*coord)).collect());
+    if shape.0.len() < 2 {
+        return None;
+    }
+
+    let graph = RoadGraph::new(osm, projection);
+    let anchors = anchor_indices(&shape, options.anchor_spacing);
+    let anchor_nodes = anchors.iter()
+        .map(|index| graph.nearest_node(shape.0[*index], options.anchor_spacing))
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut steps: Vec<RoadStep> = Vec::new();
+    for (pair, nodes) in anchors.windows(2).zip(anchor_nodes.windows(2)) {
+        if nodes[0] == nodes[1] {
+            continue;
+        }
+
+        let guide = LineString::new(shape.0[pair[0]..=pair[1]].to_vec());
+        for step in graph.route(nodes[0], nodes[1], &guide, options)? {
+            match steps.last() {
+                Some(last) if last.from == step.to && last.to == step.from => { steps.pop(); }
+                _ => steps.push(step),
+            }
+        }
+    }
+
+    let mut ways: Vec<MatchedWay> = Vec::new();
+    for step in &steps {
+        match ways.last_mut() {
+            Some(way) if way.way_id == step.way_id => way.nodes.push(step.to),
+            _ => ways.push(MatchedWay { way_id: step.way_id, nodes: vec![step.from, step.to] }),
+        }
+    }
+    if ways.is_empty() {
+        return None;
+    }
+
+    let matched_line: LineString<f64> = steps.iter()
+        .map(|step| graph.nodes[&step.from])
+        .chain(steps.last().map(|step| graph.nodes[&step.to]))
+        .collect();
+    let samples = shape.densify(options.max_distance / 2.0);
+    let covered = samples.points()
+        .filter(|point| point.euclidean_distance(&matched_line) <= options.max_distance)
+        .count() as f64 / samples.0.len() as f64;
+    let (shape_length, matched_length) = (shape.euclidean_length(), matched_line.euclidean_length());
+    let length_ratio = shape_length.min(matched_length) / shape_length.max(matched_length);
+
+    Some(ShapeMatch { ways, confidence: covered * length_ratio })
+}
+
+/// A way as it stands after any changes proposed so far
+fn current_way<'a>(osm: &'a OsmData, changes: &'a ProposedChanges, id: i64) -> Option<&'a OsmWay> {
+    changes.change.create.ways.get(&id)
+        .or_else(|| changes.change.modify.ways.get(&id))
+        .or_else(|| osm.way(id))
+}
+
+fn current_relation<'a>(osm: &'a OsmData, changes: &'a ProposedChanges, id: i64) -> Option<&'a OsmRelation> {
+    changes.change.create.relations.get(&id)
+        .or_else(|| changes.change.modify.relations.get(&id))
+        .or_else(|| osm.relation(id))
+}
+
+/// Adds `new` alongside `old` in every relation that has `old`, now that it's been split off.
+/// The pieces go in whichever order continues on from the member before (or into the one after).
+fn update_parent_relations(osm: &OsmData, changes: &mut ProposedChanges, old: &OsmWay, new: &OsmWay) {
+    let has_old = |relation: &&OsmRelation| relation.members.iter()
+        .any(|member| member.kind == OsmElementType::Way && member.reference == old.id);
+    let parents: BTreeSet<i64> = osm.relations.values()
+        .chain(changes.change.modify.relations.values())
+        .chain(changes.change.create.relations.values())
+        .filter(has_old)
+        .map(|relation| relation.id)
+        .collect();
+
+    for id in parents {
+        let Some(relation) = current_relation(osm, changes, id).filter(has_old) else { continue };
+        let mut relation = relation.clone();
+
+        let touches = |member: Option<&OsmMember>, node: i64| member
+            .filter(|member| member.kind == OsmElementType::Way)
+            .and_then(|member| current_way(osm, changes, member.reference))
+            .is_some_and(|way| way.nodes.first() == Some(&node) || way.nodes.last() == Some(&node));
+
+        let mut members = Vec::with_capacity(relation.members.len() + 1);
+        for (index, member) in relation.members.iter().enumerate() {
+            if member.kind != OsmElementType::Way || member.reference != old.id {
+                members.push(member.clone());
+                continue;
+            }
+
+            let before = index.checked_sub(1).and_then(|index| relation.members.get(index));
+            let after = relation.members.get(index + 1);
+            let new_member = OsmMember { reference: new.id, ..member.clone() };
+            if touches(before, new.nodes[new.nodes.len() - 1]) || touches(after, old.nodes[0]) {
+                members.extend([new_member, member.clone()]);
+            } else {
+                members.extend([member.clone(), new_member]);
+            }
+        }
+        relation.members = members;
+
+        changes.evidence.entry((OsmElementType::Relation, id))
+            .or_insert_with(|| format!("Way {} was split to add a route along part of it, so both parts are kept here.", old.id));
+        if id < 0 {
+            changes.change.create.relations.insert(id, relation);
+        } else {
+            changes.change.modify.relations.insert(id, relation);
+        }
+    }
+}
+
+/// The first relation `way_id` is in that splitting it would break, if any
+fn unsplittable_parent(osm: &OsmData, changes: &ProposedChanges, way_id: i64) -> Option<i64> {
+    osm.relations.values()
+        .chain(changes.change.modify.relations.values())
+        .chain(changes.change.create.relations.values())
+        .filter(|relation| relation.members.iter().any(|member| member.kind == OsmElementType::Way && member.reference == way_id))
+        .find(|relation| !relation.tags.get("type").is_some_and(|kind| SPLITTABLE_PARENT_TYPES.contains(&kind.as_str())))
+        .map(|relation| relation.id)
+}
+
+/// Splits whichever piece of `way_id` has `node_id` partway along it, so that a piece starts or
+/// ends there. Does nothing if one already does.
+fn split_way_at(osm: &OsmData, changes: &mut ProposedChanges, way_id: i64, node_id: i64) {
+    let mut pieces = changes.split_ways.get(&way_id).cloned().unwrap_or_else(|| vec![way_id]);
+
+    for (index, piece_id) in pieces.clone().into_iter().enumerate() {
+        let Some(piece) = current_way(osm, changes, piece_id) else { continue };
+        let Some(position) = piece.nodes.iter().position(|node| *node == node_id).filter(|position| *position > 0 && *position < piece.nodes.len() - 1) else { continue };
+
+        let mut piece = piece.clone();
+        let id = changes.change.next_placeholder_id();
+        let new = OsmWay { id, nodes: piece.nodes[position..].to_vec(), tags: piece.tags.clone(), meta: OsmMeta::default() };
+        piece.nodes.truncate(position + 1);
+
+        changes.evidence.insert((OsmElementType::Way, id), format!("Split from way {way_id} at node {node_id}, where a route joins or leaves it."));
+        changes.evidence.entry((OsmElementType::Way, piece.id))
+            .or_insert_with(|| format!("Split at node {node_id}, where a route joins or leaves it."));
+
+        update_parent_relations(osm, changes, &piece, &new);
+        if piece.id < 0 {
+            changes.change.create.ways.insert(piece.id, piece);
+        } else {
+            changes.change.modify.ways.insert(piece.id, piece);
+        }
+        changes.change.create.ways.insert(id, new);
+
+        pieces.insert(index + 1, id);
+        changes.split_ways.insert(way_id, pieces);
+        return;
+    }
+}
+
+/// The route relation way members for `shape_match`, splitting ways where the route only uses
+/// part of them, along with the ways that were used whole instead and the relation that stopped
+/// each being split.
+///
+/// Split ways are updated in any relations they were already in, so `osm` has to have every
+/// relation the matched ways are in, as [OverpassQuery::with_bus_roads] and
+/// [OsmPbfReader::extract_areas] fetch. Ways in relations that can't just take both pieces, such
+/// as turn restrictions, aren't split.
+///
+/// [OverpassQuery::with_bus_roads]: crate::osm::osm_overpass::OverpassQuery::with_bus_roads
+/// [OsmPbfReader::extract_areas]: crate::osm::osm_pbf::OsmPbfReader::extract_areas
+pub fn apply_shape_match(shape_match: &ShapeMatch, osm: &OsmData, changes: &mut ProposedChanges) -> (Vec<OsmMember>, BTreeMap<i64, i64>) {
+    let mut unsplit = BTreeMap::new();
+
+    // Split everything first, as a later split might cut through a piece an earlier way uses
+    for section in &shape_match.ways {
+        let ends = [section.nodes[0], section.nodes[section.nodes.len() - 1]];
+        if let Some(parent) = unsplittable_parent(osm, changes, section.way_id) {
+            let partial = current_way(osm, changes, section.way_id)
+                .is_some_and(|way| ends.iter().any(|node| way.nodes.first() != Some(node) && way.nodes.last() != Some(node)));
+            if partial {
+                unsplit.insert(section.way_id, parent);
+            }
+            continue;
+        }
+
+        for node in ends {
+            split_way_at(osm, changes, section.way_id, node);
+        }
+    }
+
+    let mut members: Vec<OsmMember> = Vec::new();
+    for section in &shape_match.ways {
+        let pieces = changes.split_ways.get(&section.way_id).cloned().unwrap_or_else(|| vec![section.way_id]);
+        for pair in section.nodes.windows(2) {
+            let piece = pieces.iter().copied().find(|piece| current_way(osm, changes, *piece)
+                .is_some_and(|way| way.nodes.windows(2).any(|nodes| nodes == pair || (nodes[0] == pair[1] && nodes[1] == pair[0]))));
+            let Some(piece) = piece else { continue };
+
+            if members.last().is_none_or(|member| member.reference != piece) {
+                members.push(OsmMember { kind: OsmElementType::Way, reference: piece, role: String::new() });
+            }
+        }
+    }
+
+    (members, unsplit)
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::{coord, LineString};
+    use crate::conflation::ProposedChanges;
+    use crate::conflation::shape_matching::{apply_shape_match, match_shape, MatchedWay, ShapeMatchOptions};
+    use crate::osm::osm_elements::{OsmData, OsmElementType, OsmMember, OsmMeta, OsmNode, OsmRelation, OsmWay};
+
+    /// Eddy Avenue running east from node 1 to 4, a street north from node 3 to 5, and a back
+    /// way from node 1 to 5 via 6
+    fn test_roads() -> OsmData {
+        let mut osm = OsmData::default();
+        for (id, lat, lon) in [(1, -33.88, 151.2), (2, -33.88, 151.201), (3, -33.88, 151.202), (4, -33.88, 151.203), (5, -33.879, 151.202), (6, -33.879, 151.2)] {
+            osm.nodes.insert(id, OsmNode { id, lat, lon, tags: Default::default(), meta: OsmMeta::default() });
+        }
+
+        for (id, nodes, highway) in [(100, vec![1, 2, 3, 4], "secondary"), (101, vec![3, 5], "residential"), (102, vec![1, 6, 5], "residential")] {
+            let tags = [("highway".to_string(), highway.to_string())].into_iter().collect();
+            osm.ways.insert(id, OsmWay { id, nodes, tags, meta: OsmMeta { version: Some(2), ..Default::default() } });
+        }
+
+        let members = vec![OsmMember { kind: OsmElementType::Way, reference: 100, role: String::new() }];
+        let tags = [("type".to_string(), "route".to_string()), ("route".to_string(), "bus".to_string())].into_iter().collect();
+        osm.relations.insert(200, OsmRelation { id: 200, members, tags, meta: OsmMeta::default() });
+
+        osm
+    }
+
+    #[test]
+    fn test_match_shape() {
+        let mut osm = test_roads();
+        // A few metres off the kerb, as shapes tend to be
+        let shape = LineString::new(vec![coord! { x: 151.2, y: -33.88004 }, coord! { x: 151.202, y: -33.88004 }, coord! { x: 151.20204, y: -33.879 }]);
+
+        let shape_match = match_shape(&shape, &osm, &ShapeMatchOptions::default()).unwrap();
+        assert_eq!(shape_match.ways, vec![MatchedWay { way_id: 100, nodes: vec![1, 2, 3] }, MatchedWay { way_id: 101, nodes: vec![3, 5] }]);
+        assert!(shape_match.confidence > 0.9, "{}", shape_match.confidence);
+
+        let mut changes = ProposedChanges::default();
+        let (members, unsplit) = apply_shape_match(&shape_match, &osm, &mut changes);
+        assert!(unsplit.is_empty());
+        assert_eq!(members.iter().map(|member| member.reference).collect::<Vec<_>>(), vec![100, 101]);
+        assert_eq!(changes.change.modify.ways[&100].nodes, vec![1, 2, 3]);
+        assert_eq!(changes.change.create.ways[&-1].nodes, vec![3, 4]);
+        assert_eq!(changes.change.modify.relations[&200].members.iter().map(|member| member.reference).collect::<Vec<_>>(), vec![100, -1]);
+
+        // Buses can't turn up the side street any more, so they'd have to take the back way
+        osm.ways.get_mut(&101).unwrap().tags.insert("oneway".to_string(), "-1".to_string());
+        let shape_match = match_shape(&shape, &osm, &ShapeMatchOptions::default()).unwrap();
+        assert_eq!(shape_match.ways, vec![MatchedWay { way_id: 102, nodes: vec![1, 6, 5] }]);
+        assert!(shape_match.confidence < 0.5, "{}", shape_match.confidence);
+    }
+
+    #[test]
+    fn test_unsplittable_ways() {
+        let mut osm = test_roads();
+        // No turning right from Eddy Avenue into the side street
+        let members = vec![
+            OsmMember { kind: OsmElementType::Way, reference: 100, role: "from".to_string() },
+            OsmMember { kind: OsmElementType::Node, reference: 3, role: "via".to_string() },
+            OsmMember { kind: OsmElementType::Way, reference: 101, role: "to".to_string() },
+        ];
+        let tags = [("type".to_string(), "restriction".to_string()), ("restriction".to_string(), "no_right_turn".to_string())].into_iter().collect();
+        osm.relations.insert(201, OsmRelation { id: 201, members, tags, meta: OsmMeta::default() });
+
+        let shape = LineString::new(vec![coord! { x: 151.2, y: -33.88004 }, coord! { x: 151.202, y: -33.88004 }, coord! { x: 151.20204, y: -33.879 }]);
+        let shape_match = match_shape(&shape, &osm, &ShapeMatchOptions::default()).unwrap();
+
+        let mut changes = ProposedChanges::default();
+        let (members, unsplit) = apply_shape_match(&shape_match, &osm, &mut changes);
+        assert_eq!(members.iter().map(|member| member.reference).collect::<Vec<_>>(), vec![100, 101]);
+        // The side street is used whole anyway, so only Eddy Avenue needed splitting
+        assert_eq!(unsplit.into_iter().collect::<Vec<_>>(), vec![(100, 201)]);
+        assert!(changes.change.is_empty());
+    }
+}
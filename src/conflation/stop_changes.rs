@@ -0,0 +1,223 @@
+use std::collections::{BTreeSet, HashMap};
+use crate::conflation::ProposedChanges;
+use crate::conflation::stops::{StopConflation, StopMatch, REF_TAGS};
+use crate::gtfs::gtfs_feed::GtfsFeed;
+use crate::gtfs::gtfs_filter::GtfsLocated;
+use crate::gtfs::gtfs_schedule::{GtfsScheduleStop, GtfsStopLocationType, GtfsWheelchairBoarding};
+use crate::gtfs::gtfs_types::GtfsID;
+use crate::osm::osm_elements::{OsmElementType, OsmMeta, OsmNode, OsmTags};
+
+#[derive(Debug, Clone)]
+pub struct StopChangeOptions {
+    /// The key a stop's code goes under, unless the node already has it under another of
+    /// [REF_TAGS]
+    pub ref_key: String,
+    pub network: Option<String>,
+    /// Whether each stop has a shelter, for the stops where that's known from a survey or
+    /// TfNSW's amenity data; GTFS doesn't say
+    pub shelters: HashMap<GtfsID, bool>,
+}
+
+impl Default for StopChangeOptions {
+    fn default() -> Self {
+        StopChangeOptions { ref_key: "ref".to_string(), network: None, shelters: HashMap::new() }
+    }
+}
+
+impl StopChangeOptions {
+    pub fn with_network<S: Into<String>>(self, network: S) -> StopChangeOptions {
+        StopChangeOptions { network: Some(network.into()), ..self }
+    }
+}
+
+/// The names of the agencies running routes through `stop_id`
+pub(crate) fn stop_operators<'a>(feed: &'a GtfsFeed, stop_id: &GtfsID) -> BTreeSet<&'a str> {
+    feed.routes_for_stop(stop_id)
+        .into_iter()
+        .filter_map(|route_id| feed.route(route_id))
+        .filter_map(|route| match &route.agency_id {
+            Some(agency_id) => feed.agencies.iter().find(|agency| agency.agency_id.as_ref() == Some(agency_id)),
+            // Agency IDs are optional when there's only one agency
+            None => feed.agencies.first().filter(|_| feed.agencies.len() == 1),
+        })
+        .map(|agency| agency.agency_name.as_str())
+        .collect()
+}
+
+/// The tags GTFS tells us a stop's node should have. Anything GTFS doesn't know is left out,
+/// rather than guessed.
+pub fn proposed_stop_tags(stop: &GtfsScheduleStop, feed: &GtfsFeed, options: &StopChangeOptions) -> OsmTags {
+    let mut tags = OsmTags::new();
+
+    if let Some(name) = &stop.stop_name {
+        tags.insert("name".to_string(), name.clone());
+    }
+    tags.insert(options.ref_key.clone(), stop.stop_code.clone().unwrap_or_else(|| stop.stop_id.to_string()));
+    if let Some(network) = &options.network {
+        tags.insert("network".to_string(), network.clone());
+    }
+
+    let operators = stop_operators(feed, &stop.stop_id);
+    if !operators.is_empty() {
+        tags.insert("operator".to_string(), operators.into_iter().collect::<Vec<_>>().join(";"));
+    }
+
+    match stop.wheelchair_boarding {
+        Some(GtfsWheelchairBoarding::Some) => { tags.insert("wheelchair".to_string(), "yes".to_string()); }
+        Some(GtfsWheelchairBoarding::None) => { tags.insert("wheelchair".to_string(), "no".to_string()); }
+        _ => {}
+    }
+    if let Some(shelter) = options.shelters.get(&stop.stop_id) {
+        tags.insert("shelter".to_string(), if *shelter { "yes" } else { "no" }.to_string());
+    }
+
+    tags
+}
+
+/// The short names of the routes through `stop_id`, for describing a stop
+fn stop_route_names(feed: &GtfsFeed, stop_id: &GtfsID) -> Vec<String> {
+    let names: BTreeSet<&str> = feed.routes_for_stop(stop_id)
+        .into_iter()
+        .filter_map(|route_id| feed.route(route_id))
+        .map(|route| route.route_short_name.as_deref().unwrap_or(route.route_id.as_ref()))
+        .collect();
+
+    names.into_iter().map(str::to_string).collect()
+}
+
+fn describe_stop(stop: &GtfsScheduleStop) -> String {
+    match &stop.stop_name {
+        Some(name) => format!("GTFS stop {} ({name})", stop.stop_id),
+        None => format!("GTFS stop {}", stop.stop_id),
+    }
+}
+
+fn match_evidence(stop_match: &StopMatch, updated: &OsmNode) -> String {
+    let mut evidence = vec![format!("{:.0} m away", stop_match.score.distance)];
+    match stop_match.score.ref_match {
+        Some(true) => evidence.push("ref matches".to_string()),
+        Some(false) => evidence.push("ref differs".to_string()),
+        None => {}
+    }
+    if let Some(name_similarity) = stop_match.score.name_similarity {
+        evidence.push(format!("name {:.0}% similar", name_similarity * 100.0));
+    }
+
+    let changed: Vec<&str> = updated.tags.iter()
+        .filter(|(key, value)| stop_match.node.tags.get(*key) != Some(*value))
+        .map(|(key, _)| key.as_str())
+        .collect();
+
+    format!("Matched to {} with score {:.2}: {}. Set {} from GTFS.",
+        describe_stop(stop_match.stop), stop_match.score.total, evidence.join(", "), changed.join(", "))
+}
+
+/// `node` with `proposed` applied, or `None` if it already has all of them
+fn updated_node(node: &OsmNode, mut proposed: OsmTags, options: &StopChangeOptions) -> Option<OsmNode> {
+    // Don't add a second copy of a ref the node already has under another key
+    if let Some(stop_ref) = proposed.get(&options.ref_key) {
+        if REF_TAGS.iter().any(|key| node.tags.get(*key) == Some(stop_ref)) {
+            proposed.remove(&options.ref_key);
+        }
+    }
+
+    proposed.retain(|key, value| node.tags.get(key) != Some(value));
+    if proposed.is_empty() {
+        return None;
+    }
+
+    let mut node = node.clone();
+    node.tags.extend(proposed);
+    Some(node)
+}
+
+/// The changes that would bring OSM in line with GTFS for these stops.
+///
+/// Matched nodes get their tags updated, and bus stops that are only in GTFS get a new node.
+/// Ambiguous stops need a person to decide, and stops only in OSM might be run by someone other
+/// than TfNSW, so neither are touched.
+pub fn propose_stop_changes(conflation: &StopConflation, feed: &GtfsFeed, options: &StopChangeOptions) -> ProposedChanges {
+    let mut changes = ProposedChanges::default();
+
+    for stop_match in &conflation.matched {
+        if let Some(node) = updated_node(stop_match.node, proposed_stop_tags(stop_match.stop, feed, options), options) {
+            changes.evidence.insert((OsmElementType::Node, node.id), match_evidence(stop_match, &node));
+            changes.change.modify.nodes.insert(node.id, node);
+        }
+    }
+
+    for stop in &conflation.gtfs_only {
+        // Stations and the like are mapped as areas, and stops with no bus service are either
+        // something else or unused
+        let is_stop = matches!(stop.location_type, None | Some(GtfsStopLocationType::Stop));
+        let is_bus_stop = feed.routes_for_stop(&stop.stop_id).into_iter()
+            .filter_map(|route_id| feed.route(route_id))
+            .any(|route| route.route_type.is_bus());
+        let Some(location) = stop.location().filter(|_| is_stop && is_bus_stop) else { continue };
+
+        let mut tags = proposed_stop_tags(stop, feed, options);
+        tags.insert("highway".to_string(), "bus_stop".to_string());
+        tags.insert("public_transport".to_string(), "platform".to_string());
+        tags.insert("bus".to_string(), "yes".to_string());
+
+        let id = changes.change.next_placeholder_id();
+        let evidence = format!("{} has no matching OSM stop nearby, and is served by {}.",
+            describe_stop(stop), stop_route_names(feed, &stop.stop_id).join(", "));
+        changes.evidence.insert((OsmElementType::Node, id), evidence);
+        changes.change.create.nodes.insert(id, OsmNode { id, lat: location.y(), lon: location.x(), tags, meta: OsmMeta::default() });
+        changes.created_stops.insert(stop.stop_id.clone(), id);
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conflation::stop_changes::{propose_stop_changes, StopChangeOptions};
+    use crate::conflation::stops::{conflate_stops, StopConflationOptions};
+    use crate::gtfs::gtfs_feed::GtfsFeed;
+    use crate::gtfs::gtfs_feed::tests::test_archive;
+    use crate::gtfs::gtfs_types::GtfsID;
+    use crate::osm::osm_change::write_osm_change;
+    use crate::osm::osm_elements::OsmElementType;
+    use crate::osm::osm_xml::parse_osm_xml;
+
+    #[test]
+    fn test_propose_stop_changes() {
+        let feed = GtfsFeed::from_zip(&mut test_archive()).unwrap();
+        let osm = parse_osm_xml(include_str!("../../fixtures/osm/central.osm").as_bytes()).unwrap();
+        let conflation = conflate_stops(feed.stops.values(), &osm, &StopConflationOptions::default());
+
+        let mut options = StopChangeOptions::default().with_network("Sydney Buses");
+        options.shelters.insert(GtfsID("2000323".to_string()), true);
+        let changes = propose_stop_changes(&conflation, &feed, &options);
+        let change = &changes.change;
+
+        // Redfern is served by the M30, but Central is a station and isn't a bus stop
+        assert_eq!(change.create.nodes.len(), 1);
+        let redfern = &change.create.nodes[&-1];
+        assert_eq!(redfern.tags.get("highway").map(String::as_str), Some("bus_stop"));
+        assert_eq!(redfern.tags.get("ref").map(String::as_str), Some("2010100"));
+        assert_eq!(redfern.tags.get("operator").map(String::as_str), Some("Transit Systems"));
+
+        // Stand A already has its ref under ref:tfnsw
+        let stand_a = &change.modify.nodes[&4000001];
+        assert_eq!(stand_a.tags.get("name").map(String::as_str), Some("Central Station Stand A"));
+        assert!(!stand_a.tags.contains_key("ref"));
+        assert_eq!(stand_a.tags.get("network").map(String::as_str), Some("Sydney Buses"));
+        assert_eq!(stand_a.meta.version, Some(3));
+
+        let stand_b = &change.modify.nodes[&4000002];
+        assert_eq!(stand_b.tags.get("ref").map(String::as_str), Some("2000323"));
+        assert_eq!(stand_b.tags.get("shelter").map(String::as_str), Some("yes"));
+
+        assert_eq!(changes.evidence[&(OsmElementType::Node, -1)],
+            "GTFS stop 2010100 (Redfern Station) has no matching OSM stop nearby, and is served by M30.");
+        assert!(changes.evidence[&(OsmElementType::Node, 4000001)].contains("ref matches"));
+        assert!(changes.evidence[&(OsmElementType::Node, 4000002)].ends_with("Set network, operator, ref, shelter from GTFS."));
+
+        // Proposing again from the same data gives exactly the same file
+        let again = propose_stop_changes(&conflation, &feed, &options);
+        assert_eq!(write_osm_change(change, None, Vec::new()).unwrap(), write_osm_change(&again.change, None, Vec::new()).unwrap());
+    }
+}
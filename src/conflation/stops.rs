@@ -0,0 +1,419 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use geo::HaversineDistance;
+use geo_types::Point;
+use crate::gtfs::gtfs_filter::GtfsLocated;
+use crate::gtfs::gtfs_schedule::{GtfsScheduleStop, GtfsStopLocationType};
+use crate::osm::osm_elements::{OsmData, OsmNode, OsmTags};
+
+/// Tags that mappers put TfNSW stop IDs or codes under
+pub const REF_TAGS: [&str; 3] = ["ref:tfnsw", "gtfs:stop_id", "ref"];
+
+/// Tags that a stop's name might be under
+const NAME_TAGS: [&str; 3] = ["name", "official_name", "alt_name"];
+
+/// Street types and other words that TfNSW and mappers abbreviate differently, and the form
+/// we compare them in
+const NAME_ABBREVIATIONS: [(&str, &str); 14] = [
+    ("avenue", "av"), ("ave", "av"),
+    ("street", "st"),
+    ("road", "rd"),
+    ("highway", "hwy"),
+    ("parade", "pde"),
+    ("place", "pl"),
+    ("crescent", "cres"),
+    ("drive", "dr"),
+    ("circuit", "cct"),
+    ("boulevard", "bvd"), ("blvd", "bvd"),
+    ("opposite", "opp"),
+    ("stn", "station"),
+];
+
+pub(crate) const METRES_PER_DEGREE: f64 = 111_320.0;
+
+/// Weights for each piece of evidence in [StopMatchScore::total]. A ref is the strongest, since
+/// it was put there by someone who knew which stop it was.
+const REF_WEIGHT: f64 = 3.0;
+const DISTANCE_WEIGHT: f64 = 2.0;
+const NAME_WEIGHT: f64 = 1.0;
+
+/// What an OSM node represents, going by its public transport tags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsmStopKind {
+    /// `highway=bus_stop` or `public_transport=platform`; where passengers wait
+    Platform,
+    /// `public_transport=stop_position`; where the vehicle stops, on the road itself
+    StopPosition,
+    Station,
+}
+
+impl OsmStopKind {
+    pub fn from_tags(tags: &OsmTags) -> Option<OsmStopKind> {
+        let tag = |key: &str| tags.get(key).map(String::as_str);
+
+        match (tag("public_transport"), tag("highway"), tag("amenity")) {
+            (Some("station"), _, _) | (_, _, Some("bus_station")) => Some(OsmStopKind::Station),
+            (Some("platform"), _, _) | (_, Some("bus_stop"), _) => Some(OsmStopKind::Platform),
+            (Some("stop_position"), _, _) => Some(OsmStopKind::StopPosition),
+            _ => None,
+        }
+    }
+
+    /// How well this kind of node stands in for a stop of `location_type`, or `None` if it can't.
+    /// A stop position will do for a stop, but a platform is what we're really after.
+    fn compatibility(&self, location_type: &GtfsStopLocationType) -> Option<f64> {
+        match (location_type, self) {
+            (GtfsStopLocationType::Stop, OsmStopKind::Platform) => Some(1.0),
+            (GtfsStopLocationType::Stop, OsmStopKind::StopPosition) => Some(0.5),
+            (GtfsStopLocationType::Station, OsmStopKind::Station) => Some(1.0),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StopConflationOptions {
+    /// Metres a node can be from a stop and still be considered
+    pub max_distance: f64,
+    /// Metres a node with a matching ref can be from its stop, since a ref is good evidence even
+    /// when the node's been put in the wrong place
+    pub max_ref_distance: f64,
+    /// The lowest [StopMatchScore::total] that counts as a match
+    pub min_score: f64,
+    /// How far ahead of the runner up the best candidate has to be for the match to be clear cut
+    pub ambiguity_margin: f64,
+}
+
+impl Default for StopConflationOptions {
+    fn default() -> Self {
+        StopConflationOptions { max_distance: 50.0, max_ref_distance: 500.0, min_score: 0.5, ambiguity_margin: 0.1 }
+    }
+}
+
+impl StopConflationOptions {
+    pub fn with_max_distance(self, max_distance: f64) -> StopConflationOptions {
+        StopConflationOptions { max_distance, ..self }
+    }
+
+    pub fn with_min_score(self, min_score: f64) -> StopConflationOptions {
+        StopConflationOptions { min_score, ..self }
+    }
+}
+
+/// The evidence for pairing a stop with a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopMatchScore {
+    /// From 0 to 1; the weighted average of the evidence we have, scaled by how well the node's
+    /// kind suits the stop
+    pub total: f64,
+    /// Metres
+    pub distance: f64,
+    /// Whether the node's ref tags agree with the stop, or `None` if it doesn't have any
+    pub ref_match: Option<bool>,
+    /// From 0 to 1, or `None` if either side is unnamed
+    pub name_similarity: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StopCandidate<'a> {
+    pub node: &'a OsmNode,
+    pub kind: OsmStopKind,
+    pub score: StopMatchScore,
+}
+
+#[derive(Debug, Clone)]
+pub struct StopMatch<'a> {
+    pub stop: &'a GtfsScheduleStop,
+    pub node: &'a OsmNode,
+    pub kind: OsmStopKind,
+    pub score: StopMatchScore,
+}
+
+/// A stop that has more than one plausible node, or whose best node went to a better match,
+/// which needs someone to look at it
+#[derive(Debug, Clone)]
+pub struct AmbiguousStop<'a> {
+    pub stop: &'a GtfsScheduleStop,
+    /// Best first
+    pub candidates: Vec<StopCandidate<'a>>,
+}
+
+/// Every stop and stop node, sorted into buckets. Each bucket is ordered by stop ID or node ID,
+/// so output generated from it is stable between runs.
+#[derive(Debug, Default)]
+pub struct StopConflation<'a> {
+    pub matched: Vec<StopMatch<'a>>,
+    /// Stops with no node that scored well enough, which probably need adding to OSM
+    pub gtfs_only: Vec<&'a GtfsScheduleStop>,
+    /// Stop nodes that weren't matched, and aren't a candidate for any ambiguous stop. These may
+    /// be disused, or served by something other than TfNSW.
+    pub osm_only: Vec<&'a OsmNode>,
+    pub ambiguous: Vec<AmbiguousStop<'a>>,
+}
+
+impl<'a> StopConflation<'a> {
+    pub fn matched_node(&self, stop_id: &str) -> Option<&'a OsmNode> {
+        self.matched.iter()
+            .find(|stop_match| stop_match.stop.stop_id.0 == stop_id)
+            .map(|stop_match| stop_match.node)
+    }
+}
+
+/// Splits a name into lowercase words, with common abbreviations normalised
+fn name_words(name: &str) -> HashSet<String> {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let word = word.to_lowercase();
+            NAME_ABBREVIATIONS.iter()
+                .find(|(from, _)| *from == word)
+                .map_or(word, |(_, to)| to.to_string())
+        })
+        .collect()
+}
+
+/// The Dice coefficient of the two names' words, so word order and punctuation don't matter
+pub fn name_similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (name_words(a), name_words(b));
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+
+    2.0 * a.intersection(&b).count() as f64 / (a.len() + b.len()) as f64
+}
+
+/// The refs on a node, split where a node has been given several
+fn node_refs(node: &OsmNode) -> impl Iterator<Item=&str> {
+    REF_TAGS.iter()
+        .filter_map(|key| node.tags.get(*key))
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+}
+
+fn stop_refs(stop: &GtfsScheduleStop) -> impl Iterator<Item=&str> {
+    std::iter::once(stop.stop_id.0.as_str()).chain(stop.stop_code.as_deref())
+}
+
+/// Stop nodes bucketed by location, so finding the ones near a stop doesn't mean checking every
+/// node in the extract
+struct StopNodeGrid<'a> {
+    /// Degrees
+    cell_size: f64,
+    cells: HashMap<(i64, i64), Vec<&'a OsmNode>>,
+}
+
+impl<'a> StopNodeGrid<'a> {
+    fn new<I: IntoIterator<Item=&'a OsmNode>>(nodes: I, cell_metres: f64) -> StopNodeGrid<'a> {
+        let mut grid = StopNodeGrid { cell_size: cell_metres / METRES_PER_DEGREE, cells: HashMap::new() };
+        for node in nodes {
+            grid.cells.entry(grid.cell(&node.point())).or_default().push(node);
+        }
+
+        grid
+    }
+
+    fn cell(&self, point: &Point<f64>) -> (i64, i64) {
+        ((point.x() / self.cell_size).floor() as i64, (point.y() / self.cell_size).floor() as i64)
+    }
+
+    /// Nodes within roughly `radius` metres of `point`, and possibly some a little further
+    fn near(&self, point: &Point<f64>, radius: f64) -> impl Iterator<Item=&'a OsmNode> + '_ {
+        // A degree of longitude gets shorter away from the equator
+        let lat_cells = (radius / METRES_PER_DEGREE / self.cell_size).ceil() as i64;
+        let lon_cells = (lat_cells as f64 / point.y().to_radians().cos()).ceil() as i64;
+        let (x, y) = self.cell(point);
+
+        (x - lon_cells..=x + lon_cells)
+            .flat_map(move |x| (y - lat_cells..=y + lat_cells).map(move |y| (x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+}
+
+fn score_candidate(stop: &GtfsScheduleStop, location: &Point<f64>, node: &OsmNode, compatibility: f64, options: &StopConflationOptions) -> StopMatchScore {
+    let distance = location.haversine_distance(&node.point());
+
+    let node_refs: Vec<&str> = node_refs(node).collect();
+    let ref_match = (!node_refs.is_empty())
+        .then(|| stop_refs(stop).any(|stop_ref| node_refs.contains(&stop_ref)));
+
+    let name_similarity = stop.stop_name.as_deref().and_then(|stop_name| {
+        NAME_TAGS.iter()
+            .filter_map(|key| node.tags.get(*key))
+            .map(|name| name_similarity(stop_name, name))
+            .max_by(|a, b| a.total_cmp(b))
+    });
+
+    let mut evidence = vec![(DISTANCE_WEIGHT, (1.0 - distance / options.max_distance).max(0.0))];
+    if let Some(ref_match) = ref_match {
+        evidence.push((REF_WEIGHT, if ref_match { 1.0 } else { 0.0 }));
+    }
+    if let Some(name_similarity) = name_similarity {
+        evidence.push((NAME_WEIGHT, name_similarity));
+    }
+
+    let weights: f64 = evidence.iter().map(|(weight, _)| weight).sum();
+    let total = compatibility * evidence.iter().map(|(weight, score)| weight * score).sum::<f64>() / weights;
+
+    StopMatchScore { total, distance, ref_match, name_similarity }
+}
+
+/// Every node that could be `stop`, best first
+fn candidates<'a>(stop: &GtfsScheduleStop, grid: &StopNodeGrid<'a>, by_ref: &HashMap<&str, Vec<&'a OsmNode>>, options: &StopConflationOptions) -> Vec<StopCandidate<'a>> {
+    let Some(location) = stop.location() else { return Vec::new() };
+    let location_type = stop.location_type.as_ref().unwrap_or(&GtfsStopLocationType::Stop);
+
+    let ref_nodes = stop_refs(stop).filter_map(|stop_ref| by_ref.get(stop_ref)).flatten().copied();
+    let mut seen = HashSet::new();
+    let mut candidates: Vec<StopCandidate> = grid.near(&location, options.max_distance)
+        .chain(ref_nodes)
+        .filter(|node| seen.insert(node.id))
+        .filter_map(|node| {
+            let kind = OsmStopKind::from_tags(&node.tags)?;
+            let compatibility = kind.compatibility(location_type)?;
+            let score = score_candidate(stop, &location, node, compatibility, options);
+
+            let max_distance = if score.ref_match == Some(true) { options.max_ref_distance } else { options.max_distance };
+            (score.distance <= max_distance).then_some(StopCandidate { node, kind, score })
+        })
+        .collect();
+
+    candidates.sort_by(|a, b| b.score.total.total_cmp(&a.score.total).then(a.node.id.cmp(&b.node.id)));
+    candidates
+}
+
+/// Pairs each stop with the OSM stop node that it most likely is.
+///
+/// Each stop is scored against the nearby nodes (and any with its ID as a ref) on distance, refs,
+/// name and whether the node is the right kind of thing. Stops with the most convincing matches
+/// get first pick, so a node is only ever matched to one stop. Entrances, generic nodes and
+/// boarding areas aren't mapped as stop nodes, so they're left out entirely, as are stops without
+/// a location.
+pub fn conflate_stops<'a, I: IntoIterator<Item=&'a GtfsScheduleStop>>(stops: I, osm: &'a OsmData, options: &StopConflationOptions) -> StopConflation<'a> {
+    let stop_nodes: Vec<&OsmNode> = osm.nodes.values()
+        .filter(|node| OsmStopKind::from_tags(&node.tags).is_some())
+        .collect();
+
+    let grid = StopNodeGrid::new(stop_nodes.iter().copied(), options.max_distance);
+    let mut by_ref: HashMap<&str, Vec<&OsmNode>> = HashMap::new();
+    for node in &stop_nodes {
+        for node_ref in node_refs(node) {
+            by_ref.entry(node_ref).or_default().push(node);
+        }
+    }
+
+    let mut scored: Vec<(&GtfsScheduleStop, Vec<StopCandidate>)> = stops.into_iter()
+        .filter(|stop| matches!(stop.location_type, None | Some(GtfsStopLocationType::Stop | GtfsStopLocationType::Station)))
+        .filter(|stop| stop.location().is_some())
+        .map(|stop| (stop, candidates(stop, &grid, &by_ref, options)))
+        .collect();
+
+    // Most convincing first, so they get first pick of the nodes
+    let best_score = |candidates: &[StopCandidate]| candidates.first().map_or(0.0, |candidate| candidate.score.total);
+    scored.sort_by(|(a, a_candidates), (b, b_candidates)| {
+        best_score(b_candidates).total_cmp(&best_score(a_candidates)).then_with(|| a.stop_id.0.cmp(&b.stop_id.0))
+    });
+
+    let mut conflation = StopConflation::default();
+    let mut claimed: HashSet<i64> = HashSet::new();
+    for (stop, candidates) in scored {
+        let plausible: Vec<StopCandidate> = candidates.into_iter()
+            .filter(|candidate| candidate.score.total >= options.min_score)
+            .collect();
+        let available: Vec<&StopCandidate> = plausible.iter()
+            .filter(|candidate| !claimed.contains(&candidate.node.id))
+            .collect();
+
+        match available.as_slice() {
+            [] if plausible.is_empty() => conflation.gtfs_only.push(stop),
+            [best, rest @ ..] if rest.first().is_none_or(|next| best.score.total - next.score.total >= options.ambiguity_margin) => {
+                claimed.insert(best.node.id);
+                conflation.matched.push(StopMatch { stop, node: best.node, kind: best.kind, score: best.score.clone() });
+            }
+            _ => conflation.ambiguous.push(AmbiguousStop { stop, candidates: plausible }),
+        }
+    }
+
+    let contested: HashSet<i64> = conflation.ambiguous.iter()
+        .flat_map(|ambiguous| ambiguous.candidates.iter().map(|candidate| candidate.node.id))
+        .collect();
+    conflation.osm_only = stop_nodes.into_iter()
+        .filter(|node| !claimed.contains(&node.id) && !contested.contains(&node.id))
+        .collect();
+
+    let by_stop_id = |a: &GtfsScheduleStop, b: &GtfsScheduleStop| -> Ordering { a.stop_id.0.cmp(&b.stop_id.0) };
+    conflation.matched.sort_by(|a, b| by_stop_id(a.stop, b.stop));
+    conflation.gtfs_only.sort_by(|a, b| by_stop_id(a, b));
+    conflation.ambiguous.sort_by(|a, b| by_stop_id(a.stop, b.stop));
+
+    conflation
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use crate::conflation::stops::{conflate_stops, name_similarity, OsmStopKind, StopConflationOptions};
+    use crate::gtfs::gtfs_feed::GtfsFeed;
+    use crate::gtfs::gtfs_feed::tests::test_archive;
+    use crate::gtfs::gtfs_schedule::GtfsScheduleStop;
+    use crate::osm::osm_elements::{OsmData, OsmMeta, OsmNode};
+    use crate::osm::osm_xml::parse_osm_xml;
+
+    fn stop_node(id: i64, lat: f64, lon: f64, tags: &[(&str, &str)]) -> OsmNode {
+        let tags = tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        OsmNode { id, lat, lon, tags, meta: OsmMeta::default() }
+    }
+
+    #[test]
+    fn test_name_similarity() {
+        assert_eq!(name_similarity("Eddy Avenue, Stand A", "Eddy Av Stand A"), 1.0);
+        assert_eq!(name_similarity("Town Hall Station", "Town Hall Stn"), 1.0);
+        assert_eq!(name_similarity("Town Hall Station", "Town Hall"), 0.8);
+        assert_eq!(name_similarity("Central Station", "Redfern"), 0.0);
+    }
+
+    #[test]
+    fn test_conflate_feed() {
+        let feed = GtfsFeed::from_zip(&mut test_archive()).unwrap();
+        let osm = parse_osm_xml(include_str!("../../fixtures/osm/central.osm").as_bytes()).unwrap();
+
+        let conflation = conflate_stops(feed.stops.values(), &osm, &StopConflationOptions::default());
+
+        // Stand A has a ref, Stand B only has its name and location to go by
+        assert_eq!(conflation.matched.len(), 2);
+        assert_eq!(conflation.matched_node("2000322").map(|node| node.id), Some(4000001));
+        assert_eq!(conflation.matched[0].score.ref_match, Some(true));
+        assert_eq!(conflation.matched_node("2000323").map(|node| node.id), Some(4000002));
+        assert_eq!(conflation.matched[1].kind, OsmStopKind::Platform);
+
+        // Central isn't mapped as a station node, and Redfern isn't in the extract at all
+        let gtfs_only: Vec<_> = conflation.gtfs_only.iter().map(|stop| stop.stop_id.0.as_str()).collect();
+        assert_eq!(gtfs_only, vec!["200060", "2010100"]);
+        assert!(conflation.osm_only.is_empty());
+        assert!(conflation.ambiguous.is_empty());
+    }
+
+    #[test]
+    fn test_ambiguous_and_osm_only() {
+        let stops = "stop_id,stop_name,stop_lat,stop_lon\n\
+            2000400,Elizabeth St,-33.8800,151.2100";
+        let stops: Vec<GtfsScheduleStop> = csv::Reader::from_reader(stops.as_bytes()).deserialize().map(Result::unwrap).collect();
+
+        // Two unnamed stops either side of the road, and a disused one well down the street
+        let nodes = [
+            stop_node(1, -33.88010, 151.21000, &[("highway", "bus_stop")]),
+            stop_node(2, -33.87990, 151.21000, &[("highway", "bus_stop")]),
+            stop_node(3, -33.88300, 151.21000, &[("public_transport", "platform"), ("name", "Elizabeth St")]),
+            stop_node(4, -33.88000, 151.21001, &[("highway", "crossing")]),
+        ];
+        let osm = OsmData { nodes: nodes.into_iter().map(|node| (node.id, node)).collect::<BTreeMap<_, _>>(), ..Default::default() };
+
+        let conflation = conflate_stops(&stops, &osm, &StopConflationOptions::default());
+
+        assert!(conflation.matched.is_empty());
+        assert_eq!(conflation.ambiguous.len(), 1);
+        assert_eq!(conflation.ambiguous[0].candidates.len(), 2);
+        assert_eq!(conflation.osm_only.iter().map(|node| node.id).collect::<Vec<_>>(), vec![3]);
+    }
+}
@@ -0,0 +1,309 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, warn};
+use reqwest::{Client, Response, StatusCode, Url};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, ETAG, HeaderMap, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use zip::ZipArchive;
+use crate::osm_api_client::TryToString;
+use crate::rate_limit::SharedRateLimiter;
+
+/// How hard to try before giving up on a download
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts in a row that can fail without making any progress
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(120),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadOutcome {
+    /// The server answered a conditional request with 304, and nothing was written
+    NotModified,
+    Downloaded {
+        etag: Option<String>,
+        last_modified: Option<String>,
+    },
+}
+
+/// The delay a 429 or 503 asked for, either as a number of seconds or as an HTTP date
+pub fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// The first byte of a `Content-Range: bytes {start}-{end}/{total}` header
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_RANGE)?
+        .to_str().ok()?
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .parse()
+        .ok()
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Downloads `endpoint` into `file`, resuming with `Range` requests if the connection drops part
+/// way through and backing off whenever the server is struggling.
+///
+/// `conditional` headers are only sent with the first request; once we're resuming, `If-Range`
+/// makes sure the rest of the file comes from the same version as the start of it.
+pub async fn download_resumable(
+    client: &Client,
+    rate_limiter: &SharedRateLimiter,
+    retry: &RetryPolicy,
+    endpoint: Url,
+    conditional: Option<HeaderMap>,
+    file: &NamedTempFile,
+) -> anyhow::Result<DownloadOutcome> {
+    let progress = ProgressBar::new(0)
+        .with_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7} {msg}")?)
+        .with_message(endpoint.path().to_string());
+
+    let mut written = 0u64;
+    let mut expected_length = None;
+    let mut validators: (Option<String>, Option<String>) = (None, None);
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+        let can_retry = attempt < retry.max_attempts;
+        rate_limiter.until_ready().await?;
+
+        let mut request = client.get(endpoint.clone());
+        if written == 0 {
+            if let Some(conditional) = &conditional {
+                request = request.headers(conditional.clone());
+            }
+        } else {
+            request = request.header(RANGE, format!("bytes={written}-"));
+
+            // If-Range needs a strong validator; without one the server sends the whole file again
+            match &validators {
+                (Some(etag), _) if !etag.starts_with("W/") => request = request.header(IF_RANGE, etag),
+                (_, Some(last_modified)) => request = request.header(IF_RANGE, last_modified),
+                _ => {}
+            }
+        }
+
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) if can_retry => {
+                warn!("Request for {endpoint} failed ({e}), retrying");
+                tokio::time::sleep(retry.backoff(attempt)).await;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let status = response.status();
+        if status == StatusCode::NOT_MODIFIED {
+            progress.finish_and_clear();
+            return Ok(DownloadOutcome::NotModified);
+        }
+
+        if is_retryable(status) && can_retry {
+            // Don't let a server hold us up for longer than we'd ever back off by ourselves
+            let delay = retry_after(response.headers(), Utc::now())
+                .map_or_else(|| retry.backoff(attempt), |delay| delay.min(retry.max_backoff));
+            warn!("{endpoint} returned {status}, retrying in {delay:?}");
+            tokio::time::sleep(delay).await;
+            continue;
+        }
+
+        let response = response.error_for_status()?;
+        let content_length = response.headers().get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+
+        if status == StatusCode::PARTIAL_CONTENT {
+            let start = content_range_start(response.headers());
+            if start != Some(written) {
+                if !can_retry {
+                    anyhow::bail!("{endpoint} sent a range starting at {start:?} when we asked for {written}");
+                }
+
+                // Appending a range we didn't ask for would corrupt the file, so start over without one
+                warn!("{endpoint} sent a range starting at {start:?} when we asked for {written}, starting over");
+                written = 0;
+                file.as_file().set_len(0)?;
+                continue;
+            }
+
+            debug!("Resuming {endpoint} from {written} bytes");
+        } else {
+            // Either a fresh download, or the server ignored our Range and is starting over
+            written = 0;
+            file.as_file().set_len(0)?;
+
+            let headers = response.headers();
+            validators = (
+                headers.get(ETAG).and_then(|v| v.try_to_string().ok()),
+                headers.get(LAST_MODIFIED).and_then(|v| v.try_to_string().ok()),
+            );
+            expected_length = content_length;
+        }
+
+        if let Some(expected_length) = expected_length {
+            progress.set_length(expected_length);
+        }
+        progress.set_position(written);
+
+        let resumed_from = written;
+        let result = append_body(response, file, &mut written, &progress).await;
+        if written > resumed_from {
+            // Only give up on downloads that have stopped getting anywhere
+            attempt = 0;
+        }
+        let can_retry = attempt < retry.max_attempts;
+
+        match result {
+            Ok(()) => {}
+            Err(e) if can_retry => {
+                warn!("Download of {endpoint} was interrupted at {written} bytes ({e}), resuming");
+                tokio::time::sleep(retry.backoff(attempt.max(1))).await;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        match expected_length {
+            Some(expected_length) if written < expected_length && can_retry => {
+                warn!("Download of {endpoint} ended early at {written} of {expected_length} bytes, resuming");
+                continue;
+            }
+            Some(expected_length) if written != expected_length =>
+                anyhow::bail!("Download of {endpoint} ended at {written} bytes, but expected {expected_length}"),
+            _ => break,
+        }
+    }
+
+    progress.finish_and_clear();
+    let (etag, last_modified) = validators;
+    Ok(DownloadOutcome::Downloaded { etag, last_modified })
+}
+
+/// Appends the body of `response` to `file`, counting the bytes in `written` as they land so that
+/// an interrupted download knows where to pick up from
+async fn append_body(response: Response, file: &NamedTempFile, written: &mut u64, progress: &ProgressBar) -> anyhow::Result<()> {
+    let mut output = tokio::fs::File::from(OpenOptions::new().append(true).open(file.path())?);
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                output.flush().await?;
+                return Err(e.into());
+            }
+        };
+
+        output.write_all(&chunk).await?;
+        *written += chunk.len() as u64;
+        progress.inc(chunk.len() as u64);
+    }
+
+    output.flush().await?;
+    output.sync_all().await?;
+
+    Ok(())
+}
+
+/// Reads every entry of a zip, so that a truncated or corrupt download is caught by the CRC
+/// checks here rather than half way through processing it
+pub fn verify_zip<R: Read + Seek>(reader: R) -> anyhow::Result<ZipArchive<R>> {
+    let mut archive = ZipArchive::new(reader)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        std::io::copy(&mut entry, &mut std::io::sink())
+            .map_err(|e| anyhow::anyhow!(e).context(format!("{} is corrupt", entry.name())))?;
+    }
+
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use std::time::Duration;
+    use chrono::{TimeZone, Utc};
+    use reqwest::header::{CONTENT_RANGE, HeaderMap, HeaderValue, RETRY_AFTER};
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+    use crate::download::{content_range_start, retry_after, verify_zip, RetryPolicy};
+
+    #[test]
+    fn test_retry_after() {
+        let now = Utc.with_ymd_and_hms(2024, 4, 15, 7, 28, 0).unwrap();
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers, now), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(120)));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Mon, 15 Apr 2024 07:28:30 GMT"));
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(30)));
+
+        // Dates in the past mean go ahead now
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Mon, 15 Apr 2024 07:00:00 GMT"));
+        assert_eq!(retry_after(&headers, now), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let retry = RetryPolicy::default();
+        assert_eq!(retry.backoff(1), Duration::from_secs(2));
+        assert_eq!(retry.backoff(3), Duration::from_secs(8));
+        assert_eq!(retry.backoff(40), Duration::from_secs(120));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 1024-2047/2048"));
+        assert_eq!(content_range_start(&headers), Some(1024));
+    }
+
+    #[test]
+    fn test_verify_zip() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("stops.txt", SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"stop_id,stop_name\n200060,Central Station").unwrap();
+        let zip = writer.finish().unwrap().into_inner();
+
+        assert!(verify_zip(Cursor::new(zip.clone())).is_ok());
+        assert!(verify_zip(Cursor::new(zip[..zip.len() / 2].to_vec())).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{Datelike, NaiveDate, Weekday};
+use crate::gtfs::gtfs_schedule::{GtfsExceptionType, GtfsScheduleCalendar, GtfsScheduleCalendarDate, GtfsServiceAvailability};
+use crate::gtfs::gtfs_types::GtfsID;
+
+impl GtfsScheduleCalendar {
+    pub fn availability_on(&self, weekday: Weekday) -> GtfsServiceAvailability {
+        match weekday {
+            Weekday::Mon => self.monday,
+            Weekday::Tue => self.tuesday,
+            Weekday::Wed => self.wednesday,
+            Weekday::Thu => self.thursday,
+            Weekday::Fri => self.friday,
+            Weekday::Sat => self.saturday,
+            Weekday::Sun => self.sunday,
+        }
+    }
+
+    /// Whether this calendar runs on `date`, ignoring any exceptions in calendar_dates.txt
+    pub fn runs_on(&self, date: NaiveDate) -> bool {
+        self.start_date.0 <= date
+            && date <= self.end_date.0
+            && self.availability_on(date.weekday()) == GtfsServiceAvailability::Available
+    }
+}
+
+/// Resolves which services run on a given day, combining calendar.txt's weekly patterns with the
+/// added and removed dates from calendar_dates.txt.
+///
+/// Feeds are allowed to define services purely through calendar_dates.txt, so a service may only
+/// exist in `exceptions`.
+#[derive(Debug, Default)]
+pub struct GtfsServiceCalendar {
+    calendars: HashMap<GtfsID, GtfsScheduleCalendar>,
+    exceptions: HashMap<GtfsID, HashMap<NaiveDate, GtfsExceptionType>>,
+}
+
+impl GtfsServiceCalendar {
+    pub fn new<C, D>(calendars: C, calendar_dates: D) -> GtfsServiceCalendar
+        where C: IntoIterator<Item=GtfsScheduleCalendar>, D: IntoIterator<Item=GtfsScheduleCalendarDate> {
+        let mut service_calendar = GtfsServiceCalendar::default();
+
+        for calendar in calendars {
+            service_calendar.calendars.insert(calendar.service_id.clone(), calendar);
+        }
+
+        for calendar_date in calendar_dates {
+            service_calendar.exceptions
+                .entry(calendar_date.service_id)
+                .or_default()
+                .insert(calendar_date.date.0, calendar_date.exception_type);
+        }
+
+        service_calendar
+    }
+
+    pub fn service_ids(&self) -> impl Iterator<Item=&GtfsID> {
+        self.calendars.keys()
+            .chain(self.exceptions.keys().filter(|id| !self.calendars.contains_key(*id)))
+    }
+
+    pub fn is_service_active(&self, service_id: &GtfsID, date: NaiveDate) -> bool {
+        match self.exceptions.get(service_id).and_then(|exceptions| exceptions.get(&date)) {
+            Some(GtfsExceptionType::Added) => true,
+            Some(GtfsExceptionType::Removed) => false,
+            None => self.calendars.get(service_id).is_some_and(|calendar| calendar.runs_on(date))
+        }
+    }
+
+    pub fn active_services(&self, date: NaiveDate) -> HashSet<&GtfsID> {
+        self.service_ids()
+            .filter(|id| self.is_service_active(id, date))
+            .collect()
+    }
+
+    /// All services that run on at least one day between `start` and `end` (inclusive)
+    pub fn active_services_between(&self, start: NaiveDate, end: NaiveDate) -> HashSet<&GtfsID> {
+        self.service_ids()
+            .filter(|id| start.iter_days().take_while(|date| date <= &end).any(|date| self.is_service_active(id, date)))
+            .collect()
+    }
+
+    /// The final day that `service_id` runs, if it ever runs at all
+    pub fn last_active_date(&self, service_id: &GtfsID) -> Option<NaiveDate> {
+        let last_added = self.exceptions.get(service_id)
+            .and_then(|exceptions| exceptions.iter()
+                .filter(|(_, exception)| **exception == GtfsExceptionType::Added)
+                .map(|(date, _)| *date)
+                .max());
+
+        let last_scheduled = self.calendars.get(service_id)
+            .and_then(|calendar| {
+                let mut date = calendar.end_date.0;
+                while date >= calendar.start_date.0 {
+                    if self.is_service_active(service_id, date) {
+                        return Some(date);
+                    }
+
+                    date = date.pred_opt()?;
+                }
+
+                None
+            });
+
+        last_added.max(last_scheduled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use crate::gtfs::gtfs_calendar::GtfsServiceCalendar;
+    use crate::gtfs::gtfs_schedule::{GtfsScheduleCalendar, GtfsScheduleCalendarDate};
+    use crate::gtfs::gtfs_types::GtfsID;
+
+    fn service_calendar() -> GtfsServiceCalendar {
+        let calendars = "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+            weekday,1,1,1,1,1,0,0,20240401,20240430\n\
+            weekend,0,0,0,0,0,1,1,20240401,20240430";
+        let calendar_dates = "service_id,date,exception_type\n\
+            weekday,20240425,2\n\
+            weekend,20240425,1\n\
+            special,20240501,1";
+
+        GtfsServiceCalendar::new(
+            csv::Reader::from_reader(calendars.as_bytes()).deserialize::<GtfsScheduleCalendar>().map(Result::unwrap),
+            csv::Reader::from_reader(calendar_dates.as_bytes()).deserialize::<GtfsScheduleCalendarDate>().map(Result::unwrap),
+        )
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn test_weekly_pattern() {
+        let calendar = service_calendar();
+        let weekday = GtfsID("weekday".to_string());
+        let weekend = GtfsID("weekend".to_string());
+
+        // 2024-04-03 was a Wednesday
+        assert!(calendar.is_service_active(&weekday, date(2024, 4, 3)));
+        assert!(!calendar.is_service_active(&weekend, date(2024, 4, 3)));
+        assert!(calendar.is_service_active(&weekend, date(2024, 4, 6)));
+
+        // Outside of the date range
+        assert!(!calendar.is_service_active(&weekday, date(2024, 5, 1)));
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let calendar = service_calendar();
+
+        // Anzac Day runs to a weekend timetable
+        let active = calendar.active_services(date(2024, 4, 25));
+        assert_eq!(active.len(), 1);
+        assert!(active.contains(&GtfsID("weekend".to_string())));
+
+        let active = calendar.active_services(date(2024, 5, 1));
+        assert_eq!(active.len(), 1);
+        assert!(active.contains(&GtfsID("special".to_string())));
+    }
+
+    #[test]
+    fn test_last_active_date() {
+        let calendar = service_calendar();
+
+        // 2024-04-30 was a Tuesday, and 2024-04-28 a Sunday
+        assert_eq!(calendar.last_active_date(&GtfsID("weekday".to_string())), Some(date(2024, 4, 30)));
+        assert_eq!(calendar.last_active_date(&GtfsID("weekend".to_string())), Some(date(2024, 4, 28)));
+        assert_eq!(calendar.last_active_date(&GtfsID("special".to_string())), Some(date(2024, 5, 1)));
+        assert_eq!(calendar.last_active_date(&GtfsID("missing".to_string())), None);
+    }
+}
@@ -2,13 +2,14 @@ use std::fmt::{Display as StdDisplay, Formatter};
 use std::num::{IntErrorKind, ParseIntError};
 use std::str::FromStr;
 use anyhow::bail;
-use chrono::TimeDelta;
+use chrono::{NaiveDate, TimeDelta};
 use logos::Logos;
 use thiserror::Error;
 use crate::gtfs::gtfs_chrono::GtfsLexingError::{MissingHours, MissingMinutes, MissingSeconds, UnknownToken};
-use crate::gtfs::gtfs_types::GtfsTime;
+use crate::gtfs::gtfs_types::{GtfsDate, GtfsTime};
 
 const GTFS_TIME_FORMAT: &'static str = "%Y-%m-%d %H:%M:%S";
+const GTFS_DATE_FORMAT: &'static str = "%Y%m%d";
 
 #[derive(Default, Debug, Clone, PartialEq, Error)]
 pub enum GtfsLexingError {
@@ -99,6 +100,20 @@ impl StdDisplay for GtfsTime {
     }
 }
 
+impl FromStr for GtfsDate {
+    type Err = chrono::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NaiveDate::parse_from_str(s, GTFS_DATE_FORMAT).map(GtfsDate)
+    }
+}
+
+impl StdDisplay for GtfsDate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}", self.0.format(GTFS_DATE_FORMAT)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #![feature(assert_matches)]
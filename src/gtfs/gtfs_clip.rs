@@ -0,0 +1,437 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Seek, Write};
+use csv::StringRecord;
+use indicatif::ProgressBar;
+use serde::de::DeserializeOwned;
+use zip::read::ZipFile;
+use zip::result::ZipError;
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
+use crate::gtfs::gtfs_filter::{GtfsFilterStats, GtfsStreamFilter};
+use crate::gtfs::gtfs_schedule::{GtfsScheduleAgency, GtfsScheduleCalendar, GtfsScheduleCalendarDate, GtfsScheduleFareAttribute, GtfsScheduleFareRule, GtfsScheduleFrequency, GtfsScheduleLevel, GtfsSchedulePathway, GtfsScheduleRoute, GtfsScheduleShapePoint, GtfsScheduleStop, GtfsScheduleStopTime, GtfsScheduleTransfer, GtfsScheduleTrip};
+use crate::gtfs::gtfs_types::GtfsID;
+
+/// What to keep when clipping a feed.
+///
+/// `stops` picks the stops we're interested in (usually by area), and `trips` can further narrow
+/// down which of the trips serving those stops are kept. Everything else is pulled in by reference.
+pub struct GtfsClipOptions {
+    pub stops: GtfsStreamFilter<GtfsScheduleStop>,
+    pub trips: GtfsStreamFilter<GtfsScheduleTrip>,
+}
+
+impl GtfsClipOptions {
+    pub fn new(stops: GtfsStreamFilter<GtfsScheduleStop>) -> GtfsClipOptions {
+        GtfsClipOptions { stops, trips: GtfsStreamFilter::new() }
+    }
+
+    pub fn with_trips(self, trips: GtfsStreamFilter<GtfsScheduleTrip>) -> GtfsClipOptions {
+        GtfsClipOptions { trips, ..self }
+    }
+}
+
+/// How many rows were read and kept for each file written to the clipped feed
+#[derive(Debug, Default)]
+pub struct GtfsClipSummary {
+    pub files: BTreeMap<&'static str, GtfsFilterStats>,
+}
+
+/// Everything we've decided to keep so far for a single clipped feed
+#[derive(Default)]
+struct GtfsClipState {
+    stops_filter: GtfsStreamFilter<GtfsScheduleStop>,
+    trips_filter: GtfsStreamFilter<GtfsScheduleTrip>,
+    summary: GtfsClipSummary,
+    stops: HashSet<GtfsID>,
+    candidate_trips: HashSet<GtfsID>,
+    trips: HashSet<GtfsID>,
+    routes: HashSet<GtfsID>,
+    services: HashSet<GtfsID>,
+    shapes: HashSet<GtfsID>,
+    zones: HashSet<GtfsID>,
+    levels: HashSet<GtfsID>,
+    agencies: HashSet<GtfsID>,
+    /// Whether a kept route leaves out agency_id, which it can only do when there's one agency
+    any_agency: bool,
+    fares: HashSet<GtfsID>,
+}
+
+fn keep_reference(id: &Option<GtfsID>, kept: &HashSet<GtfsID>) -> bool {
+    id.as_ref().is_none_or(|id| kept.contains(id))
+}
+
+fn open_gtfs_file<'a, R: Read + Seek>(archive: &'a mut ZipArchive<R>, name: &str) -> anyhow::Result<Option<ZipFile<'a>>> {
+    match archive.by_name(name) {
+        Ok(file) => Ok(Some(file)),
+        Err(ZipError::FileNotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads `name` from `archive`, writing each row into the outputs whose state it's kept for. Every
+/// output is written in the same pass, however many there are. Files that the source feed doesn't
+/// have are skipped entirely.
+fn clip_file<R, W, T, F>(
+    archive: &mut ZipArchive<R>,
+    outputs: &mut [ZipWriter<W>],
+    states: &mut [GtfsClipState],
+    name: &'static str,
+    progress: Option<&ProgressBar>,
+    mut keep: F,
+) -> anyhow::Result<()>
+    where R: Read + Seek, W: Write + Seek, T: DeserializeOwned, F: FnMut(&mut GtfsClipState, &T) -> bool {
+    let Some(file) = open_gtfs_file(archive, name)? else { return Ok(()) };
+
+    let input: Box<dyn Read> = match progress {
+        Some(progress) => {
+            progress.set_length(file.size());
+            progress.set_position(0);
+            Box::new(progress.wrap_read(file))
+        }
+        None => Box::new(file),
+    };
+
+    let mut reader = csv::ReaderBuilder::new().from_reader(input);
+    let headers = reader.headers()?.clone();
+
+    let mut writers = Vec::with_capacity(outputs.len());
+    for output in outputs.iter_mut() {
+        output.start_file(name, SimpleFileOptions::default())?;
+        let mut writer = csv::WriterBuilder::new().double_quote(true).from_writer(output);
+        writer.write_record(&headers)?;
+        writers.push(writer);
+    }
+
+    let mut stats = vec![GtfsFilterStats::default(); states.len()];
+    let mut row = StringRecord::new();
+    while reader.read_record(&mut row)? {
+        let record: T = row.deserialize(Some(&headers))?;
+
+        for ((state, writer), stats) in states.iter_mut().zip(writers.iter_mut()).zip(stats.iter_mut()) {
+            stats.read += 1;
+
+            if keep(state, &record) {
+                stats.matched += 1;
+                writer.write_record(&row)?;
+            }
+        }
+    }
+
+    for ((state, writer), stats) in states.iter_mut().zip(writers.iter_mut()).zip(stats) {
+        writer.flush()?;
+        state.summary.files.insert(name, stats);
+    }
+
+    Ok(())
+}
+
+/// Produces a smaller, self-contained GTFS feed from `archive`.
+///
+/// See [clip_feeds] for what ends up in the clipped feed.
+pub fn clip_feed<R: Read + Seek, W: Write + Seek>(archive: &mut ZipArchive<R>, options: GtfsClipOptions, output: W) -> anyhow::Result<GtfsClipSummary> {
+    let mut summaries = clip_feeds(archive, vec![(options, output)], &ProgressBar::hidden())?;
+    Ok(summaries.remove(0))
+}
+
+/// Produces a smaller, self-contained GTFS feed from `archive` for each of `targets`, reading the
+/// source feed only once no matter how many targets there are.
+///
+/// Every trip that stops at one of the selected stops is kept in full, so a clipped feed will
+/// contain stops outside of the selection as well. From there we keep whatever those trips
+/// reference - routes, agencies, services, shapes, frequencies - and the parent stations and
+/// levels of every kept stop, along with the transfers, pathways and fare rules that only refer to
+/// kept entities.
+///
+/// This is all done by streaming, at the cost of reading stops.txt and stop_times.txt twice.
+/// `progress` tracks the passes over stop_times.txt, which is where nearly all the time goes.
+pub fn clip_feeds<R: Read + Seek, W: Write + Seek>(archive: &mut ZipArchive<R>, targets: Vec<(GtfsClipOptions, W)>, progress: &ProgressBar) -> anyhow::Result<Vec<GtfsClipSummary>> {
+    let mut states = Vec::with_capacity(targets.len());
+    let mut outputs = Vec::with_capacity(targets.len());
+    for (options, output) in targets {
+        states.push(GtfsClipState { stops_filter: options.stops, trips_filter: options.trips, ..Default::default() });
+        outputs.push(ZipWriter::new(output));
+    }
+
+    // Pick out the stops we want, and keep track of the station hierarchy so that we can fill it
+    // in once we know every stop we need
+    let mut parents = HashMap::new();
+    let mut children: HashMap<GtfsID, Vec<GtfsID>> = HashMap::new();
+    {
+        let stops = open_gtfs_file(archive, "stops.txt")?.ok_or(ZipError::FileNotFound)?;
+        GtfsStreamFilter::<GtfsScheduleStop>::new().for_each_match(stops, |stop, _| {
+            for state in &mut states {
+                if state.stops_filter.matches(stop) {
+                    state.stops.insert(stop.stop_id.clone());
+                }
+            }
+
+            if let Some(parent) = &stop.parent_station {
+                parents.insert(stop.stop_id.clone(), parent.clone());
+                children.entry(parent.clone()).or_default().push(stop.stop_id.clone());
+            }
+
+            Ok(())
+        })?;
+    }
+
+    // A station inside the area means we want its platforms too, even if they fall just outside,
+    // along with their boarding areas
+    for state in &mut states {
+        let mut unvisited: Vec<GtfsID> = state.stops.iter().cloned().collect();
+        while let Some(stop_id) = unvisited.pop() {
+            for child in children.get(&stop_id).into_iter().flatten() {
+                if state.stops.insert(child.clone()) {
+                    unvisited.push(child.clone());
+                }
+            }
+        }
+    }
+
+    {
+        let stop_times = open_gtfs_file(archive, "stop_times.txt")?.ok_or(ZipError::FileNotFound)?;
+        progress.set_length(stop_times.size());
+        progress.set_position(0);
+        progress.set_message("Finding trips");
+
+        GtfsStreamFilter::<GtfsScheduleStopTime>::new().for_each_match(progress.wrap_read(stop_times), |stop_time, _| {
+            if let Some(stop_id) = &stop_time.stop_id {
+                for state in &mut states {
+                    if state.stops.contains(stop_id) {
+                        state.candidate_trips.insert(stop_time.trip_id.clone());
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+    }
+
+    clip_file(archive, &mut outputs, &mut states, "trips.txt", None, |state, trip: &GtfsScheduleTrip| {
+        if !state.candidate_trips.contains(&trip.trip_id) || !state.trips_filter.matches(trip) {
+            return false;
+        }
+
+        state.trips.insert(trip.trip_id.clone());
+        state.routes.insert(trip.route_id.clone());
+        state.services.insert(trip.service_id.clone());
+        state.shapes.extend(trip.shape_id.clone());
+        true
+    })?;
+
+    progress.set_message("Writing stop times");
+    clip_file(archive, &mut outputs, &mut states, "stop_times.txt", Some(progress), |state, stop_time: &GtfsScheduleStopTime| {
+        if !state.trips.contains(&stop_time.trip_id) {
+            return false;
+        }
+
+        state.stops.extend(stop_time.stop_id.clone());
+        true
+    })?;
+
+    // Boarding areas have platforms as parents, so we may need to walk up more than once
+    for state in &mut states {
+        let mut unvisited: Vec<GtfsID> = state.stops.iter().cloned().collect();
+        while let Some(stop_id) = unvisited.pop() {
+            if let Some(parent) = parents.get(&stop_id) {
+                if state.stops.insert(parent.clone()) {
+                    unvisited.push(parent.clone());
+                }
+            }
+        }
+    }
+
+    clip_file(archive, &mut outputs, &mut states, "stops.txt", None, |state, stop: &GtfsScheduleStop| {
+        if !state.stops.contains(&stop.stop_id) {
+            return false;
+        }
+
+        state.zones.extend(stop.zone_id.clone());
+        state.levels.extend(stop.level_id.clone());
+        true
+    })?;
+
+    clip_file(archive, &mut outputs, &mut states, "levels.txt", None, |state, level: &GtfsScheduleLevel|
+        state.levels.contains(&level.level_id))?;
+
+    clip_file(archive, &mut outputs, &mut states, "pathways.txt", None, |state, pathway: &GtfsSchedulePathway|
+        state.stops.contains(&pathway.from_stop_id) && state.stops.contains(&pathway.to_stop_id))?;
+
+    clip_file(archive, &mut outputs, &mut states, "routes.txt", None, |state, route: &GtfsScheduleRoute| {
+        if !state.routes.contains(&route.route_id) {
+            return false;
+        }
+
+        match &route.agency_id {
+            Some(agency_id) => { state.agencies.insert(agency_id.clone()); }
+            None => state.any_agency = true,
+        }
+        true
+    })?;
+
+    // Feeds with a single agency are allowed to leave agency_id out, on the agency, its routes or
+    // both, and routes without one belong to that agency
+    clip_file(archive, &mut outputs, &mut states, "agency.txt", None, |state, agency: &GtfsScheduleAgency|
+        state.any_agency || keep_reference(&agency.agency_id, &state.agencies))?;
+
+    clip_file(archive, &mut outputs, &mut states, "calendar.txt", None, |state, calendar: &GtfsScheduleCalendar|
+        state.services.contains(&calendar.service_id))?;
+
+    clip_file(archive, &mut outputs, &mut states, "calendar_dates.txt", None, |state, calendar_date: &GtfsScheduleCalendarDate|
+        state.services.contains(&calendar_date.service_id))?;
+
+    clip_file(archive, &mut outputs, &mut states, "frequencies.txt", None, |state, frequency: &GtfsScheduleFrequency|
+        state.trips.contains(&frequency.trip_id))?;
+
+    clip_file(archive, &mut outputs, &mut states, "shapes.txt", None, |state, point: &GtfsScheduleShapePoint|
+        state.shapes.contains(&point.shape_id))?;
+
+    clip_file(archive, &mut outputs, &mut states, "transfers.txt", None, |state, transfer: &GtfsScheduleTransfer| {
+        keep_reference(&transfer.from_stop_id, &state.stops) && keep_reference(&transfer.to_stop_id, &state.stops)
+            && keep_reference(&transfer.from_route_id, &state.routes) && keep_reference(&transfer.to_route_id, &state.routes)
+            && keep_reference(&transfer.from_trip_id, &state.trips) && keep_reference(&transfer.to_trip_id, &state.trips)
+    })?;
+
+    clip_file(archive, &mut outputs, &mut states, "fare_rules.txt", None, |state, rule: &GtfsScheduleFareRule| {
+        let keep = keep_reference(&rule.route_id, &state.routes) && keep_reference(&rule.origin_id, &state.zones)
+            && keep_reference(&rule.destination_id, &state.zones) && keep_reference(&rule.contains_id, &state.zones);
+
+        if keep {
+            state.fares.insert(rule.fare_id.clone());
+        }
+
+        keep
+    })?;
+
+    clip_file(archive, &mut outputs, &mut states, "fare_attributes.txt", None, |state, fare: &GtfsScheduleFareAttribute|
+        state.fares.contains(&fare.fare_id))?;
+
+    // feed_info.txt describes the feed as a whole, so it comes across as is
+    if let Some(mut feed_info_file) = open_gtfs_file(archive, "feed_info.txt")? {
+        let mut feed_info = Vec::new();
+        feed_info_file.read_to_end(&mut feed_info)?;
+
+        for output in &mut outputs {
+            output.start_file("feed_info.txt", SimpleFileOptions::default())?;
+            output.write_all(&feed_info)?;
+        }
+    }
+
+    for mut output in outputs {
+        output.finish()?;
+    }
+
+    Ok(states.into_iter().map(|state| state.summary).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use geo_types::{coord, Rect};
+    use zip::ZipArchive;
+    use indicatif::ProgressBar;
+    use crate::gtfs::gtfs_clip::{clip_feed, clip_feeds, GtfsClipOptions};
+    use crate::gtfs::gtfs_feed::GtfsFeed;
+    use crate::gtfs::gtfs_feed::tests::{archive_from, TEST_FILES};
+    use crate::gtfs::gtfs_filter::GtfsStreamFilter;
+    use crate::gtfs::gtfs_types::GtfsID;
+
+    #[test]
+    fn test_clip_around_redfern() {
+        let mut files = TEST_FILES.to_vec();
+        files.push(("transfers.txt", "from_stop_id,to_stop_id,transfer_type,min_transfer_time\n\
+            2000322,2010100,2,300\n\
+            2000323,2010100,2,300"));
+        files.push(("feed_info.txt", "feed_publisher_name,feed_publisher_url,feed_lang\n\
+            Transport for NSW,https://transportnsw.info,en"));
+        files[1] = ("stops.txt", "stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station,level_id\n\
+            200060,Central Station,-33.8840,151.2063,1,,\n\
+            2000322,Central Station Stand A,-33.8837,151.2065,0,200060,L0\n\
+            2000323,Central Station Stand B,-33.8838,151.2066,0,200060,L0\n\
+            2010100,Redfern Station,-33.8917,151.1987,1,,\n\
+            2010101,Redfern Platform 1,-33.8918,151.1988,0,2010100,L1\n\
+            2010102,Redfern Platform 1 Lift,-33.8918,151.1988,4,2010101,L1");
+        files.push(("levels.txt", "level_id,level_index,level_name\n\
+            L0,0,Street\n\
+            L1,-1,Concourse"));
+        files.push(("pathways.txt", "pathway_id,from_stop_id,to_stop_id,pathway_mode,is_bidirectional\n\
+            P1,2010101,2010102,5,1\n\
+            P2,2000322,2000323,1,1"));
+        files.push(("frequencies.txt", "trip_id,start_time,end_time,headway_secs\n\
+            trip_a,07:00:00,09:00:00,600\n\
+            trip_b,07:00:00,09:00:00,900"));
+
+        // Only Redfern is inside, but trip_a also stops at Central Stand A
+        let options = GtfsClipOptions::new(GtfsStreamFilter::new()
+            .in_bounding_box(Rect::new(coord! { x: 151.19, y: -33.90 }, coord! { x: 151.20, y: -33.89 })));
+
+        let mut output = Cursor::new(Vec::new());
+        let summary = clip_feed(&mut archive_from(&files), options, &mut output).unwrap();
+        assert_eq!(summary.files["stop_times.txt"].matched, 2);
+
+        let mut clipped = ZipArchive::new(output).unwrap();
+        assert!(clipped.by_name("feed_info.txt").is_ok());
+
+        let feed = GtfsFeed::from_zip(&mut clipped).unwrap();
+        assert_eq!(feed.trips.len(), 1);
+        assert_eq!(feed.routes.len(), 1);
+        assert_eq!(feed.agencies.len(), 1);
+        assert!(feed.route(&GtfsID("2441_M30".to_string())).is_some());
+
+        // Redfern with its platform and boarding area, Central Stand A and its parent station, but
+        // not Stand B
+        let mut stops: Vec<_> = feed.stops.keys().map(|id| id.0.as_str()).collect();
+        stops.sort();
+        assert_eq!(stops, vec!["2000322", "200060", "2010100", "2010101", "2010102"]);
+
+        assert_eq!(summary.files["transfers.txt"].matched, 1);
+        // Redfern's platform and its boarding area, but not Stand B
+        assert_eq!(summary.files["pathways.txt"].matched, 1);
+        assert_eq!(summary.files["levels.txt"].matched, 2);
+        assert_eq!(summary.files["frequencies.txt"].matched, 1);
+    }
+
+    #[test]
+    fn test_clip_many_areas_at_once() {
+        let redfern = GtfsClipOptions::new(GtfsStreamFilter::new()
+            .in_bounding_box(Rect::new(coord! { x: 151.19, y: -33.90 }, coord! { x: 151.20, y: -33.89 })));
+        let stand_b = GtfsClipOptions::new(GtfsStreamFilter::new()
+            .with_stops(HashSet::from([GtfsID("2000323".to_string())])));
+
+        let mut redfern_output = Cursor::new(Vec::new());
+        let mut stand_b_output = Cursor::new(Vec::new());
+        let summaries = clip_feeds(
+            &mut archive_from(&TEST_FILES),
+            vec![(redfern, &mut redfern_output), (stand_b, &mut stand_b_output)],
+            &ProgressBar::hidden(),
+        ).unwrap();
+
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].files["trips.txt"].matched, 1);
+        assert_eq!(summaries[1].files["trips.txt"].matched, 1);
+
+        let redfern = GtfsFeed::from_zip(&mut ZipArchive::new(redfern_output).unwrap()).unwrap();
+        let stand_b = GtfsFeed::from_zip(&mut ZipArchive::new(stand_b_output).unwrap()).unwrap();
+        assert!(redfern.trip(&GtfsID("trip_a".to_string())).is_some());
+        assert!(stand_b.trip(&GtfsID("trip_b".to_string())).is_some());
+        assert_eq!(stand_b.stops.len(), 2);
+    }
+
+    #[test]
+    fn test_clip_single_agency_without_ids() {
+        let mut files = TEST_FILES.to_vec();
+        files[2] = ("routes.txt", "route_id,route_short_name,route_long_name,route_type\n\
+            2441_M30,M30,Mosman to Sydenham,700\n\
+            2441_309,309,Port Botany to Central,700");
+
+        let options = GtfsClipOptions::new(GtfsStreamFilter::new()
+            .in_bounding_box(Rect::new(coord! { x: 151.19, y: -33.90 }, coord! { x: 151.20, y: -33.89 })));
+        let mut output = Cursor::new(Vec::new());
+        let summary = clip_feed(&mut archive_from(&files), options, &mut output).unwrap();
+
+        // The agency has an ID that no route mentions, but it's still the one running them
+        assert_eq!(summary.files["agency.txt"].matched, 1);
+        let feed = GtfsFeed::from_zip(&mut ZipArchive::new(output).unwrap()).unwrap();
+        assert_eq!(feed.agencies.len(), 1);
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek};
+use serde::de::DeserializeOwned;
+use zip::result::ZipError;
+use zip::ZipArchive;
+use crate::gtfs::gtfs_calendar::GtfsServiceCalendar;
+use crate::gtfs::gtfs_schedule::{GtfsScheduleAgency, GtfsScheduleRoute, GtfsScheduleStop, GtfsScheduleStopTime, GtfsScheduleTrip};
+use crate::gtfs::gtfs_shapes::{assemble_shapes, GtfsShape};
+use crate::gtfs::gtfs_types::GtfsID;
+
+/// Reads every row of `name` from a GTFS archive.
+///
+/// Optional files that aren't present in the archive are treated as empty, while missing required
+/// files are an error.
+pub fn read_gtfs_file<R: Read + Seek, T: DeserializeOwned>(archive: &mut ZipArchive<R>, name: &str, required: bool) -> anyhow::Result<Vec<T>> {
+    let file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) if !required => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow::anyhow!(e).context(format!("Failed to open {name}"))),
+    };
+
+    csv::ReaderBuilder::new()
+        .from_reader(file)
+        .deserialize()
+        .collect::<Result<Vec<T>, _>>()
+        .map_err(|e| anyhow::anyhow!(e).context(format!("Failed to read {name}")))
+}
+
+/// A GTFS schedule held entirely in memory, with indexes for the lookups we do most often.
+///
+/// Stop times are stored once, and indexed by position into `stop_times`; the per-trip index is
+/// kept in `stop_sequence` order.
+#[derive(Debug, Default)]
+pub struct GtfsFeed {
+    pub agencies: Vec<GtfsScheduleAgency>,
+    pub stops: HashMap<GtfsID, GtfsScheduleStop>,
+    pub routes: HashMap<GtfsID, GtfsScheduleRoute>,
+    pub trips: HashMap<GtfsID, GtfsScheduleTrip>,
+    pub stop_times: Vec<GtfsScheduleStopTime>,
+    pub services: GtfsServiceCalendar,
+    pub shapes: HashMap<GtfsID, GtfsShape>,
+
+    stop_times_by_trip: HashMap<GtfsID, Vec<usize>>,
+    stop_times_by_stop: HashMap<GtfsID, Vec<usize>>,
+    trips_by_route: HashMap<GtfsID, Vec<GtfsID>>,
+    stops_by_parent: HashMap<GtfsID, Vec<GtfsID>>,
+}
+
+impl GtfsFeed {
+    pub fn from_zip<R: Read + Seek>(archive: &mut ZipArchive<R>) -> anyhow::Result<GtfsFeed> {
+        let agencies = read_gtfs_file(archive, "agency.txt", true)?;
+        let stops = read_gtfs_file(archive, "stops.txt", true)?;
+        let routes = read_gtfs_file(archive, "routes.txt", true)?;
+        let trips = read_gtfs_file(archive, "trips.txt", true)?;
+        let stop_times = read_gtfs_file(archive, "stop_times.txt", true)?;
+        let services = GtfsServiceCalendar::new(
+            read_gtfs_file(archive, "calendar.txt", false)?,
+            read_gtfs_file(archive, "calendar_dates.txt", false)?,
+        );
+        let shapes = assemble_shapes(read_gtfs_file(archive, "shapes.txt", false)?);
+
+        Ok(GtfsFeed::new(agencies, stops, routes, trips, stop_times, services, shapes))
+    }
+
+    pub fn new(
+        agencies: Vec<GtfsScheduleAgency>,
+        stops: Vec<GtfsScheduleStop>,
+        routes: Vec<GtfsScheduleRoute>,
+        trips: Vec<GtfsScheduleTrip>,
+        stop_times: Vec<GtfsScheduleStopTime>,
+        services: GtfsServiceCalendar,
+        shapes: HashMap<GtfsID, GtfsShape>,
+    ) -> GtfsFeed {
+        let mut stops_by_parent: HashMap<GtfsID, Vec<GtfsID>> = HashMap::new();
+        for stop in &stops {
+            if let Some(parent) = &stop.parent_station {
+                stops_by_parent.entry(parent.clone()).or_default().push(stop.stop_id.clone());
+            }
+        }
+
+        let mut trips_by_route: HashMap<GtfsID, Vec<GtfsID>> = HashMap::new();
+        for trip in &trips {
+            trips_by_route.entry(trip.route_id.clone()).or_default().push(trip.trip_id.clone());
+        }
+
+        let mut stop_times_by_trip: HashMap<GtfsID, Vec<usize>> = HashMap::new();
+        let mut stop_times_by_stop: HashMap<GtfsID, Vec<usize>> = HashMap::new();
+        for (i, stop_time) in stop_times.iter().enumerate() {
+            stop_times_by_trip.entry(stop_time.trip_id.clone()).or_default().push(i);
+
+            if let Some(stop_id) = &stop_time.stop_id {
+                stop_times_by_stop.entry(stop_id.clone()).or_default().push(i);
+            }
+        }
+
+        for indices in stop_times_by_trip.values_mut() {
+            indices.sort_by_key(|i| stop_times[*i].stop_sequence);
+        }
+
+        GtfsFeed {
+            agencies,
+            stops: stops.into_iter().map(|stop| (stop.stop_id.clone(), stop)).collect(),
+            routes: routes.into_iter().map(|route| (route.route_id.clone(), route)).collect(),
+            trips: trips.into_iter().map(|trip| (trip.trip_id.clone(), trip)).collect(),
+            stop_times,
+            services,
+            shapes,
+            stop_times_by_trip,
+            stop_times_by_stop,
+            trips_by_route,
+            stops_by_parent,
+        }
+    }
+
+    pub fn stop(&self, stop_id: &GtfsID) -> Option<&GtfsScheduleStop> {
+        self.stops.get(stop_id)
+    }
+
+    pub fn route(&self, route_id: &GtfsID) -> Option<&GtfsScheduleRoute> {
+        self.routes.get(route_id)
+    }
+
+    pub fn trip(&self, trip_id: &GtfsID) -> Option<&GtfsScheduleTrip> {
+        self.trips.get(trip_id)
+    }
+
+    /// Stop times for `trip_id`, in `stop_sequence` order
+    pub fn stop_times_for_trip(&self, trip_id: &GtfsID) -> impl Iterator<Item=&GtfsScheduleStopTime> {
+        self.stop_times_by_trip.get(trip_id)
+            .into_iter()
+            .flatten()
+            .map(|i| &self.stop_times[*i])
+    }
+
+    pub fn stop_times_for_stop(&self, stop_id: &GtfsID) -> impl Iterator<Item=&GtfsScheduleStopTime> {
+        self.stop_times_by_stop.get(stop_id)
+            .into_iter()
+            .flatten()
+            .map(|i| &self.stop_times[*i])
+    }
+
+    pub fn trips_for_route(&self, route_id: &GtfsID) -> impl Iterator<Item=&GtfsScheduleTrip> {
+        self.trips_by_route.get(route_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|trip_id| self.trips.get(trip_id))
+    }
+
+    /// Platforms, entrances and other stops that have `parent_station` set to `station_id`
+    pub fn child_stops(&self, station_id: &GtfsID) -> impl Iterator<Item=&GtfsScheduleStop> {
+        self.stops_by_parent.get(station_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|stop_id| self.stops.get(stop_id))
+    }
+
+    /// Every route that has at least one trip stopping at `stop_id`
+    pub fn routes_for_stop(&self, stop_id: &GtfsID) -> HashSet<&GtfsID> {
+        self.stop_times_for_stop(stop_id)
+            .filter_map(|stop_time| self.trips.get(&stop_time.trip_id))
+            .map(|trip| &trip.route_id)
+            .collect()
+    }
+
+    /// Every stop served by at least one trip on `route_id`
+    pub fn stops_for_route(&self, route_id: &GtfsID) -> HashSet<&GtfsID> {
+        self.trips_for_route(route_id)
+            .flat_map(|trip| self.stop_times_for_trip(&trip.trip_id))
+            .filter_map(|stop_time| stop_time.stop_id.as_ref())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+    use zip::{ZipArchive, ZipWriter};
+    use crate::gtfs::gtfs_feed::GtfsFeed;
+    use crate::gtfs::gtfs_types::GtfsID;
+
+    pub(crate) const TEST_FILES: [(&str, &str); 6] = [
+        ("agency.txt", "agency_id,agency_name,agency_url,agency_timezone\n\
+            2441,Transit Systems,https://transportnsw.info,Australia/Sydney"),
+        ("stops.txt", "stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station\n\
+            200060,Central Station,-33.8840,151.2063,1,\n\
+            2000322,Central Station Stand A,-33.8837,151.2065,0,200060\n\
+            2000323,Central Station Stand B,-33.8838,151.2066,0,200060\n\
+            2010100,Redfern Station,-33.8917,151.1987,0,"),
+        ("routes.txt", "route_id,agency_id,route_short_name,route_long_name,route_type\n\
+            2441_M30,2441,M30,Mosman to Sydenham,700\n\
+            2441_309,2441,309,Port Botany to Central,700"),
+        ("trips.txt", "route_id,service_id,trip_id\n\
+            2441_M30,weekday,trip_a\n\
+            2441_309,weekday,trip_b"),
+        ("stop_times.txt", "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+            trip_a,08:05:00,08:05:00,2010100,2\n\
+            trip_a,08:00:00,08:00:00,2000322,1\n\
+            trip_b,09:00:00,09:00:00,2000323,1"),
+        ("calendar.txt", "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+            weekday,1,1,1,1,1,0,0,20240401,20240430"),
+    ];
+
+    pub(crate) fn archive_from(files: &[(&str, &str)]) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            writer.start_file(*name, SimpleFileOptions::default()).unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+
+        ZipArchive::new(writer.finish().unwrap()).unwrap()
+    }
+
+    pub(crate) fn test_archive() -> ZipArchive<Cursor<Vec<u8>>> {
+        archive_from(&TEST_FILES)
+    }
+
+    #[test]
+    fn test_feed_indexes() {
+        let feed = GtfsFeed::from_zip(&mut test_archive()).unwrap();
+
+        assert_eq!(feed.stops.len(), 4);
+        assert!(feed.shapes.is_empty());
+
+        let trip_a: Vec<_> = feed.stop_times_for_trip(&GtfsID("trip_a".to_string()))
+            .map(|stop_time| stop_time.stop_sequence)
+            .collect();
+        assert_eq!(trip_a, vec![1, 2]);
+
+        let routes = feed.routes_for_stop(&GtfsID("2010100".to_string()));
+        assert_eq!(routes.len(), 1);
+        assert!(routes.contains(&GtfsID("2441_M30".to_string())));
+
+        assert_eq!(feed.child_stops(&GtfsID("200060".to_string())).count(), 2);
+        assert_eq!(feed.stops_for_route(&GtfsID("2441_309".to_string())).len(), 1);
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use csv::StringRecord;
+use geo::Intersects;
+use geo_types::{MultiPolygon, Point, Polygon, Rect};
+use serde::de::DeserializeOwned;
+use crate::gtfs::gtfs_schedule::{GtfsScheduleRoute, GtfsScheduleShapePoint, GtfsScheduleStop, GtfsScheduleStopTime, GtfsScheduleTrip};
+use crate::gtfs::gtfs_types::GtfsID;
+
+/// Records that can be placed on a map, as (longitude, latitude)
+pub trait GtfsLocated {
+    fn location(&self) -> Option<Point<f64>>;
+}
+
+pub trait GtfsStopReference {
+    fn referenced_stop_id(&self) -> Option<&GtfsID>;
+}
+
+pub trait GtfsRouteReference {
+    fn referenced_route_id(&self) -> &GtfsID;
+}
+
+pub trait GtfsTripReference {
+    fn referenced_trip_id(&self) -> &GtfsID;
+}
+
+impl GtfsLocated for GtfsScheduleStop {
+    fn location(&self) -> Option<Point<f64>> {
+        Some(Point::new(self.stop_longitude?, self.stop_latitude?))
+    }
+}
+
+impl GtfsLocated for GtfsScheduleShapePoint {
+    fn location(&self) -> Option<Point<f64>> {
+        Some(Point::new(self.shape_pt_longitude, self.shape_pt_latitude))
+    }
+}
+
+impl GtfsStopReference for GtfsScheduleStop {
+    fn referenced_stop_id(&self) -> Option<&GtfsID> {
+        Some(&self.stop_id)
+    }
+}
+
+impl GtfsStopReference for GtfsScheduleStopTime {
+    fn referenced_stop_id(&self) -> Option<&GtfsID> {
+        self.stop_id.as_ref()
+    }
+}
+
+impl GtfsRouteReference for GtfsScheduleRoute {
+    fn referenced_route_id(&self) -> &GtfsID {
+        &self.route_id
+    }
+}
+
+impl GtfsRouteReference for GtfsScheduleTrip {
+    fn referenced_route_id(&self) -> &GtfsID {
+        &self.route_id
+    }
+}
+
+impl GtfsTripReference for GtfsScheduleTrip {
+    fn referenced_trip_id(&self) -> &GtfsID {
+        &self.trip_id
+    }
+}
+
+impl GtfsTripReference for GtfsScheduleStopTime {
+    fn referenced_trip_id(&self) -> &GtfsID {
+        &self.trip_id
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GtfsFilterStats {
+    pub read: u64,
+    pub matched: u64,
+}
+
+type GtfsPredicate<T> = Box<dyn Fn(&T) -> bool>;
+
+/// Streams a GTFS file one row at a time, keeping only the rows that pass every predicate.
+///
+/// Each row is deserialised into `T` to test it, but the original row is what gets written out, so
+/// columns that we don't model (like TfNSW's `trip_note`) survive filtering untouched.
+pub struct GtfsStreamFilter<T> {
+    predicates: Vec<GtfsPredicate<T>>,
+}
+
+impl<T> Default for GtfsStreamFilter<T> {
+    fn default() -> Self {
+        GtfsStreamFilter { predicates: Vec::new() }
+    }
+}
+
+impl<T: DeserializeOwned + 'static> GtfsStreamFilter<T> {
+    pub fn new() -> GtfsStreamFilter<T> {
+        GtfsStreamFilter::default()
+    }
+
+    pub fn with_predicate<F: Fn(&T) -> bool + 'static>(mut self, predicate: F) -> GtfsStreamFilter<T> {
+        self.predicates.push(Box::new(predicate));
+        self
+    }
+
+    /// Combines two filters, keeping only records that pass both
+    pub fn and(mut self, other: GtfsStreamFilter<T>) -> GtfsStreamFilter<T> {
+        self.predicates.extend(other.predicates);
+        self
+    }
+
+    /// Keeps records inside `bounds`, including those that sit exactly on the edge
+    pub fn in_bounding_box(self, bounds: Rect<f64>) -> GtfsStreamFilter<T> where T: GtfsLocated {
+        self.with_predicate(move |record| record.location().is_some_and(|point| bounds.intersects(&point)))
+    }
+
+    pub fn in_polygon(self, polygon: Polygon<f64>) -> GtfsStreamFilter<T> where T: GtfsLocated {
+        self.in_multi_polygon(MultiPolygon::new(vec![polygon]))
+    }
+
+    pub fn in_multi_polygon(self, polygon: MultiPolygon<f64>) -> GtfsStreamFilter<T> where T: GtfsLocated {
+        self.with_predicate(move |record| record.location().is_some_and(|point| polygon.intersects(&point)))
+    }
+
+    pub fn with_stops(self, stops: HashSet<GtfsID>) -> GtfsStreamFilter<T> where T: GtfsStopReference {
+        self.with_predicate(move |record| record.referenced_stop_id().is_some_and(|stop_id| stops.contains(stop_id)))
+    }
+
+    pub fn with_routes(self, routes: HashSet<GtfsID>) -> GtfsStreamFilter<T> where T: GtfsRouteReference {
+        self.with_predicate(move |record| routes.contains(record.referenced_route_id()))
+    }
+
+    pub fn with_trips(self, trips: HashSet<GtfsID>) -> GtfsStreamFilter<T> where T: GtfsTripReference {
+        self.with_predicate(move |record| trips.contains(record.referenced_trip_id()))
+    }
+
+    pub fn matches(&self, record: &T) -> bool {
+        self.predicates.iter().all(|predicate| predicate(record))
+    }
+
+    /// Calls `on_match` for every matching row, along with the raw row it was read from.
+    /// Only a single row is held in memory at a time.
+    pub fn for_each_match<R, F>(&self, input: R, on_match: F) -> anyhow::Result<GtfsFilterStats>
+        where R: Read, F: FnMut(&T, &StringRecord) -> anyhow::Result<()> {
+        self.for_each_match_in(&mut csv::ReaderBuilder::new().from_reader(input), on_match)
+    }
+
+    fn for_each_match_in<R, F>(&self, reader: &mut csv::Reader<R>, mut on_match: F) -> anyhow::Result<GtfsFilterStats>
+        where R: Read, F: FnMut(&T, &StringRecord) -> anyhow::Result<()> {
+        let headers = reader.headers()?.clone();
+        let mut row = StringRecord::new();
+        let mut stats = GtfsFilterStats::default();
+
+        while reader.read_record(&mut row)? {
+            stats.read += 1;
+
+            let record: T = row.deserialize(Some(&headers))?;
+            if self.matches(&record) {
+                stats.matched += 1;
+                on_match(&record, &row)?;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Copies every matching row from `input` into `output`, headers included
+    pub fn filter_csv<R: Read, W: Write>(&self, input: R, output: &mut csv::Writer<W>) -> anyhow::Result<GtfsFilterStats> {
+        self.filter_csv_with(input, output, |_| Ok(()))
+    }
+
+    /// As with [GtfsStreamFilter::filter_csv], but also hands each matching record to `on_match`
+    /// after it's been written
+    pub fn filter_csv_with<R, W, F>(&self, input: R, output: &mut csv::Writer<W>, mut on_match: F) -> anyhow::Result<GtfsFilterStats>
+        where R: Read, W: Write, F: FnMut(&T) -> anyhow::Result<()> {
+        let mut reader = csv::ReaderBuilder::new().from_reader(input);
+        output.write_record(reader.headers()?)?;
+
+        let stats = self.for_each_match_in(&mut reader, |record, row| {
+            output.write_record(row)?;
+            on_match(record)
+        })?;
+        output.flush()?;
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use geo_types::{coord, polygon, Rect};
+    use crate::gtfs::gtfs_filter::GtfsStreamFilter;
+    use crate::gtfs::gtfs_schedule::{GtfsScheduleStop, GtfsScheduleStopTime};
+    use crate::gtfs::gtfs_types::GtfsID;
+
+    const STOPS: &str = "stop_id,stop_name,stop_lat,stop_lon,platform_note\n\
+        2000322,Central Station Stand A,-33.8837,151.2065,Stand A\n\
+        2010100,Redfern Station,-33.8917,151.1987,\n\
+        2035143,Dee Why,-33.7537,151.2863,";
+
+    #[test]
+    fn test_bounding_box() {
+        let filter = GtfsStreamFilter::<GtfsScheduleStop>::new()
+            .in_bounding_box(Rect::new(coord! { x: 151.19, y: -33.90 }, coord! { x: 151.21, y: -33.88 }));
+
+        let mut output = csv::Writer::from_writer(Vec::new());
+        let stats = filter.filter_csv(STOPS.as_bytes(), &mut output).unwrap();
+
+        assert_eq!(stats.read, 3);
+        assert_eq!(stats.matched, 2);
+
+        // Columns we don't model are passed through
+        let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "stop_id,stop_name,stop_lat,stop_lon,platform_note\n\
+            2000322,Central Station Stand A,-33.8837,151.2065,Stand A\n\
+            2010100,Redfern Station,-33.8917,151.1987,\n");
+    }
+
+    #[test]
+    fn test_polygon() {
+        let filter = GtfsStreamFilter::<GtfsScheduleStop>::new()
+            .in_polygon(polygon![
+                (x: 151.20, y: -33.88),
+                (x: 151.21, y: -33.88),
+                (x: 151.21, y: -33.89),
+            ]);
+
+        let mut matched = Vec::new();
+        filter.for_each_match(STOPS.as_bytes(), |stop, _| {
+            matched.push(stop.stop_id.clone());
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(matched, vec![GtfsID("2000322".to_string())]);
+    }
+
+    #[test]
+    fn test_stop_and_trip_sets() {
+        let stop_times = "trip_id,stop_id,stop_sequence\n\
+            trip_a,2000322,1\n\
+            trip_a,2010100,2\n\
+            trip_b,2010100,1";
+
+        let filter = GtfsStreamFilter::<GtfsScheduleStopTime>::new()
+            .with_stops(HashSet::from([GtfsID("2010100".to_string())]))
+            .with_trips(HashSet::from([GtfsID("trip_b".to_string())]));
+
+        let stats = filter.for_each_match(stop_times.as_bytes(), |_, _| Ok(())).unwrap();
+        assert_eq!(stats.matched, 1);
+    }
+}
@@ -3,12 +3,20 @@ use serde_repr::{Deserialize_repr, Serialize_repr};
 use url::Url;
 use crate::gtfs::gtfs_schedule::GtfsStopLocationType::Stop;
 use crate::gtfs::gtfs_schedule::GtfsWheelchairBoarding::Unknown;
-use crate::gtfs::gtfs_types::{GtfsID, GtfsTime};
+use crate::gtfs::gtfs_types::{GtfsColourCode, GtfsCurrencyAmount, GtfsCurrencyCode, GtfsDate, GtfsEmail, GtfsID, GtfsLanguageCode, GtfsTime};
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct GTFSAgency {
-    agency_id: String
+pub struct GtfsScheduleAgency {
+    pub agency_id: Option<GtfsID>,
+    pub agency_name: String,
+    pub agency_url: Url,
+    pub agency_timezone: String,
+    pub agency_lang: Option<GtfsLanguageCode>,
+    pub agency_phone: Option<String>,
+    pub agency_fare_url: Option<Url>,
+    pub agency_email: Option<GtfsEmail>
 }
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GtfsScheduleStop {
     pub stop_id: GtfsID,
@@ -30,6 +38,104 @@ pub struct GtfsScheduleStop {
     pub platform_code: Option<String>
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleRoute {
+    pub route_id: GtfsID,
+    pub agency_id: Option<GtfsID>,
+    pub route_short_name: Option<String>,
+    pub route_long_name: Option<String>,
+    pub route_desc: Option<String>,
+    pub route_type: GtfsRouteType,
+    pub route_url: Option<Url>,
+    #[serde(rename = "route_color")]
+    pub route_colour: Option<GtfsColourCode>,
+    #[serde(rename = "route_text_color")]
+    pub route_text_colour: Option<GtfsColourCode>,
+    pub route_sort_order: Option<u32>,
+    pub continuous_pickup: Option<GtfsContinuousPickupDropOff>,
+    pub continuous_drop_off: Option<GtfsContinuousPickupDropOff>,
+    pub network_id: Option<GtfsID>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleTrip {
+    pub route_id: GtfsID,
+    pub service_id: GtfsID,
+    pub trip_id: GtfsID,
+    pub trip_headsign: Option<String>,
+    pub trip_short_name: Option<String>,
+    pub direction_id: Option<GtfsDirection>,
+    pub block_id: Option<GtfsID>,
+    pub shape_id: Option<GtfsID>,
+    pub wheelchair_accessible: Option<GtfsWheelchairAccessible>,
+    pub bikes_allowed: Option<GtfsBikesAllowed>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleCalendar {
+    pub service_id: GtfsID,
+    pub monday: GtfsServiceAvailability,
+    pub tuesday: GtfsServiceAvailability,
+    pub wednesday: GtfsServiceAvailability,
+    pub thursday: GtfsServiceAvailability,
+    pub friday: GtfsServiceAvailability,
+    pub saturday: GtfsServiceAvailability,
+    pub sunday: GtfsServiceAvailability,
+    pub start_date: GtfsDate,
+    pub end_date: GtfsDate
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleCalendarDate {
+    pub service_id: GtfsID,
+    pub date: GtfsDate,
+    pub exception_type: GtfsExceptionType
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleShapePoint {
+    pub shape_id: GtfsID,
+    #[serde(rename = "shape_pt_lat")]
+    pub shape_pt_latitude: f64,
+    #[serde(rename = "shape_pt_lon")]
+    pub shape_pt_longitude: f64,
+    pub shape_pt_sequence: u32,
+    pub shape_dist_traveled: Option<f64>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleTransfer {
+    pub from_stop_id: Option<GtfsID>,
+    pub to_stop_id: Option<GtfsID>,
+    pub from_route_id: Option<GtfsID>,
+    pub to_route_id: Option<GtfsID>,
+    pub from_trip_id: Option<GtfsID>,
+    pub to_trip_id: Option<GtfsID>,
+    pub transfer_type: GtfsTransferType,
+    pub min_transfer_time: Option<u32>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleFareAttribute {
+    pub fare_id: GtfsID,
+    pub price: GtfsCurrencyAmount,
+    pub currency_type: GtfsCurrencyCode,
+    pub payment_method: GtfsPaymentMethod,
+    /// Empty means unlimited transfers are permitted
+    pub transfers: Option<u8>,
+    pub agency_id: Option<GtfsID>,
+    pub transfer_duration: Option<u32>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleFareRule {
+    pub fare_id: GtfsID,
+    pub route_id: Option<GtfsID>,
+    pub origin_id: Option<GtfsID>,
+    pub destination_id: Option<GtfsID>,
+    pub contains_id: Option<GtfsID>
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GtfsScheduleStopTime {
     pub trip_id: GtfsID,
@@ -51,6 +157,38 @@ pub struct GtfsScheduleStopTime {
     pub pickup_booking_rule_id: Option<GtfsID>,
     pub drop_off_booking_rule_id: Option<GtfsID>
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleFrequency {
+    pub trip_id: GtfsID,
+    pub start_time: GtfsTime,
+    pub end_time: GtfsTime,
+    pub headway_secs: u32,
+    pub exact_times: Option<u8>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsSchedulePathway {
+    pub pathway_id: GtfsID,
+    pub from_stop_id: GtfsID,
+    pub to_stop_id: GtfsID,
+    pub pathway_mode: u8,
+    pub is_bidirectional: u8,
+    pub length: Option<f64>,
+    pub traversal_time: Option<u32>,
+    pub stair_count: Option<i32>,
+    pub max_slope: Option<f64>,
+    pub min_width: Option<f64>,
+    pub signposted_as: Option<String>,
+    pub reversed_signposted_as: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GtfsScheduleLevel {
+    pub level_id: GtfsID,
+    pub level_index: f64,
+    pub level_name: Option<String>
+}
 //
 #[repr(u8)]
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
@@ -94,4 +232,283 @@ pub enum GtfsTimeAccuracy {
     Approximate = 0,
     #[default]
     Exact = 1
-}
\ No newline at end of file
+}
+
+/// Declares a C-like enum with codes, plus an `Other` variant for any code that isn't listed
+macro_rules! open_code_enum {
+    ($(#[$meta:meta])* $vis:vis enum $name:ident { $($variant:ident = $code:literal),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        $vis enum $name {
+            $($variant,)*
+            Other(u16),
+        }
+
+        impl $name {
+            pub fn code(&self) -> u16 {
+                match self {
+                    $($name::$variant => $code,)*
+                    $name::Other(code) => *code,
+                }
+            }
+
+            pub fn from_code(code: u16) -> $name {
+                match code {
+                    $($code => $name::$variant,)*
+                    code => $name::Other(code),
+                }
+            }
+        }
+    };
+}
+
+open_code_enum! {
+/// `route_type` values, covering both the basic GTFS types and the
+/// [extended route types](https://developers.google.com/transit/gtfs/reference/extended-route-types)
+/// that TfNSW publishes (eg. 401 for Metro, 700 for buses, 712 for school buses and 900 for light
+/// rail). Codes that aren't listed come through as `Other`, rather than failing all of routes.txt.
+pub enum GtfsRouteType {
+    Tram = 0,
+    Subway = 1,
+    Rail = 2,
+    Bus = 3,
+    Ferry = 4,
+    CableTram = 5,
+    AerialLift = 6,
+    Funicular = 7,
+    Trolleybus = 11,
+    Monorail = 12,
+
+    RailwayService = 100,
+    HighSpeedRailService = 101,
+    LongDistanceTrains = 102,
+    InterRegionalRailService = 103,
+    CarTransportRailService = 104,
+    SleeperRailService = 105,
+    RegionalRailService = 106,
+    TouristRailwayService = 107,
+    RailShuttle = 108,
+    SuburbanRailway = 109,
+    ReplacementRailService = 110,
+    SpecialRailService = 111,
+    AllRailServices = 113,
+    CrossCountryRailService = 114,
+    VehicleTransportRailService = 115,
+    RackAndPinionRailway = 116,
+    AdditionalRailService = 117,
+
+    CoachService = 200,
+    InternationalCoachService = 201,
+    NationalCoachService = 202,
+    ShuttleCoachService = 203,
+    RegionalCoachService = 204,
+    SpecialCoachService = 205,
+    SightseeingCoachService = 206,
+    TouristCoachService = 207,
+    CommuterCoachService = 208,
+    AllCoachServices = 209,
+
+    UrbanRailwayService = 400,
+    MetroService = 401,
+    UndergroundService = 402,
+    AllUrbanRailwayServices = 403,
+    MonorailService = 405,
+
+    BusService = 700,
+    RegionalBusService = 701,
+    ExpressBusService = 702,
+    StoppingBusService = 703,
+    LocalBusService = 704,
+    NightBusService = 705,
+    PostBusService = 706,
+    SpecialNeedsBus = 707,
+    MobilityBusService = 708,
+    MobilityBusForRegisteredDisabled = 709,
+    SightseeingBus = 710,
+    ShuttleBus = 711,
+    SchoolBus = 712,
+    SchoolAndPublicServiceBus = 713,
+    RailReplacementBusService = 714,
+    DemandAndResponseBusService = 715,
+    AllBusServices = 716,
+
+    TrolleybusService = 800,
+
+    TramService = 900,
+    CityTramService = 901,
+    LocalTramService = 902,
+    RegionalTramService = 903,
+    SightseeingTramService = 904,
+    ShuttleTramService = 905,
+    AllTramServices = 906,
+
+    WaterTransportService = 1000,
+    AirService = 1100,
+    FerryService = 1200,
+
+    AerialLiftService = 1300,
+    TelecabinService = 1301,
+    CableCarService = 1302,
+    ElevatorService = 1303,
+    ChairLiftService = 1304,
+    DragLiftService = 1305,
+    SmallTelecabinService = 1306,
+    AllTelecabinServices = 1307,
+
+    FunicularService = 1400,
+
+    TaxiService = 1500,
+    CommunalTaxiService = 1501,
+    WaterTaxiService = 1502,
+    RailTaxiService = 1503,
+    BikeTaxiService = 1504,
+    LicensedTaxiService = 1505,
+    PrivateHireServiceVehicle = 1506,
+    AllTaxiServices = 1507,
+
+    MiscellaneousService = 1700,
+    HorseDrawnCarriage = 1702
+}
+}
+
+impl GtfsRouteType {
+    /// Whether the route is run by buses or coaches, which stop at `highway=bus_stop`s
+    pub fn is_bus(&self) -> bool {
+        matches!(self.code(), 3 | 11 | 200..=299 | 700..=799 | 800)
+    }
+
+    /// The `route` tag for an OSM route relation of this type, if it's one we map
+    pub fn osm_route(&self) -> Option<&'static str> {
+        match self.code() {
+            _ if self.is_bus() => Some("bus"),
+            2 | 100..=117 => Some("train"),
+            1 | 400..=404 => Some("subway"),
+            12 | 405 => Some("monorail"),
+            0 | 5 => Some("tram"),
+            // TfNSW only uses these for light rail
+            900..=906 => Some("light_rail"),
+            4 | 1000 | 1200 => Some("ferry"),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum GtfsDirection {
+    Outbound = 0,
+    Inbound = 1
+}
+
+#[repr(u8)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum GtfsServiceAvailability {
+    Unavailable = 0,
+    Available = 1
+}
+
+#[repr(u8)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum GtfsExceptionType {
+    Added = 1,
+    Removed = 2
+}
+
+#[repr(u8)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum GtfsTransferType {
+    Recommended = 0,
+    Timed = 1,
+    MinimumTime = 2,
+    NotPossible = 3,
+    InSeatTransfer = 4,
+    InSeatTransferNotAllowed = 5
+}
+
+#[repr(u8)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum GtfsPaymentMethod {
+    OnBoard = 0,
+    BeforeBoarding = 1
+}
+
+#[repr(u8)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+pub enum GtfsWheelchairAccessible {
+    Unknown = 0,
+    Accessible = 1,
+    NotAccessible = 2
+}
+
+#[repr(u8)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+pub enum GtfsBikesAllowed {
+    Unknown = 0,
+    Allowed = 1,
+    NotAllowed = 2
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gtfs::gtfs_schedule::{GtfsDirection, GtfsRouteType, GtfsScheduleRoute, GtfsScheduleTrip};
+    use crate::gtfs::gtfs_types::GtfsColourCode;
+
+    #[test]
+    fn test_deserialise_routes() {
+        let routes = "route_id,agency_id,route_short_name,route_long_name,route_desc,route_type,route_color,route_text_color,route_url\n\
+            2441_M30,2441,M30,Mosman to Sydenham,Sydney Buses Network,700,00B5EF,FFFFFF,\n\
+            SMNW_M1,SMNW,M1,Tallawong to Sydenham,Sydney Metro Network,401,168388,FFFFFF,\n\
+            2436_633,2436,633,Pennant Hills to Kellyville,School Buses,712,123456,000000,";
+
+        let routes: Vec<GtfsScheduleRoute> = csv::Reader::from_reader(routes.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(routes.len(), 3);
+        assert_eq!(routes[0].route_type, GtfsRouteType::BusService);
+        assert_eq!(routes[0].route_colour, Some(GtfsColourCode(0x00B5EF)));
+        assert_eq!(routes[0].route_url, None);
+        assert_eq!(routes[1].route_type, GtfsRouteType::MetroService);
+        assert_eq!(routes[2].route_type, GtfsRouteType::SchoolBus);
+        assert_eq!(routes[2].route_colour, Some(GtfsColourCode(0x123456)));
+        assert_eq!(routes[2].route_text_colour, Some(GtfsColourCode(0)));
+    }
+
+    #[test]
+    fn test_unlisted_route_types() {
+        let routes = "route_id,agency_id,route_short_name,route_long_name,route_desc,route_type\n\
+            X_1,X,1,Somewhere to Elsewhere,Mystery Services,404\n\
+            X_2,X,2,Sydney to Melbourne,Flights,1100\n\
+            X_3,X,3,Up the Hill,Test Services,999";
+
+        let routes: Vec<GtfsScheduleRoute> = csv::Reader::from_reader(routes.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(routes[0].route_type, GtfsRouteType::Other(404));
+        assert_eq!(routes[0].route_type.osm_route(), Some("subway"));
+        assert_eq!(routes[1].route_type, GtfsRouteType::AirService);
+        assert_eq!(routes[2].route_type, GtfsRouteType::Other(999));
+        assert_eq!(routes[2].route_type.osm_route(), None);
+        assert_eq!(GtfsRouteType::from_code(999).code(), 999);
+        assert_eq!(GtfsRouteType::from_code(700), GtfsRouteType::BusService);
+    }
+
+    #[test]
+    fn test_deserialise_trips() {
+        let trips = "route_id,service_id,trip_id,shape_id,trip_headsign,direction_id,block_id,wheelchair_accessible,route_direction,trip_note,bikes_allowed\n\
+            2441_M30,1234,1234.1.2441_M30.1,2441_M30_1,Sydenham,0,,1,Mosman to Sydenham,,";
+
+        let trips: Vec<GtfsScheduleTrip> = csv::Reader::from_reader(trips.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(trips.len(), 1);
+        assert_eq!(trips[0].direction_id, Some(GtfsDirection::Outbound));
+        assert!(trips[0].block_id.is_none());
+        assert!(trips[0].bikes_allowed.is_none());
+    }
+}
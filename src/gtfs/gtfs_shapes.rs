@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use geo_types::{Coord, LineString};
+use geojson::{Feature, Geometry, JsonObject};
+use serde_json::Value;
+use crate::gtfs::gtfs_schedule::GtfsScheduleShapePoint;
+use crate::gtfs::gtfs_types::GtfsID;
+
+/// A single shape from shapes.txt, with its points in `shape_pt_sequence` order.
+///
+/// `distances` runs parallel to the coordinates of `line`, since `shape_dist_traveled` is optional
+/// on a per point basis.
+#[derive(Debug, Clone)]
+pub struct GtfsShape {
+    pub shape_id: GtfsID,
+    pub line: LineString<f64>,
+    pub distances: Vec<Option<f64>>,
+}
+
+impl GtfsShape {
+    pub fn from_points(shape_id: GtfsID, mut points: Vec<GtfsScheduleShapePoint>) -> GtfsShape {
+        points.sort_by_key(|point| point.shape_pt_sequence);
+
+        let distances = points.iter().map(|point| point.shape_dist_traveled).collect();
+        let line = points.iter()
+            .map(|point| Coord { x: point.shape_pt_longitude, y: point.shape_pt_latitude })
+            .collect();
+
+        GtfsShape { shape_id, line, distances }
+    }
+
+    pub fn to_geojson_feature(&self) -> Feature {
+        let mut properties = JsonObject::new();
+        properties.insert("shape_id".to_string(), Value::String(self.shape_id.to_string()));
+
+        Feature {
+            bbox: None,
+            geometry: Some(Geometry::from(&self.line)),
+            id: Some(geojson::feature::Id::String(self.shape_id.to_string())),
+            properties: Some(properties),
+            foreign_members: None,
+        }
+    }
+}
+
+/// Groups shapes.txt rows by `shape_id` and assembles each group into a [GtfsShape].
+/// Rows don't need to be sorted, or even grouped together.
+pub fn assemble_shapes<I: IntoIterator<Item=GtfsScheduleShapePoint>>(points: I) -> HashMap<GtfsID, GtfsShape> {
+    let mut grouped: HashMap<GtfsID, Vec<GtfsScheduleShapePoint>> = HashMap::new();
+    for point in points {
+        grouped.entry(point.shape_id.clone()).or_default().push(point);
+    }
+
+    grouped.into_iter()
+        .map(|(shape_id, points)| (shape_id.clone(), GtfsShape::from_points(shape_id, points)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::Coord;
+    use crate::gtfs::gtfs_schedule::GtfsScheduleShapePoint;
+    use crate::gtfs::gtfs_shapes::assemble_shapes;
+    use crate::gtfs::gtfs_types::GtfsID;
+
+    #[test]
+    fn test_assemble_out_of_order() {
+        let shapes = "shape_id,shape_pt_lat,shape_pt_lon,shape_pt_sequence,shape_dist_traveled\n\
+            a,-33.87,151.21,2,100.5\n\
+            b,-33.80,151.10,1,\n\
+            a,-33.86,151.20,1,0\n\
+            a,-33.88,151.22,3,";
+
+        let mut reader = csv::Reader::from_reader(shapes.as_bytes());
+        let shapes = assemble_shapes(reader.deserialize::<GtfsScheduleShapePoint>().map(Result::unwrap));
+
+        assert_eq!(shapes.len(), 2);
+
+        let a = &shapes[&GtfsID("a".to_string())];
+        assert_eq!(a.line.0, vec![
+            Coord { x: 151.20, y: -33.86 },
+            Coord { x: 151.21, y: -33.87 },
+            Coord { x: 151.22, y: -33.88 },
+        ]);
+        assert_eq!(a.distances, vec![Some(0.0), Some(100.5), None]);
+
+        let feature = a.to_geojson_feature();
+        assert_eq!(feature.property("shape_id").and_then(|v| v.as_str()), Some("a"));
+    }
+}
@@ -1,7 +1,7 @@
 use std::fmt::{Display, Formatter};
 use std::num::ParseIntError;
 use std::str::FromStr;
-use chrono::{NaiveTime, TimeDelta};
+use chrono::{NaiveDate, NaiveTime, TimeDelta};
 
 use num_traits::{FromPrimitive, ToPrimitive};
 use serde::{Deserialize, Serialize};
@@ -9,20 +9,22 @@ use serde::{Deserialize, Serialize};
 use crate::make_from_primitive_try_from;
 use crate::try_from_prim;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct GtfsColourCode(pub u32);
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct GtfsCurrencyCode(pub String);
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct GtfsCurrencyAmount(pub f64);
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct GtfsEmail(pub String);
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct GtfsID(pub String);
 #[derive(Debug, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
 pub struct GtfsLanguageCode(pub String);
 #[derive(Debug)]
 pub struct GtfsTime(pub TimeDelta);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GtfsDate(pub NaiveDate);
 
 impl FromStr for GtfsColourCode {
     type Err = ParseIntError;
@@ -33,7 +35,7 @@ impl FromStr for GtfsColourCode {
 }
 impl Display for GtfsColourCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{:X}", self.0))
+        f.write_fmt(format_args!("{:06X}", self.0))
     }
 }
 impl Display for GtfsID {
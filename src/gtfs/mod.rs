@@ -3,4 +3,10 @@
 pub mod gtfs_schedule;
 pub mod gtfs_types;
 pub mod serde;
-pub mod gtfs_chrono;
\ No newline at end of file
+pub mod gtfs_chrono;
+pub mod gtfs_calendar;
+pub mod gtfs_shapes;
+pub mod gtfs_feed;
+pub mod gtfs_filter;
+pub mod gtfs_clip;
+pub mod realtime;
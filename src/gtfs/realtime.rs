@@ -0,0 +1,634 @@
+//! Decoding for GTFS-Realtime feeds, following <https://gtfs.org/realtime/reference/> and the
+//! TfNSW extensions in `tfnsw-gtfs-realtime.proto` (which all hang off field 1007 and up).
+
+use strum::FromRepr;
+use crate::protobuf::{ProtobufError, ProtobufField, ProtobufMessage};
+
+macro_rules! protobuf_enum {
+    ($field:ident as $T:ty) => {
+        <$T>::from_repr($field.as_i32()?)
+    };
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FeedMessage {
+    pub header: FeedHeader,
+    pub entity: Vec<FeedEntity>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FeedHeader {
+    pub gtfs_realtime_version: String,
+    pub incrementality: Option<Incrementality>,
+    /// POSIX time that the feed was generated
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRepr)]
+#[repr(i32)]
+pub enum Incrementality {
+    FullDataset = 0,
+    Differential = 1,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FeedEntity {
+    pub id: String,
+    pub is_deleted: bool,
+    pub trip_update: Option<TripUpdate>,
+    pub vehicle: Option<VehiclePosition>,
+    pub alert: Option<Alert>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TripUpdate {
+    pub trip: TripDescriptor,
+    pub vehicle: Option<VehicleDescriptor>,
+    pub stop_time_update: Vec<StopTimeUpdate>,
+    pub timestamp: Option<u64>,
+    pub delay: Option<i32>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StopTimeEvent {
+    pub delay: Option<i32>,
+    pub time: Option<i64>,
+    pub uncertainty: Option<i32>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StopTimeUpdate {
+    pub stop_sequence: Option<u32>,
+    pub stop_id: Option<String>,
+    pub arrival: Option<StopTimeEvent>,
+    pub departure: Option<StopTimeEvent>,
+    pub schedule_relationship: Option<StopTimeScheduleRelationship>,
+    /// TfNSW extension 1007
+    pub departure_occupancy_status: Option<OccupancyStatus>,
+    /// TfNSW extension 1008
+    pub carriage_seq_predictive_occupancy: Vec<CarriageDescriptor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRepr)]
+#[repr(i32)]
+pub enum StopTimeScheduleRelationship {
+    Scheduled = 0,
+    Skipped = 1,
+    NoData = 2,
+    Unscheduled = 3,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VehiclePosition {
+    pub trip: Option<TripDescriptor>,
+    pub vehicle: Option<VehicleDescriptor>,
+    pub position: Option<Position>,
+    pub current_stop_sequence: Option<u32>,
+    pub stop_id: Option<String>,
+    pub current_status: Option<VehicleStopStatus>,
+    pub timestamp: Option<u64>,
+    pub congestion_level: Option<CongestionLevel>,
+    pub occupancy_status: Option<OccupancyStatus>,
+    pub occupancy_percentage: Option<u32>,
+    /// TfNSW extension 1007, describing each carriage of a train
+    pub consist: Vec<CarriageDescriptor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRepr)]
+#[repr(i32)]
+pub enum VehicleStopStatus {
+    IncomingAt = 0,
+    StoppedAt = 1,
+    InTransitTo = 2,
+}
+
+// The variant names mirror gtfs-realtime.proto
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRepr)]
+#[repr(i32)]
+pub enum CongestionLevel {
+    UnknownCongestionLevel = 0,
+    RunningSmoothly = 1,
+    StopAndGo = 2,
+    Congestion = 3,
+    SevereCongestion = 4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRepr)]
+#[repr(i32)]
+pub enum OccupancyStatus {
+    Empty = 0,
+    ManySeatsAvailable = 1,
+    FewSeatsAvailable = 2,
+    StandingRoomOnly = 3,
+    CrushedStandingRoomOnly = 4,
+    Full = 5,
+    NotAcceptingPassengers = 6,
+    NoDataAvailable = 7,
+    NotBoardable = 8,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Position {
+    pub latitude: f32,
+    pub longitude: f32,
+    pub bearing: Option<f32>,
+    pub odometer: Option<f64>,
+    /// Metres per second
+    pub speed: Option<f32>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TripDescriptor {
+    pub trip_id: Option<String>,
+    pub route_id: Option<String>,
+    pub direction_id: Option<u32>,
+    pub start_time: Option<String>,
+    pub start_date: Option<String>,
+    pub schedule_relationship: Option<TripScheduleRelationship>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRepr)]
+#[repr(i32)]
+pub enum TripScheduleRelationship {
+    Scheduled = 0,
+    Added = 1,
+    Unscheduled = 2,
+    Canceled = 3,
+    Replacement = 5,
+    Duplicated = 6,
+    Deleted = 7,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VehicleDescriptor {
+    pub id: Option<String>,
+    pub label: Option<String>,
+    pub license_plate: Option<String>,
+    /// TfNSW extension 1007
+    pub tfnsw_vehicle_descriptor: Option<TfnswVehicleDescriptor>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TfnswVehicleDescriptor {
+    pub air_conditioned: Option<bool>,
+    pub wheelchair_accessible: Option<i32>,
+    pub vehicle_model: Option<String>,
+    pub performing_prior_trip: Option<bool>,
+    pub special_vehicle_attributes: Option<i32>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CarriageDescriptor {
+    pub name: Option<String>,
+    pub position_in_consist: i32,
+    pub occupancy_status: Option<OccupancyStatus>,
+    pub quiet_carriage: Option<bool>,
+    pub toilet: Option<ToiletStatus>,
+    pub luggage_rack: Option<bool>,
+    pub departure_occupancy_status: Option<OccupancyStatus>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRepr)]
+#[repr(i32)]
+pub enum ToiletStatus {
+    None = 0,
+    Normal = 1,
+    Accessible = 2,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Alert {
+    pub active_period: Vec<TimeRange>,
+    pub informed_entity: Vec<EntitySelector>,
+    pub cause: Option<AlertCause>,
+    pub effect: Option<AlertEffect>,
+    pub url: Option<TranslatedString>,
+    pub header_text: Option<TranslatedString>,
+    pub description_text: Option<TranslatedString>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRepr)]
+#[repr(i32)]
+pub enum AlertCause {
+    UnknownCause = 1,
+    OtherCause = 2,
+    TechnicalProblem = 3,
+    Strike = 4,
+    Demonstration = 5,
+    Accident = 6,
+    Holiday = 7,
+    Weather = 8,
+    Maintenance = 9,
+    Construction = 10,
+    PoliceActivity = 11,
+    MedicalEmergency = 12,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRepr)]
+#[repr(i32)]
+pub enum AlertEffect {
+    NoService = 1,
+    ReducedService = 2,
+    SignificantDelays = 3,
+    Detour = 4,
+    AdditionalService = 5,
+    ModifiedService = 6,
+    OtherEffect = 7,
+    UnknownEffect = 8,
+    StopMoved = 9,
+    NoEffect = 10,
+    AccessibilityIssue = 11,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TimeRange {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EntitySelector {
+    pub agency_id: Option<String>,
+    pub route_id: Option<String>,
+    pub route_type: Option<i32>,
+    pub trip: Option<TripDescriptor>,
+    pub stop_id: Option<String>,
+    pub direction_id: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TranslatedString {
+    pub translation: Vec<Translation>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Translation {
+    pub text: String,
+    pub language: Option<String>,
+}
+
+impl TranslatedString {
+    /// The translation for `language`, falling back to the untagged translation if there is one
+    pub fn text(&self, language: &str) -> Option<&str> {
+        self.translation.iter()
+            .find(|translation| translation.language.as_deref() == Some(language))
+            .or_else(|| self.translation.iter().find(|translation| translation.language.is_none()))
+            .map(|translation| translation.text.as_str())
+    }
+}
+
+impl ProtobufMessage for FeedMessage {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.header = field.as_message()?,
+            2 => self.entity.push(field.as_message()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for FeedHeader {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.gtfs_realtime_version = field.as_string()?,
+            2 => self.incrementality = protobuf_enum!(field as Incrementality),
+            3 => self.timestamp = Some(field.as_u64()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for FeedEntity {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.id = field.as_string()?,
+            2 => self.is_deleted = field.as_bool()?,
+            3 => self.trip_update = Some(field.as_message()?),
+            4 => self.vehicle = Some(field.as_message()?),
+            5 => self.alert = Some(field.as_message()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for TripUpdate {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.trip = field.as_message()?,
+            2 => self.stop_time_update.push(field.as_message()?),
+            3 => self.vehicle = Some(field.as_message()?),
+            4 => self.timestamp = Some(field.as_u64()?),
+            5 => self.delay = Some(field.as_i32()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for StopTimeEvent {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.delay = Some(field.as_i32()?),
+            2 => self.time = Some(field.as_i64()?),
+            3 => self.uncertainty = Some(field.as_i32()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for StopTimeUpdate {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.stop_sequence = Some(field.as_u32()?),
+            2 => self.arrival = Some(field.as_message()?),
+            3 => self.departure = Some(field.as_message()?),
+            4 => self.stop_id = Some(field.as_string()?),
+            5 => self.schedule_relationship = protobuf_enum!(field as StopTimeScheduleRelationship),
+            1007 => self.departure_occupancy_status = protobuf_enum!(field as OccupancyStatus),
+            1008 => self.carriage_seq_predictive_occupancy.push(field.as_message()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for VehiclePosition {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.trip = Some(field.as_message()?),
+            2 => self.position = Some(field.as_message()?),
+            3 => self.current_stop_sequence = Some(field.as_u32()?),
+            4 => self.current_status = protobuf_enum!(field as VehicleStopStatus),
+            5 => self.timestamp = Some(field.as_u64()?),
+            6 => self.congestion_level = protobuf_enum!(field as CongestionLevel),
+            7 => self.stop_id = Some(field.as_string()?),
+            8 => self.vehicle = Some(field.as_message()?),
+            9 => self.occupancy_status = protobuf_enum!(field as OccupancyStatus),
+            10 => self.occupancy_percentage = Some(field.as_u32()?),
+            1007 => self.consist.push(field.as_message()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for Position {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.latitude = field.as_f32()?,
+            2 => self.longitude = field.as_f32()?,
+            3 => self.bearing = Some(field.as_f32()?),
+            4 => self.odometer = Some(field.as_f64()?),
+            5 => self.speed = Some(field.as_f32()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for TripDescriptor {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.trip_id = Some(field.as_string()?),
+            2 => self.start_time = Some(field.as_string()?),
+            3 => self.start_date = Some(field.as_string()?),
+            4 => self.schedule_relationship = protobuf_enum!(field as TripScheduleRelationship),
+            5 => self.route_id = Some(field.as_string()?),
+            6 => self.direction_id = Some(field.as_u32()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for VehicleDescriptor {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.id = Some(field.as_string()?),
+            2 => self.label = Some(field.as_string()?),
+            3 => self.license_plate = Some(field.as_string()?),
+            1007 => self.tfnsw_vehicle_descriptor = Some(field.as_message()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for TfnswVehicleDescriptor {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.air_conditioned = Some(field.as_bool()?),
+            2 => self.wheelchair_accessible = Some(field.as_i32()?),
+            3 => self.vehicle_model = Some(field.as_string()?),
+            4 => self.performing_prior_trip = Some(field.as_bool()?),
+            5 => self.special_vehicle_attributes = Some(field.as_i32()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for CarriageDescriptor {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.name = Some(field.as_string()?),
+            2 => self.position_in_consist = field.as_i32()?,
+            3 => self.occupancy_status = protobuf_enum!(field as OccupancyStatus),
+            4 => self.quiet_carriage = Some(field.as_bool()?),
+            5 => self.toilet = protobuf_enum!(field as ToiletStatus),
+            6 => self.luggage_rack = Some(field.as_bool()?),
+            7 => self.departure_occupancy_status = protobuf_enum!(field as OccupancyStatus),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for Alert {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.active_period.push(field.as_message()?),
+            5 => self.informed_entity.push(field.as_message()?),
+            6 => self.cause = protobuf_enum!(field as AlertCause),
+            7 => self.effect = protobuf_enum!(field as AlertEffect),
+            8 => self.url = Some(field.as_message()?),
+            10 => self.header_text = Some(field.as_message()?),
+            11 => self.description_text = Some(field.as_message()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for TimeRange {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.start = Some(field.as_u64()?),
+            2 => self.end = Some(field.as_u64()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for EntitySelector {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.agency_id = Some(field.as_string()?),
+            2 => self.route_id = Some(field.as_string()?),
+            3 => self.route_type = Some(field.as_i32()?),
+            4 => self.trip = Some(field.as_message()?),
+            5 => self.stop_id = Some(field.as_string()?),
+            6 => self.direction_id = Some(field.as_u32()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for TranslatedString {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        if field.number == 1 {
+            self.translation.push(field.as_message()?);
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for Translation {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.text = field.as_string()?,
+            2 => self.language = Some(field.as_string()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl FeedMessage {
+    pub fn trip_updates(&self) -> impl Iterator<Item=&TripUpdate> {
+        self.entity.iter().filter_map(|entity| entity.trip_update.as_ref())
+    }
+
+    pub fn vehicle_positions(&self) -> impl Iterator<Item=&VehiclePosition> {
+        self.entity.iter().filter_map(|entity| entity.vehicle.as_ref())
+    }
+
+    pub fn alerts(&self) -> impl Iterator<Item=&Alert> {
+        self.entity.iter().filter_map(|entity| entity.alert.as_ref())
+    }
+
+    /// Every stop that a trip update says will be (or was) served, skipping cancelled trips and
+    /// skipped stops
+    pub fn served_stop_ids(&self) -> impl Iterator<Item=&str> {
+        self.trip_updates()
+            .filter(|update| update.trip.schedule_relationship != Some(TripScheduleRelationship::Canceled))
+            .flat_map(|update| update.stop_time_update.iter())
+            .filter(|update| update.schedule_relationship != Some(StopTimeScheduleRelationship::Skipped))
+            .filter_map(|update| update.stop_id.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gtfs::realtime::{AlertEffect, FeedMessage, Incrementality, OccupancyStatus, StopTimeScheduleRelationship, TripScheduleRelationship, VehicleStopStatus};
+    use crate::protobuf::ProtobufMessage;
+    use crate::protobuf::tests::ProtobufWriter;
+
+    const TRIP_UPDATES: &[u8] = include_bytes!("../../fixtures/gtfs_realtime/realtime_buses.pb");
+    const VEHICLE_POSITIONS: &[u8] = include_bytes!("../../fixtures/gtfs_realtime/vehiclepos_sydneytrains.pb");
+
+    #[test]
+    fn test_decode_trip_updates() {
+        let feed = FeedMessage::decode(TRIP_UPDATES).unwrap();
+
+        assert_eq!(feed.header.gtfs_realtime_version, "2.0");
+        assert_eq!(feed.header.incrementality, Some(Incrementality::FullDataset));
+        assert_eq!(feed.entity.len(), 2);
+
+        let update = feed.trip_updates().next().unwrap();
+        assert_eq!(update.trip.trip_id.as_deref(), Some("1234.1.2441_M30.1"));
+        assert_eq!(update.trip.schedule_relationship, Some(TripScheduleRelationship::Scheduled));
+        assert_eq!(update.stop_time_update.len(), 2);
+        assert_eq!(update.stop_time_update[0].arrival.as_ref().and_then(|event| event.delay), Some(-30));
+        assert_eq!(update.stop_time_update[1].schedule_relationship, Some(StopTimeScheduleRelationship::Skipped));
+        assert_eq!(update.stop_time_update[0].departure_occupancy_status, Some(OccupancyStatus::ManySeatsAvailable));
+
+        // The second trip is cancelled, and the second stop of the first was skipped
+        assert_eq!(feed.served_stop_ids().collect::<Vec<_>>(), vec!["2000322"]);
+    }
+
+    #[test]
+    fn test_decode_vehicle_positions() {
+        let feed = FeedMessage::decode(VEHICLE_POSITIONS).unwrap();
+
+        let vehicle = feed.vehicle_positions().next().unwrap();
+        let position = vehicle.position.as_ref().unwrap();
+        assert!((position.latitude - -33.8840).abs() < 1e-4);
+        assert!((position.longitude - 151.2063).abs() < 1e-4);
+        assert_eq!(vehicle.current_status, Some(VehicleStopStatus::StoppedAt));
+        assert_eq!(vehicle.stop_id.as_deref(), Some("2000336"));
+
+        let descriptor = vehicle.vehicle.as_ref().and_then(|vehicle| vehicle.tfnsw_vehicle_descriptor.as_ref()).unwrap();
+        assert_eq!(descriptor.vehicle_model.as_deref(), Some("Waratah"));
+        assert_eq!(descriptor.air_conditioned, Some(true));
+
+        assert_eq!(vehicle.consist.len(), 2);
+        assert_eq!(vehicle.consist[1].position_in_consist, 2);
+        assert_eq!(vehicle.consist[1].quiet_carriage, Some(true));
+    }
+
+    #[test]
+    fn test_decode_alerts() {
+        let translation = |text: &str, language: Option<&str>| {
+            let translation = ProtobufWriter::default().string(1, text);
+            ProtobufWriter::default().message(1, match language {
+                Some(language) => translation.string(2, language),
+                None => translation,
+            })
+        };
+
+        let alert = ProtobufWriter::default()
+            .message(1, ProtobufWriter::default().uint(1, 1713139200).uint(2, 1713225600))
+            .message(5, ProtobufWriter::default().string(5, "2000322"))
+            .uint(7, 9)
+            .message(10, translation("Stop moved", None))
+            .message(11, translation("Buses depart from Stand B", Some("en")));
+
+        let feed = ProtobufWriter::default()
+            .message(1, ProtobufWriter::default().string(1, "2.0"))
+            .message(2, ProtobufWriter::default().string(1, "alert").message(5, alert));
+
+        let feed = FeedMessage::decode(&feed.0).unwrap();
+        let alert = feed.alerts().next().unwrap();
+
+        assert_eq!(alert.effect, Some(AlertEffect::StopMoved));
+        assert_eq!(alert.informed_entity[0].stop_id.as_deref(), Some("2000322"));
+        assert_eq!(alert.active_period[0].end, Some(1713225600));
+        assert_eq!(alert.header_text.as_ref().and_then(|text| text.text("en")), Some("Stop moved"));
+        assert_eq!(alert.description_text.as_ref().and_then(|text| text.text("en")), Some("Buses depart from Stand B"));
+    }
+}
@@ -1,7 +1,8 @@
 pub mod serialisation {
     use serde::{ser, Serialize, Serializer};
 
-    use crate::gtfs::gtfs_types::{GtfsColourCode, GtfsID, GtfsTime};
+    use crate::gtfs::gtfs_schedule::GtfsRouteType;
+    use crate::gtfs::gtfs_types::{GtfsColourCode, GtfsDate, GtfsID, GtfsTime};
 
     macro_rules! create_serde_try_into_serialiser {
         ($T:ty, $serialize: ident) => (
@@ -43,6 +44,29 @@ pub mod serialisation {
             serializer.serialize_str(&self.to_string())
         }
     }
+
+    impl Serialize for GtfsDate {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+            serializer.serialize_str(&self.to_string())
+        }
+    }
+
+    // GTFS files store colours as six digit hex strings, so that's what readable formats get
+    impl Serialize for GtfsColourCode {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+            if serializer.is_human_readable() {
+                serializer.serialize_str(&self.to_string())
+            } else {
+                serializer.serialize_u32(self.0)
+            }
+        }
+    }
+
+    impl Serialize for GtfsRouteType {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+            serializer.serialize_u16(self.code())
+        }
+    }
 }
 
 pub mod deserialisation {
@@ -51,7 +75,8 @@ pub mod deserialisation {
     use serde::de::Error;
     use crate::errors::IntoAnyhowError;
 
-    use crate::gtfs::gtfs_types::{GtfsColourCode, GtfsTime};
+    use crate::gtfs::gtfs_schedule::GtfsRouteType;
+    use crate::gtfs::gtfs_types::{GtfsColourCode, GtfsDate, GtfsTime};
 
     struct GTFSVisitor<T>(PhantomData<T>);
 
@@ -116,17 +141,48 @@ pub mod deserialisation {
             String::deserialize(deserializer)?.parse().map_err(Error::custom)
         }
     }
+
+    impl<'de> Deserialize<'de> for GtfsDate {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+            String::deserialize(deserializer)?.parse().map_err(Error::custom)
+        }
+    }
+
+    // We can't use deserialize_any here, since csv will happily hand us "123456" as a decimal integer
+    impl<'de> Deserialize<'de> for GtfsColourCode {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+            if deserializer.is_human_readable() {
+                String::deserialize(deserializer)?.parse().map_err(Error::custom)
+            } else {
+                u32::deserialize(deserializer).map(GtfsColourCode)
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for GtfsRouteType {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+            u16::deserialize(deserializer).map(GtfsRouteType::from_code)
+        }
+    }
 }
 
 #[cfg(test)]
 mod serde_tests {
-    use serde_test::{assert_ser_tokens, Token};
+    use serde_test::{assert_de_tokens, assert_ser_tokens, Configure, Token};
 
     use crate::gtfs::gtfs_types::GtfsColourCode;
 
     #[test]
     fn test_serialisation() {
         let colour_code = GtfsColourCode(0xDEADBEEF);
-        assert_ser_tokens(&colour_code, &[Token::U32(0xDEADBEEF)])
+        assert_ser_tokens(&colour_code.compact(), &[Token::U32(0xDEADBEEF)])
+    }
+
+    #[test]
+    fn test_readable_colour_code() {
+        let colour_code = GtfsColourCode(0x00B5EF);
+        assert_ser_tokens(&colour_code.readable(), &[Token::Str("00B5EF")]);
+        assert_de_tokens(&colour_code.readable(), &[Token::Str("00B5EF")]);
+        assert_de_tokens(&GtfsColourCode(0x123456).readable(), &[Token::Str("123456")]);
     }
 }
\ No newline at end of file
@@ -4,11 +4,11 @@ use std::{env, fs};
 use std::cmp::PartialEq;
 use std::fs::File;
 use std::io::{BufWriter, IsTerminal, stderr, stdout};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use dotenvy::dotenv;
 use zip::ZipArchive;
-use crate::gtfs::gtfs_schedule::{GtfsScheduleStop, GtfsScheduleStopTime};
-use crate::transport_nswapi::TransportNswApiClient;
+use crate::gtfs::gtfs_schedule::{GtfsScheduleCalendar, GtfsScheduleCalendarDate, GtfsScheduleTrip};
+use crate::transport_nswapi::{TransportNswApiClient, TransportNswMode};
 
 mod transport_nswapi;
 mod osm_api_client;
@@ -20,12 +20,22 @@ mod errors;
 mod configs;
 mod rnd;
 mod tests;
+mod target_area;
+mod protobuf;
+mod resource_cache;
+mod download;
+mod rate_limit;
+mod trip_planner;
+mod osm;
+mod conflation;
 
 use std::io::Write;
 use std::num::{IntErrorKind, ParseIntError};
+use std::rc::Rc;
+use std::sync::Arc;
 use std::str::FromStr;
 use anyhow::bail;
-use chrono::{NaiveTime, TimeDelta};
+use chrono::{NaiveDate, NaiveTime, TimeDelta};
 use config::builder::DefaultState;
 use config::Config;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressIterator, ProgressStyle};
@@ -34,27 +44,94 @@ use rand::{Rng, thread_rng};
 use rand::distributions::uniform::SampleRange;
 use rand::rngs::ThreadRng;
 use serde::Deserialize;
+use crate::conflation::route_validation::validate_route_relations;
+use crate::conflation::routes::{propose_route_relations, RouteProposal, RouteRelationOptions};
+use crate::conflation::stop_changes::{propose_stop_changes, StopChangeOptions};
+use crate::conflation::stops::{conflate_stops, StopConflationOptions};
 use crate::configs::{build_config, ConfigBuilderOptions};
 use crate::configs::ConfigPath::Optional;
+use crate::gtfs::gtfs_calendar::GtfsServiceCalendar;
+use crate::gtfs::gtfs_clip::{clip_feeds, GtfsClipOptions};
+use crate::gtfs::gtfs_feed::{read_gtfs_file, GtfsFeed};
 use crate::gtfs::gtfs_chrono::GtfsLexingError::ParseInt;
-use crate::gtfs::gtfs_types::GtfsTime;
+use crate::gtfs::gtfs_filter::{GtfsLocated, GtfsStreamFilter};
+use crate::gtfs::gtfs_types::{GtfsID, GtfsTime};
+use crate::osm::osm_change::{write_josm_osm, write_osm_change};
+use crate::osm::osm_elements::{is_bus_road, is_public_transport, OsmTags};
+use crate::osm::osm_overpass::{OverpassClient, OverpassQuery};
+use crate::osm::osm_pbf::OsmPbfReader;
+use crate::osm_api_client::{changeset_tags, OsmApiServer, OsmRouter};
+use crate::rate_limit::{SharedRateLimiter, TransportNswQuota};
+use crate::resource_cache::ResourceCache;
 use crate::rnd::RandomTarget;
+use crate::target_area::{check_unique_names, deserialize_targets, TransportNswTargetSuburb};
 
+const DEFAULT_OUTPUT_TEMPLATE: &str = "gtfs_{name}.zip";
+const DEFAULT_CHANGE_TEMPLATE: &str = "stops_{name}.osc";
+const DEFAULT_JOSM_TEMPLATE: &str = "stops_{name}.osm";
+const DEFAULT_ROUTE_REPORT_TEMPLATE: &str = "routes_{name}.json";
+
+/// Where to get OSM data from, and where to write the proposed stop changes for each area
 #[derive(Debug, Deserialize)]
-struct TransportNswConfig {
-    api_key: String,
-    target_suburb: TransportNswTargetSuburb,
+struct OsmConflationConfig {
+    /// An `.osm.pbf` extract to read, rather than querying Overpass
+    pbf_extract: Option<PathBuf>,
+    /// The Overpass `interpreter` URL, when not using an extract; defaults to the public instance
+    overpass_endpoint: Option<String>,
+    /// The `network` tag to give stops
+    network: Option<String>,
+    /// Where each area's osmChange is written, with `{name}` replaced by the area's name
+    change_template: Option<String>,
+    /// Where each area's JOSM file is written, with `{name}` replaced by the area's name
+    josm_template: Option<String>,
+    /// Where each area's route relation report is written as JSON, with `{name}` replaced by the
+    /// area's name
+    route_report_template: Option<String>,
+    /// GTFS routes to propose new route relations for, unless OSM already has a relation with the
+    /// same `ref`. Roads are fetched too when any are listed, so the relations can be given ways.
+    #[serde(default)]
+    route_relations: Vec<GtfsID>,
+    /// If set, each area's changes are uploaded as a changeset too
+    upload: Option<OsmUploadConfig>,
 }
 
 #[derive(Debug, Deserialize)]
-enum TransportNswTargetSuburb {
-    Static {
-        name: String,
-        min_latitude: f64,
-        max_latitude: f64,
-        min_longitude: f64,
-        max_longitude: f64,
-    }
+struct OsmUploadConfig {
+    /// The dev sandbox unless set otherwise. The OSM data always comes from the real map, so any
+    /// other server is only sent changes that create new elements referring to each other, and
+    /// other areas' uploads are skipped.
+    #[serde(default)]
+    server: OsmApiServer,
+    /// An OAuth2 access token with the `write_api` scope
+    access_token: String,
+    /// The changeset comment, with `{name}` replaced by the area's name
+    comment: String,
+    /// Nothing is uploaded unless this is set; otherwise we just say what would have been
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TransportNswConfig {
+    api_key: String,
+    /// Every area to extract, or just the one; the feed is only read once no matter how many there
+    /// are
+    #[serde(deserialize_with = "deserialize_targets")]
+    target_suburb: Vec<TransportNswTargetSuburb>,
+    /// Where each area's feed is written, with `{name}` replaced by the area's name
+    output_template: Option<String>,
+    /// Only keep trips that run on this date, rather than every trip in the feed
+    service_date: Option<NaiveDate>,
+    /// If set, the complete GTFS feed is downloaded into this directory and only fetched again
+    /// once it changes, rather than being read from a local copy
+    gtfs_cache: Option<PathBuf>,
+    /// Download just this mode's timetable instead of the complete feed, when using `gtfs_cache`
+    gtfs_mode: Option<TransportNswMode>,
+    /// Our share of the API quota, which is only tracked across runs if it has a state file
+    #[serde(default)]
+    rate_limit: TransportNswQuota,
+    /// If set, each area's stops are conflated against OSM once its feed has been written
+    osm: Option<OsmConflationConfig>,
 }
 
 #[tokio::main]
@@ -80,86 +157,148 @@ async fn main() -> anyhow::Result<()> {
     // let get_complete = client.timetables().get_complete_gtfs().await?;
     // println!("{get_complete:?}");
 
-    let mut schedule = ZipArchive::new(File::open("full_greater_sydney_gtfs_static_0.zip")?)?;
+    let mut schedule = match &settings.gtfs_cache {
+        Some(directory) => {
+            let client = TransportNswApiClient::new(&settings.api_key)?
+                .with_rate_limiter(Arc::new(SharedRateLimiter::new(settings.rate_limit.clone())));
+            let cache = ResourceCache::new(directory)?;
 
-    let (suburb_name, suburb_lat, suburb_lon) = match settings.target_suburb {
-        TransportNswTargetSuburb::Static { name, min_latitude, max_latitude, min_longitude, max_longitude } => (name, min_latitude..=max_latitude, min_longitude..=max_longitude)
+            match &settings.gtfs_mode {
+                Some(mode) => client.timetables().get_mode_gtfs_cached(mode, &cache).await?.into_value(),
+                None => client.timetables().get_complete_gtfs_cached(&cache).await?.into_value(),
+            }
+        }
+        None => ZipArchive::new(File::open("full_greater_sydney_gtfs_static_0.zip")?)?,
     };
 
-    // let mut output = BufWriter::new(File::create("stops_out.txt")?);
-    let mut i = 0;
+    let target_areas = settings.target_suburb.iter()
+        .map(|target| target.resolve())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    check_unique_names(&target_areas)?;
 
-    eprintln!("Testing!");
-
-    let mut suburb_stops_vec = Vec::new();
-
-    macro_rules! make_csv_reader {
-        ($name:tt) => {csv::ReaderBuilder::new().from_reader(schedule.by_name($name)?)};
-    }
+    let service_calendar = match settings.service_date {
+        Some(_) => Some(Rc::new(GtfsServiceCalendar::new(
+            read_gtfs_file::<_, GtfsScheduleCalendar>(&mut schedule, "calendar.txt", false)?,
+            read_gtfs_file::<_, GtfsScheduleCalendarDate>(&mut schedule, "calendar_dates.txt", false)?,
+        ))),
+        None => None
+    };
 
-    {
-        let mut stops_reader = make_csv_reader!("stops.txt");
-        let mut suburb_stops_out = csv::WriterBuilder::new()
-            .double_quote(true)
-            .from_writer(BufWriter::new(File::create(format!("stops_{}.csv", suburb_name))?));
-
-        // Loop over each record.
-        for result in stops_reader.deserialize() {
-            let record: GtfsScheduleStop = result?;
-            if let Some(lat) = record.stop_latitude {
-                if let Some(lon) = record.stop_longitude {
-                    if suburb_lat.contains(&lat) && suburb_lon.contains(&lon) {
-                        i += 1;
-                        print!("\rRecord {i}");
-                        suburb_stops_out.serialize(&record)?;
-                        suburb_stops_vec.push(record);
-                    }
-                }
-            }
+    let output_template = settings.output_template.as_deref().unwrap_or(DEFAULT_OUTPUT_TEMPLATE);
+    let mut targets = Vec::with_capacity(target_areas.len());
+    let mut output_paths = Vec::with_capacity(target_areas.len());
+    for target_area in &target_areas {
+        let mut trips_filter = GtfsStreamFilter::<GtfsScheduleTrip>::new();
+        if let (Some(service_date), Some(service_calendar)) = (settings.service_date, service_calendar.clone()) {
+            trips_filter = trips_filter.with_predicate(move |trip| service_calendar.is_service_active(&trip.service_id, service_date));
         }
 
-        suburb_stops_out.flush()?;
+        let options = GtfsClipOptions::new(GtfsStreamFilter::new().in_multi_polygon(target_area.area.clone()))
+            .with_trips(trips_filter);
+        let output_path = output_template.replace("{name}", &target_area.file_name());
+        let output = BufWriter::new(File::create(&output_path)?);
+
+        targets.push((options, output));
+        output_paths.push(output_path);
     }
 
-    {
-        println!("Getting stop times count");
-        let stop_times_count = make_csv_reader!("stop_times.txt").records().count();
+    let bar = ProgressBar::new(0)
+        .with_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7} {msg}")?);
+    bar.set_draw_target(ProgressDrawTarget::stdout());
 
-        let bar = ProgressBar::new(stop_times_count as u64)
-            .with_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")?);
+    let summaries = clip_feeds(&mut schedule, targets, &bar)?;
+    bar.finish_and_clear();
 
-        bar.set_draw_target(ProgressDrawTarget::stdout());
+    for (target_area, summary) in target_areas.iter().zip(summaries) {
+        println!("{}:", target_area.name);
+
+        for (file, stats) in summary.files {
+            println!("  {file}: kept {} of {} records", stats.matched, stats.read);
+        }
+    }
 
-        println!("Making reader");
-        let mut stop_times_reader = make_csv_reader!("stop_times.txt");
+    if let Some(osm_settings) = &settings.osm {
+        let overpass = match &osm_settings.overpass_endpoint {
+            Some(endpoint) => OverpassClient::with_endpoint(endpoint.as_str())?,
+            None => OverpassClient::new()?,
+        };
+        let change_options = StopChangeOptions { network: osm_settings.network.clone(), ..Default::default() };
+        let route_options = RouteRelationOptions { network: osm_settings.network.clone(), ..Default::default() };
 
-        let mut suburb_stop_times_out = csv::WriterBuilder::new()
-            .double_quote(true)
-            .from_writer(BufWriter::new(File::create(format!("stop_times_{}.csv", suburb_name))?));
-        i = 0;
-        let mut s = 0;
+        // Roads are only needed to give new route relations their ways
+        let with_roads = !osm_settings.route_relations.is_empty();
+        let keep: fn(&OsmTags) -> bool = if with_roads { |tags| is_public_transport(tags) || is_bus_road(tags) } else { is_public_transport };
+        // Reading the extract is the slow part, so every area comes out of the same pass
+        let mut extracts = match &osm_settings.pbf_extract {
+            Some(extract) => OsmPbfReader::open(extract)?.extract_areas(&target_areas, keep)?,
+            None => Vec::new(),
+        }.into_iter();
 
-        println!("{}", std::io::stdout().is_terminal());
+        for (target_area, output_path) in target_areas.iter().zip(&output_paths) {
+            let feed = GtfsFeed::from_zip(&mut ZipArchive::new(File::open(output_path)?)?)?;
+            let osm = match extracts.next() {
+                Some(osm) => osm,
+                None if with_roads => overpass.query(&OverpassQuery::public_transport(target_area.into()).with_bus_roads()).await?,
+                None => overpass.query(&OverpassQuery::public_transport(target_area.into())).await?,
+            };
 
-        println!("Running!!");
-        for_iter!(result in stop_times_reader.deserialize().progress_with(bar), |iter| {
-            let record: GtfsScheduleStopTime = result?;
-            if let Some(ref stop_id) = record.stop_id {
-                if suburb_stops_vec.iter().any(|stop| &stop.stop_id == stop_id) {
-                    i += 1;
-                    suburb_stop_times_out.serialize(&record)?;
-                    iter.progress.set_message(format!("Found {i} stop times in {suburb_name}"))
+            // The clipped feed keeps every stop its trips call at, even outside the area, and those
+            // would all look like they're missing from OSM since we only fetched the area itself
+            let stops_in_area = feed.stops.values().filter(|stop| stop.location().is_some_and(|location| target_area.contains(&location)));
+            let conflation = conflate_stops(stops_in_area, &osm, &StopConflationOptions::default());
+            let mut changes = propose_stop_changes(&conflation, &feed, &change_options);
+            let mut already_mapped = Vec::new();
+            let mut nothing_in_osm = Vec::new();
+            for route_id in osm_settings.route_relations.iter().filter(|route_id| feed.route(route_id).is_some()) {
+                match propose_route_relations(&feed, route_id, &conflation, &osm, &route_options, &mut changes)? {
+                    RouteProposal::AlreadyMapped(existing) => already_mapped.push((route_id, existing)),
+                    RouteProposal::NothingInOsm => nothing_in_osm.push(route_id),
+                    _ => {}
                 }
             }
 
-            s += 1;
+            let change_path = osm_settings.change_template.as_deref().unwrap_or(DEFAULT_CHANGE_TEMPLATE).replace("{name}", &target_area.file_name());
+            write_osm_change(&changes.change, None, BufWriter::new(File::create(change_path)?))?.flush()?;
+            let josm_path = osm_settings.josm_template.as_deref().unwrap_or(DEFAULT_JOSM_TEMPLATE).replace("{name}", &target_area.file_name());
+            write_josm_osm(&changes.change, &changes.evidence, BufWriter::new(File::create(josm_path)?))?.flush()?;
 
-            if s % 100 == 0 {
-                suburb_stop_times_out.flush()?
+            println!("{}: {} stops matched, {} only in GTFS, {} only in OSM, {} ambiguous; {} to create and {} to update, and {} new relations",
+                target_area.name, conflation.matched.len(), conflation.gtfs_only.len(), conflation.osm_only.len(), conflation.ambiguous.len(),
+                changes.change.create.nodes.len(), changes.change.modify.nodes.len(), changes.change.create.relations.len());
+            for (route_id, existing) in already_mapped {
+                let existing: Vec<String> = existing.iter().map(i64::to_string).collect();
+                println!("  {route_id} is already mapped as relation {}, so it's left alone", existing.join(", "));
             }
-        });
+            for route_id in nothing_in_osm {
+                println!("  {route_id} has no stops or roads in OSM yet, so it has no relations");
+            }
+
+            let report = validate_route_relations(&feed, &osm, &conflation, target_area);
+            let report_path = osm_settings.route_report_template.as_deref().unwrap_or(DEFAULT_ROUTE_REPORT_TEMPLATE).replace("{name}", &target_area.file_name());
+            let mut report_file = BufWriter::new(File::create(report_path)?);
+            serde_json::to_writer_pretty(&mut report_file, &report)?;
+            report_file.flush()?;
+            print!("{report}");
+
+            if let Some(upload) = osm_settings.upload.as_ref().filter(|_| !changes.change.is_empty()) {
+                // Overpass and extracts both come from the real map, so anywhere else can only be
+                // given new elements
+                let router = OsmRouter::for_server(&upload.server)?
+                    .with_access_token(&upload.access_token)
+                    .with_uploads_confirmed(upload.confirm)
+                    .with_data_from(OsmApiServer::Production.api_base()?);
 
-        suburb_stop_times_out.flush()?
+                // Checked here so that one area's changes not fitting doesn't stop the rest
+                if let Err(error) = router.check_change_matches_server(&changes.change) {
+                    println!("  Not uploading: {error}");
+                } else if upload.confirm {
+                    let (changeset, _) = router.upload(&changes.change, &changeset_tags(&upload.comment.replace("{name}", &target_area.name))).await?;
+                    println!("  Uploaded to changeset {changeset} on {}", upload.server.api_base()?);
+                } else {
+                    println!("  Not uploading to {} until osm.upload.confirm is set", upload.server.api_base()?);
+                }
+            }
+        }
     }
 
     Ok(())
@@ -0,0 +1,5 @@
+pub mod osm_elements;
+pub mod osm_xml;
+pub mod osm_overpass;
+pub mod osm_pbf;
+pub mod osm_change;
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use crate::osm::osm_elements::{OsmData, OsmElementType, OsmMeta, OsmTags};
+use crate::osm::osm_xml::{meta_attributes, OsmXmlAttributes, OsmXmlWriter};
+use crate::osm_api_client::USER_AGENT;
+
+/// The `source` tag given to new elements in JOSM files
+pub const JOSM_SOURCE: &str = "Transport for NSW";
+
+/// An osmChange document; what to create, modify and delete in a single upload.
+///
+/// Created elements should have negative IDs, which other created elements can refer to until
+/// the API assigns real ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OsmChange {
+    pub create: OsmData,
+    pub modify: OsmData,
+    pub delete: OsmData,
+}
+
+impl OsmChange {
+    pub fn is_empty(&self) -> bool {
+        [&self.create, &self.modify, &self.delete].iter()
+            .all(|data| data.nodes.is_empty() && data.ways.is_empty() && data.relations.is_empty())
+    }
+
+    /// The next free ID for a created element, counting down from -1
+    pub fn next_placeholder_id(&self) -> i64 {
+        let lowest = self.create.nodes.keys().next().into_iter()
+            .chain(self.create.ways.keys().next())
+            .chain(self.create.relations.keys().next())
+            .min()
+            .copied()
+            .unwrap_or(0);
+
+        lowest.min(0) - 1
+    }
+}
+
+/// What happened to one element of an upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OsmDiffEntry {
+    /// The element's real ID; new for created elements, and `None` for deleted ones
+    pub new_id: Option<i64>,
+    /// `None` for deleted elements
+    pub new_version: Option<u32>,
+}
+
+/// The API's response to an upload, by element type and the ID we uploaded it with
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OsmDiffResult {
+    pub elements: BTreeMap<(OsmElementType, i64), OsmDiffEntry>,
+}
+
+impl OsmDiffResult {
+    /// The real ID of an element we uploaded with a placeholder ID
+    pub fn new_id(&self, kind: OsmElementType, old_id: i64) -> Option<i64> {
+        self.elements.get(&(kind, old_id)).and_then(|entry| entry.new_id)
+    }
+}
+
+/// Writes `change` as an osmChange (`.osc`) document.
+///
+/// Every element is written with `changeset` if it's given, as the API requires on upload. The
+/// create and modify sections list nodes before ways before relations, and the delete section
+/// lists them the other way round, each in ID order, so the same change always produces the same
+/// file.
+pub fn write_osm_change<W: Write>(change: &OsmChange, changeset: Option<u64>, output: W) -> anyhow::Result<W> {
+    let mut writer = OsmXmlWriter::new(output)?;
+    writer.start("osmChange", &[("version", "0.6".to_string()), ("generator", USER_AGENT.to_string())])?;
+
+    // The API applies each section in order, and won't delete a node that a way still uses, so
+    // deletes have to go the other way round
+    let forwards = [OsmElementType::Node, OsmElementType::Way, OsmElementType::Relation];
+    let backwards = [OsmElementType::Relation, OsmElementType::Way, OsmElementType::Node];
+    let actions = [("create", &change.create, forwards), ("modify", &change.modify, forwards), ("delete", &change.delete, backwards)];
+
+    for (action, data, order) in actions {
+        if data.nodes.is_empty() && data.ways.is_empty() && data.relations.is_empty() {
+            continue;
+        }
+
+        let attributes = |meta| {
+            let mut attributes = meta_attributes(meta, false);
+            if let Some(changeset) = changeset {
+                attributes.retain(|(key, _)| *key != "changeset");
+                attributes.push(("changeset", changeset.to_string()));
+            }
+            attributes
+        };
+
+        writer.start(action, &[])?;
+        for kind in order {
+            match kind {
+                OsmElementType::Node => for node in data.nodes.values() {
+                    writer.node(node, attributes(&node.meta))?;
+                },
+                OsmElementType::Way => for way in data.ways.values() {
+                    writer.way(way, attributes(&way.meta))?;
+                },
+                OsmElementType::Relation => for relation in data.relations.values() {
+                    writer.relation(relation, attributes(&relation.meta))?;
+                },
+            }
+        }
+        writer.end(action)?;
+    }
+
+    writer.end("osmChange")?;
+    writer.finish()
+}
+
+/// The attributes for an element in a JOSM file; JOSM wants `action` on anything that's changed,
+/// and new elements have no version
+fn josm_attributes(action: &str, meta: &OsmMeta) -> OsmXmlAttributes {
+    std::iter::once(("action", action.to_string())).chain(meta_attributes(meta, true)).collect()
+}
+
+/// `tags` with the note for `(kind, id)` added, and a source tag if it's a new element
+fn josm_tags(tags: &OsmTags, kind: OsmElementType, id: i64, notes: &BTreeMap<(OsmElementType, i64), String>) -> OsmTags {
+    let mut tags = tags.clone();
+    if let Some(note) = notes.get(&(kind, id)) {
+        tags.insert("note".to_string(), note.clone());
+    }
+    if id < 0 {
+        tags.entry("source".to_string()).or_insert_with(|| JOSM_SOURCE.to_string());
+    }
+
+    tags
+}
+
+/// Writes `change` as a `.osm` file that JOSM opens with every element marked as modified or
+/// deleted, ready to review and upload.
+///
+/// Each element in `notes` gets its note as a `note` tag, and new elements get a `source` tag, so
+/// reviewers can see why a change was proposed. Notes are for review only, and should be removed
+/// before uploading. Elements are written in the same stable order as [write_osm_change].
+pub fn write_josm_osm<W: Write>(change: &OsmChange, notes: &BTreeMap<(OsmElementType, i64), String>, output: W) -> anyhow::Result<W> {
+    let mut writer = OsmXmlWriter::new(output)?;
+    writer.start("osm", &[("version", "0.6".to_string()), ("generator", USER_AGENT.to_string())])?;
+
+    let actions = [("modify", &change.create), ("modify", &change.modify), ("delete", &change.delete)];
+
+    let mut nodes: Vec<_> = actions.iter().flat_map(|(action, data)| data.nodes.values().map(move |node| (*action, node))).collect();
+    nodes.sort_by_key(|(_, node)| node.id);
+    for (action, node) in nodes {
+        let mut node = node.clone();
+        node.tags = josm_tags(&node.tags, OsmElementType::Node, node.id, notes);
+        writer.node(&node, josm_attributes(action, &node.meta))?;
+    }
+
+    let mut ways: Vec<_> = actions.iter().flat_map(|(action, data)| data.ways.values().map(move |way| (*action, way))).collect();
+    ways.sort_by_key(|(_, way)| way.id);
+    for (action, way) in ways {
+        let mut way = way.clone();
+        way.tags = josm_tags(&way.tags, OsmElementType::Way, way.id, notes);
+        writer.way(&way, josm_attributes(action, &way.meta))?;
+    }
+
+    let mut relations: Vec<_> = actions.iter().flat_map(|(action, data)| data.relations.values().map(move |relation| (*action, relation))).collect();
+    relations.sort_by_key(|(_, relation)| relation.id);
+    for (action, relation) in relations {
+        let mut relation = relation.clone();
+        relation.tags = josm_tags(&relation.tags, OsmElementType::Relation, relation.id, notes);
+        writer.relation(&relation, josm_attributes(action, &relation.meta))?;
+    }
+
+    writer.end("osm")?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use crate::osm::osm_change::{write_josm_osm, write_osm_change, OsmChange};
+    use crate::osm::osm_elements::{OsmElementType, OsmMeta, OsmNode, OsmWay};
+    use crate::osm::osm_xml::parse_osm_xml;
+
+    #[test]
+    fn test_write_osm_change() {
+        let mut change = OsmChange::default();
+        let tags = [("highway", "bus_stop"), ("name", "Stand \"C\" & D")].into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        change.create.nodes.insert(-1, OsmNode { id: -1, lat: -33.88371, lon: 151.20652, tags, meta: OsmMeta::default() });
+
+        let meta = OsmMeta { version: Some(3), changeset: Some(120000001), user: Some("mapper".to_string()), ..Default::default() };
+        change.modify.nodes.insert(4000003, OsmNode { id: 4000003, lat: -33.8836, lon: 151.206, tags: Default::default(), meta });
+
+        let meta = OsmMeta { version: Some(2), ..Default::default() };
+        change.delete.nodes.insert(4000004, OsmNode { id: 4000004, lat: -33.8835, lon: 151.2061, tags: Default::default(), meta: meta.clone() });
+        change.delete.ways.insert(5000001, OsmWay { id: 5000001, nodes: vec![4000003, 4000004], tags: Default::default(), meta });
+
+        assert_eq!(change.next_placeholder_id(), -2);
+
+        let osc = String::from_utf8(write_osm_change(&change, Some(42), Vec::new()).unwrap()).unwrap();
+        assert_eq!(osc, format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6" generator="osm-nsw/{}">
+  <create>
+    <node id="-1" changeset="42" lat="-33.8837100" lon="151.2065200">
+      <tag k="highway" v="bus_stop"/>
+      <tag k="name" v="Stand &quot;C&quot; &amp; D"/>
+    </node>
+  </create>
+  <modify>
+    <node id="4000003" version="3" changeset="42" lat="-33.8836000" lon="151.2060000"/>
+  </modify>
+  <delete>
+    <way id="5000001" version="2" changeset="42">
+      <nd ref="4000003"/>
+      <nd ref="4000004"/>
+    </way>
+    <node id="4000004" version="2" changeset="42" lat="-33.8835000" lon="151.2061000"/>
+  </delete>
+</osmChange>
+"#, env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn test_write_josm_osm() {
+        let mut change = OsmChange::default();
+        let tags = [("highway".to_string(), "bus_stop".to_string())].into_iter().collect();
+        change.create.nodes.insert(-1, OsmNode { id: -1, lat: -33.88371, lon: 151.20652, tags, meta: OsmMeta::default() });
+
+        let meta = OsmMeta { version: Some(3), changeset: Some(120000001), uid: Some(1001), user: Some("mapper".to_string()), ..Default::default() };
+        change.modify.nodes.insert(4000003, OsmNode { id: 4000003, lat: -33.8836, lon: 151.206, tags: Default::default(), meta });
+
+        let notes = BTreeMap::from([((OsmElementType::Node, -1), "GTFS stop 2000324 has no matching OSM stop nearby.".to_string())]);
+        let osm = String::from_utf8(write_josm_osm(&change, &notes, Vec::new()).unwrap()).unwrap();
+
+        assert!(osm.contains(r#"<node id="-1" action="modify" visible="true" lat="-33.8837100" lon="151.2065200">"#));
+        assert!(osm.contains(r#"<tag k="note" v="GTFS stop 2000324 has no matching OSM stop nearby."/>"#));
+        assert!(osm.contains(r#"<tag k="source" v="Transport for NSW"/>"#));
+        assert!(osm.contains(r#"<node id="4000003" action="modify" visible="true" version="3" changeset="120000001" user="mapper" uid="1001" lat="-33.8836000" lon="151.2060000"/>"#));
+
+        // Everything JOSM needs to show the elements survives a round trip
+        let data = parse_osm_xml(osm.as_bytes()).unwrap();
+        assert_eq!(data.nodes.len(), 2);
+        assert_eq!(data.node(4000003).unwrap().meta.version, Some(3));
+    }
+}
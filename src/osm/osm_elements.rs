@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use chrono::{DateTime, Utc};
+use geo_types::{LineString, Point, Rect};
+use serde::Serialize;
+use strum::{AsRefStr, EnumString};
+
+/// Tags are kept sorted, so that anything we write back out is stable
+pub type OsmTags = BTreeMap<String, String>;
+
+/// Whether an element is a stop, platform, station or bus route; the same features
+/// [crate::osm::osm_overpass::OverpassQuery::public_transport] asks for
+pub fn is_public_transport(tags: &OsmTags) -> bool {
+    let tag = |key: &str| tags.get(key).map(String::as_str);
+
+    tag("highway") == Some("bus_stop")
+        || matches!(tag("public_transport"), Some("platform" | "stop_position" | "station"))
+        || tag("route") == Some("bus")
+}
+
+/// `highway` values for roads buses can use
+pub const BUS_HIGHWAYS: [&str; 16] = [
+    "motorway", "motorway_link", "trunk", "trunk_link", "primary", "primary_link", "secondary", "secondary_link",
+    "tertiary", "tertiary_link", "unclassified", "residential", "living_street", "service", "busway", "road",
+];
+
+/// Whether a way is a road buses could be routed along
+pub fn is_bus_road(tags: &OsmTags) -> bool {
+    tags.get("highway").is_some_and(|highway| BUS_HIGHWAYS.contains(&highway.as_str()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, AsRefStr, EnumString, strum::Display, Serialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum OsmElementType {
+    Node,
+    Way,
+    Relation,
+}
+
+/// Editing metadata, which is only present on elements that came from the API (or a full history
+/// extract) rather than ones we've created ourselves
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmMeta {
+    pub version: Option<u32>,
+    pub changeset: Option<u64>,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub user: Option<String>,
+    pub uid: Option<u64>,
+    pub visible: bool,
+}
+
+impl Default for OsmMeta {
+    fn default() -> Self {
+        OsmMeta { version: None, changeset: None, timestamp: None, user: None, uid: None, visible: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmNode {
+    pub id: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub tags: OsmTags,
+    pub meta: OsmMeta,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmWay {
+    pub id: i64,
+    pub nodes: Vec<i64>,
+    pub tags: OsmTags,
+    pub meta: OsmMeta,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmMember {
+    pub kind: OsmElementType,
+    pub reference: i64,
+    pub role: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmRelation {
+    pub id: i64,
+    pub members: Vec<OsmMember>,
+    pub tags: OsmTags,
+    pub meta: OsmMeta,
+}
+
+impl OsmNode {
+    /// As longitude, latitude, to match the rest of the crate
+    pub fn point(&self) -> Point<f64> {
+        Point::new(self.lon, self.lat)
+    }
+}
+
+/// A set of elements, such as the result of a map call or a parsed `.osm` file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OsmData {
+    pub bounds: Option<Rect<f64>>,
+    pub nodes: BTreeMap<i64, OsmNode>,
+    pub ways: BTreeMap<i64, OsmWay>,
+    pub relations: BTreeMap<i64, OsmRelation>,
+}
+
+impl OsmData {
+    pub fn node(&self, id: i64) -> Option<&OsmNode> {
+        self.nodes.get(&id)
+    }
+
+    pub fn way(&self, id: i64) -> Option<&OsmWay> {
+        self.ways.get(&id)
+    }
+
+    pub fn relation(&self, id: i64) -> Option<&OsmRelation> {
+        self.relations.get(&id)
+    }
+
+    /// The geometry of a way, if every one of its nodes is present
+    pub fn way_line(&self, id: i64) -> Option<LineString<f64>> {
+        self.way(id)?
+            .nodes
+            .iter()
+            .map(|node| self.node(*node).map(|node| node.point().0))
+            .collect::<Option<Vec<_>>>()
+            .map(LineString::new)
+    }
+
+    /// Adds everything from `other`, with its elements replacing ours where they share an ID
+    pub fn merge(&mut self, other: OsmData) {
+        self.nodes.extend(other.nodes);
+        self.ways.extend(other.ways);
+        self.relations.extend(other.relations);
+        self.bounds = self.bounds.or(other.bounds);
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt::{Display, Formatter, Write};
+use std::io::Cursor;
+use std::sync::Arc;
+use geo_types::{LineString, Rect};
+use reqwest::{Client, IntoUrl, Url};
+use strum::AsRefStr;
+use crate::osm::osm_elements::{OsmData, BUS_HIGHWAYS};
+use crate::osm::osm_xml::parse_osm_xml;
+use crate::osm_api_client::USER_AGENT;
+use crate::target_area::TargetArea;
+
+pub const DEFAULT_OVERPASS_ENDPOINT: &str = "https://overpass-api.de/api/interpreter";
+
+/// Which elements a statement selects; `nwr` selects all three
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr)]
+#[strum(serialize_all = "lowercase")]
+pub enum OverpassElements {
+    Node,
+    Way,
+    Relation,
+    Nwr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverpassTag {
+    Exists(String),
+    Equals(String, String),
+    OneOf(String, Vec<String>),
+}
+
+/// Where a query looks. Polygons only use their exterior ring, since Overpass doesn't support
+/// holes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverpassArea {
+    BoundingBox(Rect<f64>),
+    Polygons(Vec<LineString<f64>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverpassFilter {
+    pub elements: OverpassElements,
+    pub tags: Vec<OverpassTag>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverpassQuery {
+    pub area: OverpassArea,
+    pub filters: Vec<OverpassFilter>,
+    /// Seconds the server may spend on the query before giving up
+    pub timeout: u32,
+    /// Also fetch the nodes of ways and the members of relations, so geometry can be built
+    pub with_members: bool,
+    /// Also fetch every relation any of the ways are in, so nothing is missed when a way is split
+    pub with_parent_relations: bool,
+}
+
+/// Quotes a string for Overpass QL
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn regex_escape(value: &str) -> String {
+    value.chars().fold(String::new(), |mut escaped, c| {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+impl Display for OverpassTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverpassTag::Exists(key) => write!(f, "[{}]", quote(key)),
+            OverpassTag::Equals(key, value) => write!(f, "[{}={}]", quote(key), quote(value)),
+            OverpassTag::OneOf(key, values) => {
+                let values = values.iter().map(|value| regex_escape(value)).collect::<Vec<_>>().join("|");
+                write!(f, "[{}~{}]", quote(key), quote(&format!("^({values})$")))
+            }
+        }
+    }
+}
+
+impl OverpassArea {
+    /// Every spatial filter needed to cover the area; a multi-polygon needs one per polygon
+    fn spatial_filters(&self) -> Vec<String> {
+        match self {
+            OverpassArea::BoundingBox(rect) =>
+                vec![format!("({},{},{},{})", rect.min().y, rect.min().x, rect.max().y, rect.max().x)],
+            OverpassArea::Polygons(polygons) => polygons.iter()
+                .map(|polygon| {
+                    let points = polygon.coords().map(|c| format!("{} {}", c.y, c.x)).collect::<Vec<_>>().join(" ");
+                    format!("(poly:{})", quote(&points))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&TargetArea> for OverpassArea {
+    fn from(target: &TargetArea) -> Self {
+        OverpassArea::Polygons(target.area.iter().map(|polygon| polygon.exterior().clone()).collect())
+    }
+}
+
+impl OverpassFilter {
+    pub fn new(elements: OverpassElements) -> OverpassFilter {
+        OverpassFilter { elements, tags: Vec::new() }
+    }
+
+    pub fn with_tag<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> OverpassFilter {
+        self.tags.push(OverpassTag::Equals(key.into(), value.into()));
+        self
+    }
+
+    pub fn with_tag_in<K: Into<String>>(mut self, key: K, values: &[&str]) -> OverpassFilter {
+        self.tags.push(OverpassTag::OneOf(key.into(), values.iter().map(|value| value.to_string()).collect()));
+        self
+    }
+
+    pub fn with_key<K: Into<String>>(mut self, key: K) -> OverpassFilter {
+        self.tags.push(OverpassTag::Exists(key.into()));
+        self
+    }
+}
+
+impl OverpassQuery {
+    pub fn new(area: OverpassArea) -> OverpassQuery {
+        OverpassQuery { area, filters: Vec::new(), timeout: 90, with_members: false, with_parent_relations: false }
+    }
+
+    /// Bus stops, platforms, stop positions, stations and bus routes
+    pub fn public_transport(area: OverpassArea) -> OverpassQuery {
+        OverpassQuery::new(area)
+            .with_filter(OverpassFilter::new(OverpassElements::Node).with_tag("highway", "bus_stop"))
+            .with_filter(OverpassFilter::new(OverpassElements::Nwr).with_tag_in("public_transport", &["platform", "stop_position", "station"]))
+            .with_filter(OverpassFilter::new(OverpassElements::Relation).with_tag("route", "bus"))
+    }
+
+    /// Also ask for the roads buses can use, along with their nodes and every relation they're in
+    pub fn with_bus_roads(self) -> OverpassQuery {
+        self.with_filter(OverpassFilter::new(OverpassElements::Way).with_tag_in("highway", &BUS_HIGHWAYS))
+            .with_members()
+            .with_parent_relations()
+    }
+
+    pub fn with_filter(mut self, filter: OverpassFilter) -> OverpassQuery {
+        self.filters.push(filter);
+        self
+    }
+
+    pub fn with_members(self) -> OverpassQuery {
+        OverpassQuery { with_members: true, ..self }
+    }
+
+    pub fn with_parent_relations(self) -> OverpassQuery {
+        OverpassQuery { with_parent_relations: true, ..self }
+    }
+
+    pub fn with_timeout(self, timeout: u32) -> OverpassQuery {
+        OverpassQuery { timeout, ..self }
+    }
+
+    /// The query in Overpass QL
+    pub fn to_ql(&self) -> String {
+        let mut ql = format!("[out:xml][timeout:{}];\n(\n", self.timeout);
+
+        for spatial in self.area.spatial_filters() {
+            for filter in &self.filters {
+                let tags: String = filter.tags.iter().map(ToString::to_string).collect();
+                let _ = writeln!(ql, "  {}{tags}{spatial};", filter.elements.as_ref());
+            }
+        }
+
+        ql.push_str(");\n");
+        if self.with_members {
+            ql.push_str("(._;>;);\n");
+        }
+        // After the members, so that the parents' own members aren't all fetched too
+        if self.with_parent_relations {
+            ql.push_str("(._;rel(bw););\n");
+        }
+        ql.push_str("out meta;");
+
+        ql
+    }
+}
+
+/// A client for an Overpass API instance, the public one by default
+pub struct OverpassClient {
+    endpoint: Url,
+    client: Arc<Client>,
+}
+
+impl OverpassClient {
+    pub fn new() -> anyhow::Result<OverpassClient> {
+        Self::with_endpoint(DEFAULT_OVERPASS_ENDPOINT)
+    }
+
+    /// Points at another instance, such as a local one, by its `interpreter` URL
+    pub fn with_endpoint<T: IntoUrl>(endpoint: T) -> anyhow::Result<OverpassClient> {
+        let endpoint = endpoint.into_url()?;
+        let client = Arc::new(Client::builder().user_agent(USER_AGENT).build()?);
+
+        Ok(OverpassClient { endpoint, client })
+    }
+
+    pub async fn query(&self, query: &OverpassQuery) -> anyhow::Result<OsmData> {
+        self.query_ql(&query.to_ql()).await
+    }
+
+    pub async fn query_ql(&self, ql: &str) -> anyhow::Result<OsmData> {
+        let body = self.client
+            .post(self.endpoint.clone())
+            .form(&[("data", ql)])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())?
+            .bytes()
+            .await?;
+
+        parse_osm_xml(Cursor::new(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::{coord, line_string, Rect};
+    use crate::osm::osm_overpass::{OverpassArea, OverpassClient, OverpassElements, OverpassFilter, OverpassQuery};
+    use crate::tests::stand_in_server::{StandInResponse, StandInServer};
+
+    #[test]
+    fn test_public_transport_query() {
+        let area = OverpassArea::BoundingBox(Rect::new(coord! { x: 151.20, y: -33.89 }, coord! { x: 151.21, y: -33.88 }));
+        let query = OverpassQuery::public_transport(area).with_members();
+
+        assert_eq!(query.to_ql(), "[out:xml][timeout:90];\n(\n  \
+            node[\"highway\"=\"bus_stop\"](-33.89,151.2,-33.88,151.21);\n  \
+            nwr[\"public_transport\"~\"^(platform|stop_position|station)$\"](-33.89,151.2,-33.88,151.21);\n  \
+            relation[\"route\"=\"bus\"](-33.89,151.2,-33.88,151.21);\n\
+            );\n(._;>;);\nout meta;");
+    }
+
+    #[test]
+    fn test_bus_roads_query() {
+        let area = OverpassArea::BoundingBox(Rect::new(coord! { x: 151.20, y: -33.89 }, coord! { x: 151.21, y: -33.88 }));
+        let ql = OverpassQuery::public_transport(area).with_bus_roads().to_ql();
+
+        assert!(ql.contains("  way[\"highway\"~\"^(motorway|motorway_link|"));
+        assert!(ql.ends_with(");\n(._;>;);\n(._;rel(bw););\nout meta;"));
+    }
+
+    #[test]
+    fn test_polygon_query() {
+        let area = OverpassArea::Polygons(vec![line_string![(x: 151.20, y: -33.88), (x: 151.21, y: -33.88), (x: 151.21, y: -33.89)]]);
+        let query = OverpassQuery::new(area)
+            .with_filter(OverpassFilter::new(OverpassElements::Node).with_tag("name", "Stand \"A\"").with_key("ref:tfnsw"));
+
+        assert!(query.to_ql().contains("node[\"name\"=\"Stand \\\"A\\\"\"][\"ref:tfnsw\"](poly:\"-33.88 151.2 -33.88 151.21 -33.89 151.21\");"));
+    }
+
+    #[tokio::test]
+    async fn test_overpass_client() {
+        let server = StandInServer::start(|_| StandInResponse::ok(include_str!("../../fixtures/osm/central.osm"))).await;
+        let client = OverpassClient::with_endpoint(server.url("/api/interpreter")).unwrap();
+
+        let area = OverpassArea::BoundingBox(Rect::new(coord! { x: 151.20, y: -33.89 }, coord! { x: 151.21, y: -33.88 }));
+        let data = client.query(&OverpassQuery::public_transport(area)).await.unwrap();
+        assert_eq!(data.relations.len(), 1);
+
+        let request = &server.requests()[0];
+        assert_eq!(request.method, "POST");
+        assert!(String::from_utf8_lossy(&request.body).starts_with("data=%5Bout%3Axml%5D"));
+    }
+
+    #[tokio::test]
+    async fn test_overpass_runtime_error() {
+        // Overpass still answers 200 when a query times out, with a remark in place of the rest
+        let server = StandInServer::start(|_| StandInResponse::ok(r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="Overpass API">
+  <remark> runtime error: Query timed out in "query" at line 3 after 91 seconds. </remark>
+</osm>"#)).await;
+        let client = OverpassClient::with_endpoint(server.url("/api/interpreter")).unwrap();
+
+        let error = client.query_ql("node(1);out;").await.unwrap_err();
+        assert!(error.to_string().contains("Query timed out"), "{error}");
+    }
+}
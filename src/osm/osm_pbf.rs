@@ -0,0 +1,742 @@
+//! Reading `.osm.pbf` extracts, see <https://wiki.openstreetmap.org/wiki/PBF_Format>.
+//!
+//! Only zlib compressed and uncompressed blobs are supported, which covers Geofabrik's extracts.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::Path;
+use anyhow::{anyhow, bail, Context};
+use chrono::DateTime;
+use flate2::read::ZlibDecoder;
+use geo::Intersects;
+use geo_types::{coord, Point, Rect};
+use crate::osm::osm_elements::{is_public_transport, OsmData, OsmElementType, OsmMember, OsmMeta, OsmNode, OsmRelation, OsmTags, OsmWay};
+use crate::protobuf::{ProtobufError, ProtobufField, ProtobufMessage, ProtobufReader};
+use crate::target_area::TargetArea;
+
+/// Limits from the spec, so a corrupt length can't make us allocate gigabytes
+const MAX_BLOB_HEADER_SIZE: usize = 64 * 1024;
+const MAX_BLOB_SIZE: usize = 32 * 1024 * 1024;
+
+/// Features we understand; a file requiring anything else can't be read correctly
+const SUPPORTED_FEATURES: [&str; 3] = ["OsmSchema-V0.6", "DenseNodes", "HistoricalInformation"];
+
+#[derive(Debug, Default)]
+struct PbfBlobHeader {
+    kind: String,
+    data_size: usize,
+}
+
+impl ProtobufMessage for PbfBlobHeader {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.kind = field.as_string()?,
+            3 => self.data_size = field.as_u32()? as usize,
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct PbfBlob {
+    raw: Option<Vec<u8>>,
+    raw_size: Option<usize>,
+    zlib_data: Option<Vec<u8>>,
+    unsupported_compression: Option<u32>,
+}
+
+impl ProtobufMessage for PbfBlob {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.raw = Some(field.as_bytes()?.to_vec()),
+            2 => self.raw_size = Some(field.as_u32()? as usize),
+            3 => self.zlib_data = Some(field.as_bytes()?.to_vec()),
+            4..=7 => self.unsupported_compression = Some(field.number),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl PbfBlob {
+    fn into_data(self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            PbfBlob { raw: Some(raw), .. } => Ok(raw),
+            PbfBlob { zlib_data: Some(zlib_data), raw_size, .. } => {
+                let mut data = Vec::with_capacity(raw_size.unwrap_or(zlib_data.len() * 4).min(MAX_BLOB_SIZE));
+                // A small blob can inflate into something huge, so don't trust it to stop by itself
+                ZlibDecoder::new(&zlib_data[..]).take(MAX_BLOB_SIZE as u64 + 1).read_to_end(&mut data)?;
+                if data.len() > MAX_BLOB_SIZE {
+                    bail!("Blob inflates to more than {MAX_BLOB_SIZE} bytes");
+                }
+                if let Some(raw_size) = raw_size.filter(|raw_size| *raw_size != data.len()) {
+                    bail!("Blob inflated to {} bytes, but should be {raw_size}", data.len());
+                }
+
+                Ok(data)
+            }
+            PbfBlob { unsupported_compression: Some(field), .. } => bail!("Unsupported blob compression (field {field})"),
+            _ => bail!("Blob has no data"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PbfHeaderBlock {
+    bbox: Option<Rect<f64>>,
+    required_features: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct PbfHeaderBBox {
+    left: i64,
+    right: i64,
+    top: i64,
+    bottom: i64,
+}
+
+impl ProtobufMessage for PbfHeaderBBox {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => self.left = field.as_sint64()?,
+            2 => self.right = field.as_sint64()?,
+            3 => self.top = field.as_sint64()?,
+            4 => self.bottom = field.as_sint64()?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl ProtobufMessage for PbfHeaderBlock {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError> {
+        match field.number {
+            1 => {
+                let bbox: PbfHeaderBBox = field.as_message()?;
+                self.bbox = Some(Rect::new(
+                    coord! { x: bbox.left as f64 * 1e-9, y: bbox.bottom as f64 * 1e-9 },
+                    coord! { x: bbox.right as f64 * 1e-9, y: bbox.top as f64 * 1e-9 },
+                ));
+            }
+            4 => self.required_features.push(field.as_string()?),
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// The parameters shared by everything in a primitive block, needed to decode its groups
+struct PbfBlockContext<'a> {
+    strings: Vec<&'a [u8]>,
+    granularity: i64,
+    lat_offset: i64,
+    lon_offset: i64,
+    date_granularity: i64,
+}
+
+impl<'a> PbfBlockContext<'a> {
+    fn string(&self, index: u64) -> anyhow::Result<String> {
+        let bytes = self.strings.get(index as usize).ok_or_else(|| anyhow!("String table index {index} out of range"))?;
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+
+    fn tags(&self, keys: &[u64], values: &[u64]) -> anyhow::Result<OsmTags> {
+        keys.iter().zip(values)
+            .map(|(key, value)| Ok((self.string(*key)?, self.string(*value)?)))
+            .collect()
+    }
+
+    fn lat(&self, lat: i64) -> f64 {
+        (self.lat_offset + self.granularity * lat) as f64 * 1e-9
+    }
+
+    fn lon(&self, lon: i64) -> f64 {
+        (self.lon_offset + self.granularity * lon) as f64 * 1e-9
+    }
+
+    fn meta(&self, version: Option<u32>, timestamp: Option<i64>, changeset: Option<i64>, uid: Option<i64>, user_sid: Option<u64>, visible: Option<bool>) -> anyhow::Result<OsmMeta> {
+        Ok(OsmMeta {
+            version,
+            changeset: changeset.map(|changeset| changeset as u64),
+            timestamp: timestamp.and_then(|timestamp| DateTime::from_timestamp_millis(timestamp * self.date_granularity)),
+            user: user_sid.filter(|sid| *sid != 0).map(|sid| self.string(sid)).transpose()?,
+            uid: uid.map(|uid| uid as u64),
+            visible: visible.unwrap_or(true),
+        })
+    }
+
+    /// An `Info` message, attached to plain nodes, ways and relations
+    fn info(&self, info: &[u8]) -> anyhow::Result<OsmMeta> {
+        let (mut version, mut timestamp, mut changeset, mut uid, mut user_sid, mut visible) = (None, None, None, None, None, None);
+        for field in ProtobufReader::new(info) {
+            let field = field?;
+            match field.number {
+                1 => version = Some(field.as_u32()?),
+                2 => timestamp = Some(field.as_i64()?),
+                3 => changeset = Some(field.as_i64()?),
+                4 => uid = Some(field.as_i32()? as i64),
+                5 => user_sid = Some(field.as_u64()?),
+                6 => visible = Some(field.as_bool()?),
+                _ => {}
+            }
+        }
+
+        self.meta(version, timestamp, changeset, uid, user_sid, visible)
+    }
+}
+
+/// Undoes the delta coding used by dense nodes and way/relation references
+fn undelta(values: Vec<i64>) -> Vec<i64> {
+    values.into_iter()
+        .scan(0i64, |sum, delta| {
+            *sum += delta;
+            Some(*sum)
+        })
+        .collect()
+}
+
+fn decode_node(context: &PbfBlockContext, buf: &[u8], data: &mut OsmData) -> anyhow::Result<()> {
+    let (mut id, mut keys, mut values, mut meta, mut lat, mut lon) = (0, vec![], vec![], OsmMeta::default(), 0, 0);
+    for field in ProtobufReader::new(buf) {
+        let field = field?;
+        match field.number {
+            1 => id = field.as_sint64()?,
+            2 => keys.extend(field.as_packed_varints()?),
+            3 => values.extend(field.as_packed_varints()?),
+            4 => meta = context.info(field.as_bytes()?)?,
+            8 => lat = field.as_sint64()?,
+            9 => lon = field.as_sint64()?,
+            _ => {}
+        }
+    }
+
+    let tags = context.tags(&keys, &values)?;
+    data.nodes.insert(id, OsmNode { id, lat: context.lat(lat), lon: context.lon(lon), tags, meta });
+    Ok(())
+}
+
+fn decode_dense_nodes(context: &PbfBlockContext, buf: &[u8], data: &mut OsmData) -> anyhow::Result<()> {
+    let (mut ids, mut lats, mut lons, mut keys_values, mut dense_info) = (vec![], vec![], vec![], vec![], None);
+    for field in ProtobufReader::new(buf) {
+        let field = field?;
+        match field.number {
+            1 => ids.extend(field.as_packed_sint64()?),
+            5 => dense_info = Some(field.as_bytes()?),
+            8 => lats.extend(field.as_packed_sint64()?),
+            9 => lons.extend(field.as_packed_sint64()?),
+            10 => keys_values.extend(field.as_packed_varints()?),
+            _ => {}
+        }
+    }
+
+    let (ids, lats, lons) = (undelta(ids), undelta(lats), undelta(lons));
+    if lats.len() != ids.len() || lons.len() != ids.len() {
+        bail!("Dense nodes have {} ids but {} latitudes and {} longitudes", ids.len(), lats.len(), lons.len());
+    }
+
+    let (mut versions, mut timestamps, mut changesets, mut uids, mut user_sids, mut visibles) = (vec![], vec![], vec![], vec![], vec![], vec![]);
+    if let Some(dense_info) = dense_info {
+        for field in ProtobufReader::new(dense_info) {
+            let field = field?;
+            match field.number {
+                1 => versions.extend(field.as_packed_varints()?.into_iter().map(|version| version as u32)),
+                2 => timestamps.extend(field.as_packed_sint64()?),
+                3 => changesets.extend(field.as_packed_sint64()?),
+                4 => uids.extend(field.as_packed_sint64()?),
+                5 => user_sids.extend(field.as_packed_sint64()?),
+                6 => visibles.extend(field.as_packed_varints()?.into_iter().map(|visible| visible != 0)),
+                _ => {}
+            }
+        }
+    }
+    let (timestamps, changesets, uids, user_sids) = (undelta(timestamps), undelta(changesets), undelta(uids), undelta(user_sids));
+
+    // Tags for every node, as key, value pairs with a 0 after each node's tags
+    let mut keys_values = keys_values.into_iter();
+    for (i, id) in ids.into_iter().enumerate() {
+        let mut tags = OsmTags::new();
+        while let Some(key) = keys_values.next().filter(|key| *key != 0) {
+            let value = keys_values.next().ok_or_else(|| anyhow!("Dense node {id} has a key without a value"))?;
+            tags.insert(context.string(key)?, context.string(value)?);
+        }
+
+        let meta = context.meta(
+            versions.get(i).copied(),
+            timestamps.get(i).copied(),
+            changesets.get(i).copied(),
+            uids.get(i).copied(),
+            user_sids.get(i).map(|sid| *sid as u64),
+            visibles.get(i).copied(),
+        )?;
+
+        data.nodes.insert(id, OsmNode { id, lat: context.lat(lats[i]), lon: context.lon(lons[i]), tags, meta });
+    }
+
+    Ok(())
+}
+
+fn decode_way(context: &PbfBlockContext, buf: &[u8], data: &mut OsmData) -> anyhow::Result<()> {
+    let (mut id, mut keys, mut values, mut meta, mut refs) = (0, vec![], vec![], OsmMeta::default(), vec![]);
+    for field in ProtobufReader::new(buf) {
+        let field = field?;
+        match field.number {
+            1 => id = field.as_i64()?,
+            2 => keys.extend(field.as_packed_varints()?),
+            3 => values.extend(field.as_packed_varints()?),
+            4 => meta = context.info(field.as_bytes()?)?,
+            8 => refs.extend(field.as_packed_sint64()?),
+            _ => {}
+        }
+    }
+
+    let tags = context.tags(&keys, &values)?;
+    data.ways.insert(id, OsmWay { id, nodes: undelta(refs), tags, meta });
+    Ok(())
+}
+
+fn decode_relation(context: &PbfBlockContext, buf: &[u8], data: &mut OsmData) -> anyhow::Result<()> {
+    let (mut id, mut keys, mut values, mut meta) = (0, vec![], vec![], OsmMeta::default());
+    let (mut roles, mut member_ids, mut types) = (vec![], vec![], vec![]);
+    for field in ProtobufReader::new(buf) {
+        let field = field?;
+        match field.number {
+            1 => id = field.as_i64()?,
+            2 => keys.extend(field.as_packed_varints()?),
+            3 => values.extend(field.as_packed_varints()?),
+            4 => meta = context.info(field.as_bytes()?)?,
+            8 => roles.extend(field.as_packed_varints()?),
+            9 => member_ids.extend(field.as_packed_sint64()?),
+            10 => types.extend(field.as_packed_varints()?),
+            _ => {}
+        }
+    }
+
+    let members = roles.into_iter()
+        .zip(undelta(member_ids))
+        .zip(types)
+        .map(|((role, reference), kind)| Ok(OsmMember {
+            kind: match kind {
+                0 => OsmElementType::Node,
+                1 => OsmElementType::Way,
+                2 => OsmElementType::Relation,
+                _ => bail!("Relation {id} has a member of unknown type {kind}"),
+            },
+            reference,
+            role: context.string(role)?,
+        }))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let tags = context.tags(&keys, &values)?;
+    data.relations.insert(id, OsmRelation { id, members, tags, meta });
+    Ok(())
+}
+
+/// Decodes a `PrimitiveBlock` into typed elements
+fn decode_primitive_block(buf: &[u8]) -> anyhow::Result<OsmData> {
+    let mut context = PbfBlockContext { strings: vec![], granularity: 100, lat_offset: 0, lon_offset: 0, date_granularity: 1000 };
+    let mut groups = vec![];
+
+    for field in ProtobufReader::new(buf) {
+        let field = field?;
+        match field.number {
+            1 => for string in ProtobufReader::new(field.as_bytes()?) {
+                context.strings.push(string?.as_bytes()?);
+            },
+            2 => groups.push(field.as_bytes()?),
+            17 => context.granularity = field.as_i64()?,
+            18 => context.date_granularity = field.as_i64()?,
+            19 => context.lat_offset = field.as_i64()?,
+            20 => context.lon_offset = field.as_i64()?,
+            _ => {}
+        }
+    }
+
+    let mut data = OsmData::default();
+    for group in groups {
+        for field in ProtobufReader::new(group) {
+            let field = field?;
+            match field.number {
+                1 => decode_node(&context, field.as_bytes()?, &mut data)?,
+                2 => decode_dense_nodes(&context, field.as_bytes()?, &mut data)?,
+                3 => decode_way(&context, field.as_bytes()?, &mut data)?,
+                4 => decode_relation(&context, field.as_bytes()?, &mut data)?,
+                _ => {}
+            }
+        }
+    }
+
+    Ok(data)
+}
+
+/// Reads an `.osm.pbf` file one block at a time, so that extracts far bigger than memory can be
+/// filtered down as they're read
+pub struct OsmPbfReader<R: Read> {
+    input: R,
+    bounds: Option<Rect<f64>>,
+}
+
+impl OsmPbfReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        OsmPbfReader::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> OsmPbfReader<R> {
+    /// Reads the header block, failing if the file needs features we don't support
+    pub fn new(input: R) -> anyhow::Result<Self> {
+        let mut reader = OsmPbfReader { input, bounds: None };
+
+        let (kind, data) = reader.read_blob()?.ok_or_else(|| anyhow!("PBF file is empty"))?;
+        if kind != "OSMHeader" {
+            bail!("PBF file starts with a {kind} block rather than OSMHeader");
+        }
+
+        let header = PbfHeaderBlock::decode(&data)?;
+        if let Some(feature) = header.required_features.iter().find(|feature| !SUPPORTED_FEATURES.contains(&feature.as_str())) {
+            bail!("PBF file requires unsupported feature {feature}");
+        }
+
+        reader.bounds = header.bbox;
+        Ok(reader)
+    }
+
+    /// The bounds the header says the file covers, if it says
+    pub fn bounds(&self) -> Option<Rect<f64>> {
+        self.bounds
+    }
+
+    /// The next blob's type and decompressed contents, or `None` at the end of the file
+    fn read_blob(&mut self) -> anyhow::Result<Option<(String, Vec<u8>)>> {
+        let mut length = [0u8; 4];
+        match self.input.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_BLOB_HEADER_SIZE {
+            bail!("Blob header is {length} bytes, which is more than the format allows");
+        }
+
+        let mut header = vec![0u8; length];
+        self.input.read_exact(&mut header)?;
+        let header = PbfBlobHeader::decode(&header)?;
+
+        if header.data_size > MAX_BLOB_SIZE {
+            bail!("Blob is {} bytes, which is more than the format allows", header.data_size);
+        }
+
+        let mut blob = vec![0u8; header.data_size];
+        self.input.read_exact(&mut blob)?;
+
+        Ok(Some((header.kind, PbfBlob::decode(&blob)?.into_data()?)))
+    }
+
+    /// Calls `f` with the elements of each data block in turn
+    pub fn for_each_block<F: FnMut(OsmData) -> anyhow::Result<()>>(&mut self, mut f: F) -> anyhow::Result<()> {
+        while let Some((kind, data)) = self.read_blob()? {
+            // Unknown blob types are meant to be skipped
+            if kind == "OSMData" {
+                f(decode_primitive_block(&data)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every element in the file. Only sensible for small extracts.
+    pub fn read_all(mut self) -> anyhow::Result<OsmData> {
+        let mut data = OsmData { bounds: self.bounds, ..Default::default() };
+        self.for_each_block(|block| {
+            data.merge(block);
+            Ok(())
+        })?;
+
+        Ok(data)
+    }
+
+    /// Public transport stops, platforms, stations and bus routes inside `area`, in the same
+    /// shape the Overpass client returns them.
+    pub fn extract_public_transport(self, area: &TargetArea) -> anyhow::Result<OsmData> {
+        self.extract(area, is_public_transport)
+    }
+
+    /// Every element inside `area` whose tags pass `keep`.
+    pub fn extract<F: Fn(&OsmTags) -> bool>(self, area: &TargetArea, keep: F) -> anyhow::Result<OsmData> {
+        let mut extracts = self.extract_areas(std::slice::from_ref(area), keep)?;
+        Ok(extracts.remove(0))
+    }
+
+    /// Every element inside each of `areas` whose tags pass `keep`, one extract per area in the
+    /// same order, from a single pass over the file.
+    ///
+    /// This relies on the usual PBF ordering of nodes, then ways, then relations. Ways are kept
+    /// (along with their nodes) if any of their nodes are inside the area, and relations are kept
+    /// if any of their members were. Every relation a kept way is in is kept whatever its tags,
+    /// like Overpass's `rel(bw)`, so that a way can be split without missing one of them.
+    pub fn extract_areas<F: Fn(&OsmTags) -> bool>(mut self, areas: &[TargetArea], keep: F) -> anyhow::Result<Vec<OsmData>> {
+        let mut extracts = areas.iter()
+            .map(|area| {
+                let bounds = area.bounding_rect().ok_or_else(|| anyhow!("Target area {} is empty", area.name))?;
+                Ok(AreaExtract { area, bounds, kept_nodes: HashSet::new(), kept_ways: HashSet::new(), data: OsmData { bounds: Some(bounds), ..Default::default() } })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // Every node in any area's bounding box, since ways we keep will need their geometry
+        let mut nearby_nodes: HashMap<i64, OsmNode> = HashMap::new();
+
+        self.for_each_block(|block| {
+            for (id, node) in block.nodes {
+                let point = node.point();
+                let mut nearby = false;
+                for extract in extracts.iter_mut().filter(|extract| extract.bounds.intersects(&point)) {
+                    nearby = true;
+                    if keep(&node.tags) && extract.area.contains(&point) {
+                        extract.kept_nodes.insert(id);
+                    }
+                }
+
+                if nearby {
+                    nearby_nodes.insert(id, node);
+                }
+            }
+
+            for (id, way) in block.ways {
+                if !keep(&way.tags) {
+                    continue;
+                }
+
+                for extract in &mut extracts {
+                    let inside = way.nodes.iter()
+                        .filter_map(|node| nearby_nodes.get(node))
+                        .any(|node| extract.in_area(&node.point()));
+
+                    if inside {
+                        extract.kept_nodes.extend(way.nodes.iter().filter(|node| nearby_nodes.contains_key(node)));
+                        extract.kept_ways.insert(id);
+                        extract.data.ways.insert(id, way.clone());
+                    }
+                }
+            }
+
+            for (id, relation) in block.relations {
+                let wanted = keep(&relation.tags);
+
+                for extract in &mut extracts {
+                    let has_kept_way = relation.members.iter()
+                        .any(|member| member.kind == OsmElementType::Way && extract.kept_ways.contains(&member.reference));
+                    let has_node_inside = || relation.members.iter().any(|member| member.kind == OsmElementType::Node
+                        && (extract.kept_nodes.contains(&member.reference)
+                            || nearby_nodes.get(&member.reference).is_some_and(|node| extract.in_area(&node.point()))));
+
+                    if has_kept_way || (wanted && has_node_inside()) {
+                        extract.data.relations.insert(id, relation.clone());
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(extracts.into_iter()
+            .map(|mut extract| {
+                extract.data.nodes = extract.kept_nodes.iter()
+                    .filter_map(|id| nearby_nodes.get(id).map(|node| (*id, node.clone())))
+                    .collect();
+                extract.data
+            })
+            .collect())
+    }
+}
+
+/// What [OsmPbfReader::extract_areas] has kept so far for one area
+struct AreaExtract<'a> {
+    area: &'a TargetArea,
+    bounds: Rect<f64>,
+    kept_nodes: HashSet<i64>,
+    kept_ways: HashSet<i64>,
+    data: OsmData,
+}
+
+impl AreaExtract<'_> {
+    fn in_area(&self, point: &Point<f64>) -> bool {
+        self.bounds.intersects(point) && self.area.contains(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use geo_types::{coord, MultiPolygon, Rect};
+    use crate::osm::osm_elements::OsmElementType;
+    use crate::osm::osm_pbf::{OsmPbfReader, PbfBlob, MAX_BLOB_SIZE};
+    use crate::protobuf::tests::ProtobufWriter;
+    use crate::target_area::TargetArea;
+
+    fn blob(kind: &str, block: ProtobufWriter, compress: bool) -> Vec<u8> {
+        let blob = if compress {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&block.0).unwrap();
+            ProtobufWriter::default().uint(2, block.0.len() as u64).bytes(3, &encoder.finish().unwrap())
+        } else {
+            ProtobufWriter::default().bytes(1, &block.0)
+        };
+
+        let header = ProtobufWriter::default().string(1, kind).uint(3, blob.0.len() as u64);
+        let mut bytes = (header.0.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(header.0);
+        bytes.extend(blob.0);
+        bytes
+    }
+
+    /// A tiny extract around Central: two bus stops as dense nodes, one stop outside the area, a
+    /// platform way and a route relation
+    fn test_pbf() -> Vec<u8> {
+        let header = ProtobufWriter::default()
+            .message(1, ProtobufWriter::default().sint(1, 151_000_000_000).sint(2, 152_000_000_000).sint(3, -33_000_000_000).sint(4, -34_000_000_000))
+            .string(4, "OsmSchema-V0.6")
+            .string(4, "DenseNodes");
+
+        let strings = ["", "highway", "bus_stop", "name", "Stand A", "public_transport", "platform", "route", "bus", "type", "mapper"];
+        let string_table = strings.iter().fold(ProtobufWriter::default(), |table, s| table.string(1, s));
+
+        // Coordinates in units of 100 nanodegrees, delta coded
+        let dense = ProtobufWriter::default()
+            .packed_sint(1, &[1, 1, 1, 1])
+            .message(5, ProtobufWriter::default()
+                .bytes(1, &[3, 1, 1, 1])
+                .packed_sint(2, &[1_690_000_000, 0, 0, 0])
+                .packed_sint(5, &[10, 0, 0, 0]))
+            .packed_sint(8, &[-338_837_100, -1_100, 100, -72_000])
+            .packed_sint(9, &[1_512_065_200, 1_100, -5_300, -56_600])
+            .bytes(10, &[1, 2, 3, 4, 0, 0, 0, 1, 2, 0]);
+
+        let way = ProtobufWriter::default()
+            .uint(1, 20)
+            .bytes(2, &[5])
+            .bytes(3, &[6])
+            .packed_sint(8, &[1, 1, 1]);
+
+        let relation = ProtobufWriter::default()
+            .uint(1, 30)
+            .bytes(2, &[7, 9])
+            .bytes(3, &[8, 7])
+            .bytes(8, &[0, 0])
+            .packed_sint(9, &[1, 19])
+            .bytes(10, &[0, 1]);
+
+        let block = ProtobufWriter::default()
+            .message(1, string_table)
+            .message(2, ProtobufWriter::default().message(2, dense))
+            .message(2, ProtobufWriter::default().message(3, way).message(4, relation));
+
+        let mut pbf = blob("OSMHeader", header, false);
+        pbf.extend(blob("OSMData", block, true));
+        pbf
+    }
+
+    #[test]
+    fn test_read_all() {
+        let data = OsmPbfReader::new(Cursor::new(test_pbf())).unwrap().read_all().unwrap();
+
+        assert_eq!(data.nodes.len(), 4);
+        let stand_a = data.node(1).unwrap();
+        assert!((stand_a.lat - -33.88371).abs() < 1e-9);
+        assert!((stand_a.lon - 151.20652).abs() < 1e-9);
+        assert_eq!(stand_a.tags.get("name").map(String::as_str), Some("Stand A"));
+        assert_eq!(stand_a.meta.version, Some(3));
+        assert_eq!(stand_a.meta.user.as_deref(), Some("mapper"));
+        assert!(data.node(2).unwrap().tags.is_empty());
+
+        assert_eq!(data.way(20).unwrap().nodes, vec![1, 2, 3]);
+        let route = data.relation(30).unwrap();
+        assert_eq!(route.members[1].kind, OsmElementType::Way);
+        assert_eq!(route.members[1].reference, 20);
+    }
+
+    #[test]
+    fn test_extract_public_transport() {
+        let area = TargetArea {
+            name: "Central".to_string(),
+            area: MultiPolygon::new(vec![Rect::new(coord! { x: 151.2060, y: -33.8845 }, coord! { x: 151.2070, y: -33.8830 }).to_polygon()]),
+        };
+
+        let data = OsmPbfReader::new(Cursor::new(test_pbf())).unwrap().extract_public_transport(&area).unwrap();
+
+        // Node 4 is a bus stop, but a long way south of Central
+        assert!(data.node(1).is_some());
+        assert!(data.node(4).is_none());
+        // The platform way's untagged nodes come along for its geometry
+        assert!(data.way_line(20).is_some());
+        assert!(data.relation(30).is_some());
+    }
+
+    #[test]
+    fn test_extract_areas() {
+        let central = TargetArea {
+            name: "Central".to_string(),
+            area: MultiPolygon::new(vec![Rect::new(coord! { x: 151.2060, y: -33.8845 }, coord! { x: 151.2070, y: -33.8830 }).to_polygon()]),
+        };
+        let haymarket = TargetArea {
+            name: "Haymarket".to_string(),
+            area: MultiPolygon::new(vec![Rect::new(coord! { x: 151.1990, y: -33.8920 }, coord! { x: 151.2020, y: -33.8900 }).to_polygon()]),
+        };
+
+        let areas = [central, haymarket];
+        let extracts = OsmPbfReader::new(Cursor::new(test_pbf())).unwrap()
+            .extract_areas(&areas, |_| true)
+            .unwrap();
+
+        assert_eq!(extracts.len(), 2);
+        assert!(extracts[0].node(1).is_some());
+        assert!(extracts[0].node(4).is_none());
+        assert!(extracts[0].relation(30).is_some());
+        assert!(extracts[1].node(4).is_some());
+        assert!(extracts[1].node(1).is_none());
+        assert!(extracts[1].way(20).is_none());
+        assert!(extracts[1].relation(30).is_none());
+
+        // The route isn't wanted, but it has the platform way in it
+        let extracts = OsmPbfReader::new(Cursor::new(test_pbf())).unwrap()
+            .extract_areas(&areas, |tags| tags.get("public_transport").is_some_and(|value| value == "platform"))
+            .unwrap();
+        assert!(extracts[0].way(20).is_some());
+        assert!(extracts[0].relation(30).is_some());
+    }
+
+    #[test]
+    fn test_blob_size_limits() {
+        let compress = |data: &[u8]| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        // Zeroes compress so well that a bomb is only a few kilobytes
+        let bomb = PbfBlob { zlib_data: Some(compress(&vec![0; MAX_BLOB_SIZE + 1])), ..Default::default() };
+        assert!(bomb.into_data().is_err());
+
+        let short = PbfBlob { raw_size: Some(100), zlib_data: Some(compress(&[1; 50])), ..Default::default() };
+        assert!(short.into_data().is_err());
+
+        let exact = PbfBlob { raw_size: Some(50), zlib_data: Some(compress(&[1; 50])), ..Default::default() };
+        assert_eq!(exact.into_data().unwrap(), vec![1; 50]);
+    }
+
+    #[test]
+    fn test_unsupported_features() {
+        let header = ProtobufWriter::default().string(4, "OsmSchema-V0.6").string(4, "LocationsOnWays");
+        assert!(OsmPbfReader::new(Cursor::new(blob("OSMHeader", header, false))).is_err());
+    }
+}
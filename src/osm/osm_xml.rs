@@ -0,0 +1,367 @@
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, SecondsFormat, Utc};
+use geo_types::{coord, Rect};
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use crate::osm::osm_change::{OsmDiffEntry, OsmDiffResult};
+use crate::osm::osm_elements::{OsmData, OsmElementType, OsmMember, OsmMeta, OsmNode, OsmRelation, OsmTags, OsmWay};
+
+/// An element that's been opened but not closed yet, collecting its child tags
+enum OsmPending {
+    Node(OsmNode),
+    Way(OsmWay),
+    Relation(OsmRelation),
+}
+
+impl OsmPending {
+    fn tags(&mut self) -> &mut OsmTags {
+        match self {
+            OsmPending::Node(node) => &mut node.tags,
+            OsmPending::Way(way) => &mut way.tags,
+            OsmPending::Relation(relation) => &mut relation.tags,
+        }
+    }
+
+    fn finish(self, data: &mut OsmData) {
+        match self {
+            OsmPending::Node(node) => { data.nodes.insert(node.id, node); }
+            OsmPending::Way(way) => { data.ways.insert(way.id, way); }
+            OsmPending::Relation(relation) => { data.relations.insert(relation.id, relation); }
+        }
+    }
+}
+
+/// The attributes of an element, unescaped up front so they can be looked up by name
+struct OsmAttributes<'a> {
+    element: &'a str,
+    values: Vec<(String, String)>,
+}
+
+impl<'a> OsmAttributes<'a> {
+    fn read(element: &'a str, start: &BytesStart) -> anyhow::Result<OsmAttributes<'a>> {
+        let values = start.attributes()
+            .map(|attribute| {
+                let attribute = attribute?;
+                let key = String::from_utf8(attribute.key.as_ref().to_vec())?;
+                let value = attribute.unescape_value()?.into_owned();
+                Ok((key, value))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(OsmAttributes { element, values })
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.values.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn optional<T: FromStr>(&self, name: &str) -> anyhow::Result<Option<T>> where T::Err: std::error::Error + Send + Sync + 'static {
+        self.get(name)
+            .map(|value| value.parse::<T>().with_context(|| format!("Invalid {name} on <{}>: {value}", self.element)))
+            .transpose()
+    }
+
+    fn required<T: FromStr>(&self, name: &str) -> anyhow::Result<T> where T::Err: std::error::Error + Send + Sync + 'static {
+        self.optional(name)?.ok_or_else(|| anyhow!("<{}> is missing {name}", self.element))
+    }
+
+    fn meta(&self) -> anyhow::Result<OsmMeta> {
+        Ok(OsmMeta {
+            version: self.optional("version")?,
+            changeset: self.optional("changeset")?,
+            timestamp: self.optional::<DateTime<Utc>>("timestamp")?,
+            user: self.get("user").map(str::to_string),
+            uid: self.optional("uid")?,
+            visible: self.optional("visible")?.unwrap_or(true),
+        })
+    }
+}
+
+/// Parses an OSM XML document, as returned by the API or saved by JOSM.
+///
+/// Anything that isn't a node, way, relation or bounds (such as `<note>` or `<meta>`) is skipped,
+/// except for a `<remark>` reporting a runtime error. That's how Overpass says a query failed part
+/// way through, with whatever it had found so far, so it's an error rather than a quiet gap.
+pub fn parse_osm_xml<R: BufRead>(input: R) -> anyhow::Result<OsmData> {
+    let mut reader = Reader::from_reader(input);
+    reader.trim_text(true);
+
+    let mut data = OsmData::default();
+    let mut pending: Option<OsmPending> = None;
+    let mut in_remark = false;
+    let mut buf = Vec::new();
+
+    loop {
+        let event = reader.read_event_into(&mut buf)
+            .with_context(|| format!("Failed to parse OSM XML at byte {}", reader.buffer_position()))?;
+
+        match event {
+            Event::Start(ref start) | Event::Empty(ref start) => {
+                let is_empty = matches!(event, Event::Empty(_));
+                let name = String::from_utf8(start.name().as_ref().to_vec())?;
+                let attributes = OsmAttributes::read(&name, start)?;
+
+                match name.as_str() {
+                    "node" => pending = Some(OsmPending::Node(OsmNode {
+                        id: attributes.required("id")?,
+                        // Deleted nodes in history don't have a location
+                        lat: attributes.optional("lat")?.unwrap_or(f64::NAN),
+                        lon: attributes.optional("lon")?.unwrap_or(f64::NAN),
+                        tags: OsmTags::new(),
+                        meta: attributes.meta()?,
+                    })),
+                    "way" => pending = Some(OsmPending::Way(OsmWay {
+                        id: attributes.required("id")?,
+                        nodes: Vec::new(),
+                        tags: OsmTags::new(),
+                        meta: attributes.meta()?,
+                    })),
+                    "relation" => pending = Some(OsmPending::Relation(OsmRelation {
+                        id: attributes.required("id")?,
+                        members: Vec::new(),
+                        tags: OsmTags::new(),
+                        meta: attributes.meta()?,
+                    })),
+                    "tag" => if let Some(pending) = &mut pending {
+                        pending.tags().insert(attributes.required("k")?, attributes.required("v")?);
+                    },
+                    "nd" => if let Some(OsmPending::Way(way)) = &mut pending {
+                        way.nodes.push(attributes.required("ref")?);
+                    },
+                    "member" => if let Some(OsmPending::Relation(relation)) = &mut pending {
+                        relation.members.push(OsmMember {
+                            kind: attributes.required::<OsmElementType>("type")?,
+                            reference: attributes.required("ref")?,
+                            role: attributes.get("role").unwrap_or_default().to_string(),
+                        });
+                    },
+                    "remark" => in_remark = !is_empty,
+                    "bounds" => data.bounds = Some(Rect::new(
+                        coord! { x: attributes.required::<f64>("minlon")?, y: attributes.required::<f64>("minlat")? },
+                        coord! { x: attributes.required::<f64>("maxlon")?, y: attributes.required::<f64>("maxlat")? },
+                    )),
+                    _ => {}
+                }
+
+                if is_empty && matches!(name.as_str(), "node" | "way" | "relation") {
+                    if let Some(pending) = pending.take() {
+                        pending.finish(&mut data);
+                    }
+                }
+            }
+            Event::Text(ref text) if in_remark => {
+                let remark = text.unescape()?;
+                if remark.starts_with("runtime error") {
+                    bail!("Overpass query failed: {remark}");
+                }
+            }
+            Event::End(ref end) => {
+                in_remark = false;
+                if matches!(end.name().as_ref(), b"node" | b"way" | b"relation") {
+                    match pending.take() {
+                        Some(pending) => pending.finish(&mut data),
+                        None => bail!("Unexpected closing tag at byte {}", reader.buffer_position()),
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Ok(data)
+}
+
+/// Parses the `<diffResult>` the API returns from an upload
+pub fn parse_diff_result<R: BufRead>(input: R) -> anyhow::Result<OsmDiffResult> {
+    let mut reader = Reader::from_reader(input);
+    reader.trim_text(true);
+
+    let mut result = OsmDiffResult::default();
+    let mut buf = Vec::new();
+
+    loop {
+        let event = reader.read_event_into(&mut buf)
+            .with_context(|| format!("Failed to parse diff result at byte {}", reader.buffer_position()))?;
+
+        match event {
+            Event::Start(ref start) | Event::Empty(ref start) => {
+                let name = String::from_utf8(start.name().as_ref().to_vec())?;
+                if let Ok(kind) = name.parse::<OsmElementType>() {
+                    let attributes = OsmAttributes::read(&name, start)?;
+                    result.elements.insert((kind, attributes.required("old_id")?), OsmDiffEntry {
+                        new_id: attributes.optional("new_id")?,
+                        new_version: attributes.optional("new_version")?,
+                    });
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Ok(result)
+}
+
+/// Attributes for an element, in the order they're written
+pub(crate) type OsmXmlAttributes = Vec<(&'static str, String)>;
+
+/// The attributes for `meta`, in the order the API writes them. `full` includes who made the
+/// last edit and when, which the API ignores on upload but JOSM shows.
+pub(crate) fn meta_attributes(meta: &OsmMeta, full: bool) -> OsmXmlAttributes {
+    let mut attributes = OsmXmlAttributes::new();
+    if full {
+        attributes.push(("visible", meta.visible.to_string()));
+    }
+    if let Some(version) = meta.version {
+        attributes.push(("version", version.to_string()));
+    }
+    if let Some(changeset) = meta.changeset {
+        attributes.push(("changeset", changeset.to_string()));
+    }
+    if full {
+        if let Some(timestamp) = meta.timestamp {
+            attributes.push(("timestamp", timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        }
+        if let Some(user) = &meta.user {
+            attributes.push(("user", user.clone()));
+        }
+        if let Some(uid) = meta.uid {
+            attributes.push(("uid", uid.to_string()));
+        }
+    }
+
+    attributes
+}
+
+/// Writes OSM XML elements, indented the same way the API does so the output diffs nicely
+pub(crate) struct OsmXmlWriter<W: Write> {
+    writer: Writer<W>,
+}
+
+impl<W: Write> OsmXmlWriter<W> {
+    pub(crate) fn new(output: W) -> anyhow::Result<OsmXmlWriter<W>> {
+        let mut writer = Writer::new_with_indent(output, b' ', 2);
+        writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+
+        Ok(OsmXmlWriter { writer })
+    }
+
+    pub(crate) fn start(&mut self, name: &str, attributes: &[(&str, String)]) -> anyhow::Result<()> {
+        let start = BytesStart::new(name).with_attributes(attributes.iter().map(|(key, value)| (*key, value.as_str())));
+        self.writer.write_event(Event::Start(start))?;
+        Ok(())
+    }
+
+    pub(crate) fn empty(&mut self, name: &str, attributes: &[(&str, String)]) -> anyhow::Result<()> {
+        let start = BytesStart::new(name).with_attributes(attributes.iter().map(|(key, value)| (*key, value.as_str())));
+        self.writer.write_event(Event::Empty(start))?;
+        Ok(())
+    }
+
+    pub(crate) fn end(&mut self, name: &str) -> anyhow::Result<()> {
+        self.writer.write_event(Event::End(BytesEnd::new(name)))?;
+        Ok(())
+    }
+
+    /// Writes an element with its `id` first, then `attributes`, then its children. Elements with
+    /// no children are written as empty tags.
+    fn element(&mut self, name: &str, id: i64, attributes: OsmXmlAttributes, children: Vec<(&str, OsmXmlAttributes)>, tags: &OsmTags) -> anyhow::Result<()> {
+        let attributes: OsmXmlAttributes = std::iter::once(("id", id.to_string())).chain(attributes).collect();
+        if children.is_empty() && tags.is_empty() {
+            return self.empty(name, &attributes);
+        }
+
+        self.start(name, &attributes)?;
+        for (child, attributes) in children {
+            self.empty(child, &attributes)?;
+        }
+        for (key, value) in tags {
+            self.empty("tag", &[("k", key.clone()), ("v", value.clone())])?;
+        }
+        self.end(name)
+    }
+
+    pub(crate) fn node(&mut self, node: &OsmNode, mut attributes: OsmXmlAttributes) -> anyhow::Result<()> {
+        attributes.push(("lat", format!("{:.7}", node.lat)));
+        attributes.push(("lon", format!("{:.7}", node.lon)));
+        self.element("node", node.id, attributes, Vec::new(), &node.tags)
+    }
+
+    pub(crate) fn way(&mut self, way: &OsmWay, attributes: OsmXmlAttributes) -> anyhow::Result<()> {
+        let children = way.nodes.iter().map(|node| ("nd", vec![("ref", node.to_string())])).collect();
+        self.element("way", way.id, attributes, children, &way.tags)
+    }
+
+    pub(crate) fn relation(&mut self, relation: &OsmRelation, attributes: OsmXmlAttributes) -> anyhow::Result<()> {
+        let children = relation.members.iter()
+            .map(|member| ("member", vec![
+                ("type", member.kind.to_string()),
+                ("ref", member.reference.to_string()),
+                ("role", member.role.clone()),
+            ]))
+            .collect();
+        self.element("relation", relation.id, attributes, children, &relation.tags)
+    }
+
+    pub(crate) fn finish(self) -> anyhow::Result<W> {
+        let mut output = self.writer.into_inner();
+        output.write_all(b"\n")?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::osm::osm_elements::OsmElementType;
+    use crate::osm::osm_xml::{parse_diff_result, parse_osm_xml};
+
+    const TEST_OSM: &str = include_str!("../../fixtures/osm/central.osm");
+
+    #[test]
+    fn test_parse_osm_xml() {
+        let data = parse_osm_xml(TEST_OSM.as_bytes()).unwrap();
+
+        assert_eq!(data.nodes.len(), 4);
+        assert!(data.bounds.is_some());
+
+        let stand_a = data.node(4000001).unwrap();
+        assert_eq!(stand_a.tags.get("name").map(String::as_str), Some("Central Station, Eddy Av, Stand A"));
+        assert_eq!(stand_a.tags.get("ref:tfnsw").map(String::as_str), Some("2000322"));
+        assert_eq!(stand_a.meta.version, Some(3));
+        assert_eq!(stand_a.meta.user.as_deref(), Some("mapper & co"));
+
+        assert_eq!(data.way_line(5000001).unwrap().0.len(), 2);
+
+        let route = data.relation(6000001).unwrap();
+        assert_eq!(route.members.len(), 3);
+        assert_eq!(route.members[1].kind, OsmElementType::Way);
+        assert_eq!(route.members[0].role, "platform");
+    }
+
+    #[test]
+    fn test_parse_diff_result() {
+        let diff = r#"<diffResult version="0.6" generator="OpenStreetMap server">
+            <node old_id="-1" new_id="4000010" new_version="1"/>
+            <node old_id="4000002" new_id="4000002" new_version="2"/>
+            <way old_id="5000002"/>
+        </diffResult>"#;
+        let result = parse_diff_result(diff.as_bytes()).unwrap();
+
+        assert_eq!(result.new_id(OsmElementType::Node, -1), Some(4000010));
+        assert_eq!(result.elements[&(OsmElementType::Node, 4000002)].new_version, Some(2));
+        assert_eq!(result.new_id(OsmElementType::Way, 5000002), None);
+    }
+
+    #[test]
+    fn test_malformed_osm_xml() {
+        assert!(parse_osm_xml(r#"<osm><node id="x" lat="1" lon="2"/></osm>"#.as_bytes()).is_err());
+        assert!(parse_osm_xml(r#"<osm><node id="1" lat="1" lon="2"></way></osm>"#.as_bytes()).is_err());
+    }
+}
@@ -1,14 +1,330 @@
-use std::net::SocketAddr;
-use reqwest::header::{HeaderMap, HeaderValue, ToStrError};
-use reqwest::StatusCode;
-use reqwest::tls::Version;
-use tempfile::NamedTempFile;
+use std::io::Cursor;
+use anyhow::Context;
+use std::sync::Arc;
+use geo_types::Rect;
+use reqwest::header::{HeaderMap, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, InvalidHeaderValue, ToStrError};
+use reqwest::{Client, IntoUrl, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use thiserror::Error;
 use url::Url;
-use zip::ZipArchive;
-use crate::transport_nswapi::TransportNswApiClient;
+use crate::errors::IntoAnyhowError;
+use crate::osm::osm_change::{write_osm_change, OsmChange, OsmDiffResult};
+use crate::osm::osm_elements::{OsmData, OsmElementType, OsmNode, OsmRelation, OsmTags, OsmWay};
+use crate::osm::osm_xml::{parse_diff_result, parse_osm_xml, OsmXmlWriter};
 
+/// OSM asks that every client identifies itself
+/// (<https://operations.osmfoundation.org/policies/api/>)
+pub(crate) const USER_AGENT: &str = concat!("osm-nsw/", env!("CARGO_PKG_VERSION"));
+
+pub const OSM_API_BASE: &str = "https://api.openstreetmap.org/api/0.6/";
+/// The sandbox at <https://master.apis.dev.openstreetmap.org>, which has its own accounts and data
+pub const OSM_DEV_API_BASE: &str = "https://master.apis.dev.openstreetmap.org/api/0.6/";
+
+/// The `source` tag on our changesets
+pub const CHANGESET_SOURCE: &str = "Transport for NSW";
+
+/// Which OSM API to talk to. The sandbox is the default, so that nothing ends up in the real map
+/// without asking for it.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsmApiServer {
+    Production,
+    #[default]
+    Dev,
+    /// Any other API 0.6 instance, such as a local stand-in, by its `api/0.6/` URL
+    Custom(Url),
+}
+
+impl OsmApiServer {
+    pub fn api_base(&self) -> anyhow::Result<Url> {
+        match self {
+            OsmApiServer::Production => Url::parse(OSM_API_BASE).map_anyhow(),
+            OsmApiServer::Dev => Url::parse(OSM_DEV_API_BASE).map_anyhow(),
+            OsmApiServer::Custom(api_base) => Ok(api_base.clone()),
+        }
+    }
+}
+
+/// Errors from the API that are worth handling, rather than just reporting
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum OsmApiError {
+    #[error("Uploads haven't been confirmed")]
+    UploadNotConfirmed,
+    #[error("Uploading needs an access token")]
+    NotAuthenticated,
+    /// Someone else has edited the element since we downloaded it
+    #[error("{kind} {id} is at version {server_version}, but we uploaded version {our_version}")]
+    VersionConflict { kind: OsmElementType, id: i64, our_version: u32, server_version: u32 },
+    #[error("Changeset {id} was closed at {closed_at}")]
+    ChangesetClosed { id: u64, closed_at: String },
+    /// Any other 409, with the API's message
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    /// The change touches or refers to elements that were read from a different server, where the
+    /// same IDs belong to unrelated elements
+    #[error("Can only create self-contained elements on {api_base}, since the data being changed came from {data_api_base}")]
+    DataFromOtherServer { api_base: String, data_api_base: String },
+}
+
+impl OsmApiError {
+    /// Interprets the message of a 409 response
+    fn from_conflict(message: &str) -> OsmApiError {
+        let message = message.trim();
+
+        // "Version mismatch: Provided 2, server had: 3 of Node 4000001"
+        if let Some(rest) = message.strip_prefix("Version mismatch: Provided ") {
+            let parsed = rest.split_once(", server had: ")
+                .and_then(|(ours, rest)| Some((ours, rest.split_once(" of ")?)))
+                .and_then(|(ours, (theirs, element))| {
+                    let (kind, id) = element.split_once(' ')?;
+                    Some(OsmApiError::VersionConflict {
+                        kind: kind.to_lowercase().parse().ok()?,
+                        id: id.parse().ok()?,
+                        our_version: ours.parse().ok()?,
+                        server_version: theirs.parse().ok()?,
+                    })
+                });
+
+            if let Some(error) = parsed {
+                return error;
+            }
+        }
+
+        // "The changeset 1234 was closed at 2024-04-01 00:00:00 UTC"
+        if let Some((id, closed_at)) = message.strip_prefix("The changeset ").and_then(|rest| rest.split_once(" was closed at ")) {
+            if let Ok(id) = id.parse() {
+                return OsmApiError::ChangesetClosed { id, closed_at: closed_at.to_string() };
+            }
+        }
+
+        OsmApiError::Conflict(message.to_string())
+    }
+}
+
+/// Tags for a changeset of our edits
+pub fn changeset_tags(comment: &str) -> OsmTags {
+    OsmTags::from([
+        ("comment".to_string(), comment.to_string()),
+        ("source".to_string(), CHANGESET_SOURCE.to_string()),
+        ("created_by".to_string(), USER_AGENT.to_string()),
+    ])
+}
+
+/// A client for the OpenStreetMap API 0.6, see <https://wiki.openstreetmap.org/wiki/API_v0.6>
 pub struct OsmRouter {
-    transport_nsw: TransportNswApiClient,
+    api_base: Url,
+    client: Arc<Client>,
+    /// An OAuth2 access token with the `write_api` scope, needed for anything that edits the map
+    access_token: Option<String>,
+    /// Uploads are refused until this is set, so that nothing is uploaded by accident
+    uploads_confirmed: bool,
+    /// The API the elements being changed were read from, if it isn't this one
+    data_api_base: Option<Url>,
+}
+
+impl OsmRouter {
+    /// A router for the default server, which is the dev sandbox
+    pub fn new() -> anyhow::Result<OsmRouter> {
+        Self::for_server(&OsmApiServer::default())
+    }
+
+    pub fn for_server(server: &OsmApiServer) -> anyhow::Result<OsmRouter> {
+        Self::with_api_base(server.api_base()?)
+    }
+
+    pub fn with_api_base<T: IntoUrl>(api_base: T) -> anyhow::Result<OsmRouter> {
+        let api_base = api_base.into_url()?;
+        let client = Arc::new(Client::builder().user_agent(USER_AGENT).build()?);
+
+        Ok(OsmRouter { api_base, client, access_token: None, uploads_confirmed: false, data_api_base: None })
+    }
+
+    pub fn with_access_token<S: Into<String>>(self, access_token: S) -> OsmRouter {
+        OsmRouter { access_token: Some(access_token.into()), ..self }
+    }
+
+    pub fn with_uploads_confirmed(self, uploads_confirmed: bool) -> OsmRouter {
+        OsmRouter { uploads_confirmed, ..self }
+    }
+
+    /// Where the elements we'll be uploading changes to were read from. If it's not this API,
+    /// only uploads that just create elements are allowed.
+    pub fn with_data_from(self, data_api_base: Url) -> OsmRouter {
+        OsmRouter { data_api_base: Some(data_api_base), ..self }
+    }
+
+    fn authenticated(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.access_token {
+            Some(access_token) => request.bearer_auth(access_token),
+            None => request,
+        }
+    }
+
+    /// Turns 409s into [OsmApiError]s, and any other error status into a [reqwest::Error]
+    async fn check_response(response: Response) -> anyhow::Result<Response> {
+        if response.status() == StatusCode::CONFLICT {
+            let message = response.text().await?;
+            return Err(OsmApiError::from_conflict(&message).into());
+        }
+
+        response.error_for_status().map_anyhow()
+    }
+
+    /// Fetches and parses an OSM XML document, treating elements that don't exist (404) or have
+    /// been deleted (410) as missing rather than as errors
+    async fn get_osm(&self, path: &str) -> anyhow::Result<Option<OsmData>> {
+        let response = self.authenticated(self.client.get(self.api_base.join(path)?))
+            .send()
+            .await?;
+
+        if matches!(response.status(), StatusCode::NOT_FOUND | StatusCode::GONE) {
+            return Ok(None);
+        }
+
+        let body = Self::check_response(response).await?.bytes().await?;
+        parse_osm_xml(Cursor::new(body)).map(Some)
+    }
+
+    pub async fn node(&self, id: i64) -> anyhow::Result<Option<OsmNode>> {
+        Ok(self.get_osm(&format!("node/{id}")).await?.and_then(|mut data| data.nodes.remove(&id)))
+    }
+
+    pub async fn way(&self, id: i64) -> anyhow::Result<Option<OsmWay>> {
+        Ok(self.get_osm(&format!("way/{id}")).await?.and_then(|mut data| data.ways.remove(&id)))
+    }
+
+    pub async fn relation(&self, id: i64) -> anyhow::Result<Option<OsmRelation>> {
+        Ok(self.get_osm(&format!("relation/{id}")).await?.and_then(|mut data| data.relations.remove(&id)))
+    }
+
+    /// An element along with everything it references: a way's nodes, or a relation's members
+    /// (and the nodes of any member ways)
+    pub async fn full(&self, kind: OsmElementType, id: i64) -> anyhow::Result<Option<OsmData>> {
+        match kind {
+            OsmElementType::Node => self.get_osm(&format!("node/{id}")).await,
+            _ => self.get_osm(&format!("{kind}/{id}/full")).await,
+        }
+    }
+
+    /// Several elements of the same type in one request
+    pub async fn elements(&self, kind: OsmElementType, ids: &[i64]) -> anyhow::Result<OsmData> {
+        if ids.is_empty() {
+            return Ok(OsmData::default());
+        }
+
+        let ids = ids.iter().map(i64::to_string).collect::<Vec<_>>().join(",");
+        Ok(self.get_osm(&format!("{kind}s?{kind}s={ids}")).await?.unwrap_or_default())
+    }
+
+    /// Everything inside `bounds` (as longitude, latitude). The API refuses areas larger than
+    /// 0.25 square degrees, or with more than 50,000 nodes.
+    pub async fn map(&self, bounds: Rect<f64>) -> anyhow::Result<OsmData> {
+        let (min, max) = (bounds.min(), bounds.max());
+        let mut endpoint = self.api_base.join("map").map_anyhow()?;
+        endpoint.query_pairs_mut().append_pair("bbox", &format!("{},{},{},{}", min.x, min.y, max.x, max.y));
+
+        let response = self.authenticated(self.client.get(endpoint))
+            .send()
+            .await?;
+
+        let body = Self::check_response(response).await?.bytes().await?;
+        parse_osm_xml(Cursor::new(body))
+    }
+
+    /// Checks that we're allowed to edit before making any requests that would
+    fn check_can_upload(&self) -> Result<(), OsmApiError> {
+        if !self.uploads_confirmed {
+            return Err(OsmApiError::UploadNotConfirmed);
+        }
+        if self.access_token.is_none() {
+            return Err(OsmApiError::NotAuthenticated);
+        }
+
+        Ok(())
+    }
+
+    /// Refuses to modify, delete or refer to elements that came from another server, since their IDs
+    /// mean something else here. New elements are fine as long as they only refer to each other.
+    pub fn check_change_matches_server(&self, change: &OsmChange) -> Result<(), OsmApiError> {
+        let only_creates = [&change.modify, &change.delete].iter()
+            .all(|data| data.nodes.is_empty() && data.ways.is_empty() && data.relations.is_empty());
+        let self_contained = change.create.ways.values().all(|way| way.nodes.iter().all(|node| *node < 0))
+            && change.create.relations.values().all(|relation| relation.members.iter().all(|member| member.reference < 0));
+
+        match &self.data_api_base {
+            Some(data_api_base) if *data_api_base != self.api_base && !(only_creates && self_contained) => Err(OsmApiError::DataFromOtherServer {
+                api_base: self.api_base.to_string(),
+                data_api_base: data_api_base.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Opens a changeset with `tags`, returning its ID
+    pub async fn open_changeset(&self, tags: &OsmTags) -> anyhow::Result<u64> {
+        self.check_can_upload()?;
+
+        let mut writer = OsmXmlWriter::new(Vec::new())?;
+        writer.start("osm", &[])?;
+        writer.start("changeset", &[])?;
+        for (key, value) in tags {
+            writer.empty("tag", &[("k", key.clone()), ("v", value.clone())])?;
+        }
+        writer.end("changeset")?;
+        writer.end("osm")?;
+
+        let response = self.authenticated(self.client.put(self.api_base.join("changeset/create")?))
+            .header(reqwest::header::CONTENT_TYPE, "text/xml")
+            .body(writer.finish()?)
+            .send()
+            .await?;
+
+        let id = Self::check_response(response).await?.text().await?;
+        id.trim().parse().with_context(|| format!("Expected a changeset ID, but got {id}"))
+    }
+
+    /// Uploads `change` into an open changeset
+    pub async fn upload_to_changeset(&self, changeset: u64, change: &OsmChange) -> anyhow::Result<OsmDiffResult> {
+        self.check_can_upload()?;
+        self.check_change_matches_server(change)?;
+
+        let body = write_osm_change(change, Some(changeset), Vec::new())?;
+        let response = self.authenticated(self.client.post(self.api_base.join(&format!("changeset/{changeset}/upload"))?))
+            .header(reqwest::header::CONTENT_TYPE, "text/xml")
+            .body(body)
+            .send()
+            .await?;
+
+        let body = Self::check_response(response).await?.bytes().await?;
+        parse_diff_result(Cursor::new(body))
+    }
+
+    pub async fn close_changeset(&self, changeset: u64) -> anyhow::Result<()> {
+        self.check_can_upload()?;
+
+        let response = self.authenticated(self.client.put(self.api_base.join(&format!("changeset/{changeset}/close"))?))
+            .send()
+            .await?;
+
+        Self::check_response(response).await?;
+        Ok(())
+    }
+
+    /// Uploads `change` in a changeset of its own, returning the changeset's ID and the new IDs
+    /// and versions of everything in it. The changeset is closed whether or not the upload worked.
+    pub async fn upload(&self, change: &OsmChange, tags: &OsmTags) -> anyhow::Result<(u64, OsmDiffResult)> {
+        // Checked before opening the changeset, so a refused upload doesn't leave one behind
+        self.check_change_matches_server(change)?;
+
+        let changeset = self.open_changeset(tags).await?;
+        let result = self.upload_to_changeset(changeset, change).await;
+        let closed = self.close_changeset(changeset).await;
+
+        let result = result.with_context(|| format!("Failed to upload to changeset {changeset}"))?;
+        closed?;
+
+        Ok((changeset, result))
+    }
 }
 
 #[derive(Debug)]
@@ -23,6 +339,84 @@ pub enum ResourceWithValidity<T> {
     Missing(T),
 }
 
+impl<T> ResourceWithValidity<T> {
+    /// Picks the right variant for whichever validators the server gave us
+    pub fn new(value: T, etag: Option<String>, last_modified: Option<String>) -> ResourceWithValidity<T> {
+        match (etag, last_modified) {
+            (Some(etag), Some(last_modified)) => ResourceWithValidity::ETagAndModification { value, etag, last_modified },
+            (Some(etag), None) => ResourceWithValidity::ETag((value, etag)),
+            (None, Some(last_modified)) => ResourceWithValidity::LastModified((value, last_modified)),
+            (None, None) => ResourceWithValidity::Missing(value),
+        }
+    }
+
+    pub fn value(&self) -> &T {
+        match self {
+            ResourceWithValidity::ETagAndModification { value, .. } => value,
+            ResourceWithValidity::ETag((value, _)) => value,
+            ResourceWithValidity::LastModified((value, _)) => value,
+            ResourceWithValidity::Missing(value) => value,
+        }
+    }
+
+    pub fn value_mut(&mut self) -> &mut T {
+        match self {
+            ResourceWithValidity::ETagAndModification { value, .. } => value,
+            ResourceWithValidity::ETag((value, _)) => value,
+            ResourceWithValidity::LastModified((value, _)) => value,
+            ResourceWithValidity::Missing(value) => value,
+        }
+    }
+
+    pub fn into_value(self) -> T {
+        self.into_parts().0
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        match self {
+            ResourceWithValidity::ETagAndModification { etag, .. } => Some(etag),
+            ResourceWithValidity::ETag((_, etag)) => Some(etag),
+            _ => None,
+        }
+    }
+
+    pub fn last_modified(&self) -> Option<&str> {
+        match self {
+            ResourceWithValidity::ETagAndModification { last_modified, .. } => Some(last_modified),
+            ResourceWithValidity::LastModified((_, last_modified)) => Some(last_modified),
+            _ => None,
+        }
+    }
+
+    /// Splits into the value, ETag and Last-Modified
+    pub fn into_parts(self) -> (T, Option<String>, Option<String>) {
+        match self {
+            ResourceWithValidity::ETagAndModification { value, etag, last_modified } => (value, Some(etag), Some(last_modified)),
+            ResourceWithValidity::ETag((value, etag)) => (value, Some(etag), None),
+            ResourceWithValidity::LastModified((value, last_modified)) => (value, None, Some(last_modified)),
+            ResourceWithValidity::Missing(value) => (value, None, None),
+        }
+    }
+
+    pub fn try_map<U, E, F: FnOnce(T) -> Result<U, E>>(self, f: F) -> Result<ResourceWithValidity<U>, E> {
+        let (value, etag, last_modified) = self.into_parts();
+        Ok(ResourceWithValidity::new(f(value)?, etag, last_modified))
+    }
+
+    /// The `If-None-Match` and `If-Modified-Since` headers to revalidate this resource with
+    pub fn conditional_headers(&self) -> Result<HeaderMap, InvalidHeaderValue> {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = self.etag() {
+            headers.insert(IF_NONE_MATCH, HeaderValue::from_str(etag)?);
+        }
+        if let Some(last_modified) = self.last_modified() {
+            headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_str(last_modified)?);
+        }
+
+        Ok(headers)
+    }
+}
+
 pub trait TryToString {
     type Error;
 
@@ -36,4 +430,166 @@ impl TryToString for &HeaderValue {
     fn try_to_string(self) -> Result<String, Self::Error> {
         self.to_str().map(|v| v.to_owned())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::{coord, Rect};
+    use reqwest::header::USER_AGENT;
+    use reqwest::StatusCode;
+    use reqwest::header::AUTHORIZATION;
+    use crate::osm::osm_change::OsmChange;
+    use crate::osm::osm_elements::{OsmElementType, OsmMember, OsmMeta, OsmNode, OsmRelation};
+    use crate::osm_api_client::{changeset_tags, OsmApiError, OsmApiServer, OsmRouter};
+    use crate::tests::stand_in_server::{StandInResponse, StandInServer};
+
+    const TEST_OSM: &str = include_str!("../fixtures/osm/central.osm");
+
+    async fn stand_in_router() -> (StandInServer, OsmRouter) {
+        let server = StandInServer::start(|request| match request.path.as_str() {
+            "/api/0.6/node/4000001" | "/api/0.6/relation/6000001/full" => StandInResponse::ok(TEST_OSM),
+            path if path.starts_with("/api/0.6/map?") => StandInResponse::ok(TEST_OSM),
+            "/api/0.6/node/4000009" => StandInResponse::status(StatusCode::GONE),
+            _ => StandInResponse::status(StatusCode::NOT_FOUND),
+        }).await;
+        let router = OsmRouter::with_api_base(server.url("/api/0.6/")).unwrap();
+
+        (server, router)
+    }
+
+    #[tokio::test]
+    async fn test_elements_by_id() {
+        let (server, router) = stand_in_router().await;
+
+        let node = router.node(4000001).await.unwrap().unwrap();
+        assert_eq!(node.tags.get("highway").map(String::as_str), Some("bus_stop"));
+        assert!(router.node(4000009).await.unwrap().is_none());
+        assert!(router.way(1).await.unwrap().is_none());
+
+        let route = router.full(OsmElementType::Relation, 6000001).await.unwrap().unwrap();
+        assert!(route.way_line(5000001).is_some());
+
+        assert!(server.requests()[0].headers.get(USER_AGENT).unwrap().to_str().unwrap().starts_with("osm-nsw/"));
+    }
+
+    #[tokio::test]
+    async fn test_map() {
+        let (server, router) = stand_in_router().await;
+
+        let bounds = Rect::new(coord! { x: 151.204, y: -33.886 }, coord! { x: 151.209, y: -33.882 });
+        let data = router.map(bounds).await.unwrap();
+        assert_eq!(data.ways.len(), 1);
+
+        assert_eq!(server.requests()[0].path, "/api/0.6/map?bbox=151.204%2C-33.886%2C151.209%2C-33.882");
+    }
+
+    fn test_change() -> OsmChange {
+        let mut change = OsmChange::default();
+        let tags = [("highway".to_string(), "bus_stop".to_string())].into_iter().collect();
+        change.create.nodes.insert(-1, OsmNode { id: -1, lat: -33.8837, lon: 151.2065, tags, meta: OsmMeta::default() });
+        change
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let server = StandInServer::start(|request| match (request.method.as_str(), request.path.as_str()) {
+            ("PUT", "/api/0.6/changeset/create") => StandInResponse::ok("1234"),
+            ("POST", "/api/0.6/changeset/1234/upload") =>
+                StandInResponse::ok(r#"<diffResult version="0.6"><node old_id="-1" new_id="4000010" new_version="1"/></diffResult>"#),
+            ("PUT", "/api/0.6/changeset/1234/close") => StandInResponse::ok(""),
+            _ => StandInResponse::status(StatusCode::NOT_FOUND),
+        }).await;
+        let router = OsmRouter::for_server(&OsmApiServer::Custom(server.url("/api/0.6/"))).unwrap()
+            .with_access_token("token")
+            .with_uploads_confirmed(true);
+
+        let (changeset, result) = router.upload(&test_change(), &changeset_tags("Add missing bus stops")).await.unwrap();
+        assert_eq!(changeset, 1234);
+        assert_eq!(result.new_id(OsmElementType::Node, -1), Some(4000010));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|request| request.headers.get(AUTHORIZATION).unwrap() == "Bearer token"));
+
+        let create = String::from_utf8_lossy(&requests[0].body);
+        assert!(create.contains(r#"<tag k="comment" v="Add missing bus stops"/>"#));
+        assert!(create.contains(r#"<tag k="source" v="Transport for NSW"/>"#));
+        assert!(String::from_utf8_lossy(&requests[1].body).contains(r#"<node id="-1" changeset="1234""#));
+    }
+
+    #[tokio::test]
+    async fn test_upload_conflict() {
+        let server = StandInServer::start(|request| match (request.method.as_str(), request.path.as_str()) {
+            ("PUT", "/api/0.6/changeset/create") => StandInResponse::ok("1234"),
+            ("POST", _) => StandInResponse::ok("Version mismatch: Provided 2, server had: 3 of Node 4000001").with_status(StatusCode::CONFLICT),
+            _ => StandInResponse::ok(""),
+        }).await;
+        let router = OsmRouter::with_api_base(server.url("/api/0.6/")).unwrap()
+            .with_access_token("token")
+            .with_uploads_confirmed(true);
+
+        let error = router.upload(&test_change(), &changeset_tags("Retag stops")).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<OsmApiError>(), Some(&OsmApiError::VersionConflict {
+            kind: OsmElementType::Node,
+            id: 4000001,
+            our_version: 2,
+            server_version: 3,
+        }));
+
+        // The changeset still gets closed
+        assert_eq!(server.requests().last().unwrap().path, "/api/0.6/changeset/1234/close");
+    }
+
+    #[tokio::test]
+    async fn test_upload_needs_confirmation() {
+        let server = StandInServer::start(|_| StandInResponse::ok("1234")).await;
+        let router = OsmRouter::with_api_base(server.url("/api/0.6/")).unwrap().with_access_token("token");
+
+        let error = router.upload(&test_change(), &changeset_tags("Add missing bus stops")).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<OsmApiError>(), Some(&OsmApiError::UploadNotConfirmed));
+
+        let router = OsmRouter::with_api_base(server.url("/api/0.6/")).unwrap().with_uploads_confirmed(true);
+        let error = router.upload(&test_change(), &changeset_tags("Add missing bus stops")).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<OsmApiError>(), Some(&OsmApiError::NotAuthenticated));
+
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upload_data_from_other_server() {
+        let server = StandInServer::start(|request| match (request.method.as_str(), request.path.as_str()) {
+            ("PUT", "/api/0.6/changeset/create") => StandInResponse::ok("1234"),
+            ("POST", "/api/0.6/changeset/1234/upload") =>
+                StandInResponse::ok(r#"<diffResult version="0.6"><node old_id="-1" new_id="4000010" new_version="1"/></diffResult>"#),
+            _ => StandInResponse::ok(""),
+        }).await;
+        let router = OsmRouter::with_api_base(server.url("/api/0.6/")).unwrap()
+            .with_access_token("token")
+            .with_uploads_confirmed(true)
+            .with_data_from(OsmApiServer::Production.api_base().unwrap());
+
+        let mut change = test_change();
+        change.modify.nodes.insert(4000001, OsmNode { id: 4000001, lat: -33.8836, lon: 151.206, tags: Default::default(), meta: OsmMeta::default() });
+        let error = router.upload(&change, &changeset_tags("Retag stops")).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<OsmApiError>(), Some(OsmApiError::DataFromOtherServer { .. })));
+        assert!(server.requests().is_empty());
+
+        // A new relation of existing stops would point at whatever has their IDs here
+        let mut change = test_change();
+        let members = vec![OsmMember { kind: OsmElementType::Node, reference: 4000001, role: "platform".to_string() }];
+        change.create.relations.insert(-2, OsmRelation { id: -2, members, tags: Default::default(), meta: OsmMeta::default() });
+        let error = router.upload(&change, &changeset_tags("Add the M30")).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<OsmApiError>(), Some(OsmApiError::DataFromOtherServer { .. })));
+        assert!(server.requests().is_empty());
+
+        // New elements that only refer to each other don't clash with anything
+        router.upload(&test_change(), &changeset_tags("Add missing bus stops")).await.unwrap();
+    }
+
+    #[test]
+    fn test_conflict_messages() {
+        assert_eq!(OsmApiError::from_conflict("The changeset 1234 was closed at 2024-04-01 00:00:00 UTC"),
+            OsmApiError::ChangesetClosed { id: 1234, closed_at: "2024-04-01 00:00:00 UTC".to_string() });
+        assert_eq!(OsmApiError::from_conflict("Changeset 1234 is not open"), OsmApiError::Conflict("Changeset 1234 is not open".to_string()));
+    }
+}
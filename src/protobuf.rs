@@ -0,0 +1,315 @@
+//! Just enough of the protobuf wire format to decode the handful of messages we care about
+//! (GTFS-Realtime feeds and OSM PBF blocks), without pulling in a code generator.
+//!
+//! See <https://protobuf.dev/programming-guides/encoding/>
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ProtobufError {
+    #[error("Unexpected end of message")]
+    UnexpectedEof,
+    #[error("Varint is longer than 64 bits")]
+    VarintOverflow,
+    #[error("Unsupported wire type {0}")]
+    UnsupportedWireType(u8),
+    #[error("Field {field} has the wrong wire type, expected {expected}")]
+    WireTypeMismatch { field: u32, expected: &'static str },
+    #[error("Field {0} is not valid UTF-8")]
+    InvalidUtf8(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtobufValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// A single field read from a message, which can be interpreted as whatever type the schema says
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProtobufField<'a> {
+    pub number: u32,
+    pub value: ProtobufValue<'a>,
+}
+
+impl<'a> ProtobufField<'a> {
+    fn mismatch<T>(&self, expected: &'static str) -> Result<T, ProtobufError> {
+        Err(ProtobufError::WireTypeMismatch { field: self.number, expected })
+    }
+
+    pub fn as_u64(&self) -> Result<u64, ProtobufError> {
+        match self.value {
+            ProtobufValue::Varint(v) => Ok(v),
+            _ => self.mismatch("varint"),
+        }
+    }
+
+    pub fn as_u32(&self) -> Result<u32, ProtobufError> {
+        self.as_u64().map(|v| v as u32)
+    }
+
+    /// For `int32` and `int64`, which are sign extended to 64 bits on the wire
+    pub fn as_i64(&self) -> Result<i64, ProtobufError> {
+        self.as_u64().map(|v| v as i64)
+    }
+
+    pub fn as_i32(&self) -> Result<i32, ProtobufError> {
+        self.as_u64().map(|v| v as i32)
+    }
+
+    /// For `sint32` and `sint64`, which are zigzag encoded
+    pub fn as_sint64(&self) -> Result<i64, ProtobufError> {
+        self.as_u64().map(decode_zigzag)
+    }
+
+    pub fn as_bool(&self) -> Result<bool, ProtobufError> {
+        self.as_u64().map(|v| v != 0)
+    }
+
+    pub fn as_f32(&self) -> Result<f32, ProtobufError> {
+        match self.value {
+            ProtobufValue::Fixed32(v) => Ok(f32::from_bits(v)),
+            _ => self.mismatch("fixed32"),
+        }
+    }
+
+    pub fn as_f64(&self) -> Result<f64, ProtobufError> {
+        match self.value {
+            ProtobufValue::Fixed64(v) => Ok(f64::from_bits(v)),
+            _ => self.mismatch("fixed64"),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<&'a [u8], ProtobufError> {
+        match self.value {
+            ProtobufValue::Bytes(bytes) => Ok(bytes),
+            _ => self.mismatch("length delimited"),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&'a str, ProtobufError> {
+        std::str::from_utf8(self.as_bytes()?).map_err(|_| ProtobufError::InvalidUtf8(self.number))
+    }
+
+    pub fn as_string(&self) -> Result<String, ProtobufError> {
+        self.as_str().map(str::to_string)
+    }
+
+    pub fn as_message<M: ProtobufMessage>(&self) -> Result<M, ProtobufError> {
+        M::decode(self.as_bytes()?)
+    }
+
+    /// Packed repeated varints, as used by `repeated int64 ... [packed = true]`.
+    /// Also accepts a single unpacked value, since parsers are required to handle both.
+    pub fn as_packed_varints(&self) -> Result<Vec<u64>, ProtobufError> {
+        match self.value {
+            ProtobufValue::Varint(v) => Ok(vec![v]),
+            ProtobufValue::Bytes(mut bytes) => {
+                let mut values = Vec::new();
+                while !bytes.is_empty() {
+                    values.push(read_varint(&mut bytes)?);
+                }
+
+                Ok(values)
+            }
+            _ => self.mismatch("packed varints"),
+        }
+    }
+
+    pub fn as_packed_sint64(&self) -> Result<Vec<i64>, ProtobufError> {
+        self.as_packed_varints().map(|values| values.into_iter().map(decode_zigzag).collect())
+    }
+}
+
+pub fn decode_zigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64, ProtobufError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or(ProtobufError::UnexpectedEof)?;
+        *buf = rest;
+
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(ProtobufError::VarintOverflow)
+}
+
+fn read_bytes<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], ProtobufError> {
+    if buf.len() < len {
+        return Err(ProtobufError::UnexpectedEof);
+    }
+
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes)
+}
+
+/// Iterates over the fields of an encoded message in the order they appear
+pub struct ProtobufReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> ProtobufReader<'a> {
+    pub fn new(buf: &'a [u8]) -> ProtobufReader<'a> {
+        ProtobufReader { buf }
+    }
+
+    fn read_field(&mut self) -> Result<ProtobufField<'a>, ProtobufError> {
+        let key = read_varint(&mut self.buf)?;
+        let number = (key >> 3) as u32;
+
+        let value = match (key & 0x7) as u8 {
+            0 => ProtobufValue::Varint(read_varint(&mut self.buf)?),
+            1 => ProtobufValue::Fixed64(u64::from_le_bytes(read_bytes(&mut self.buf, 8)?.try_into().unwrap())),
+            2 => {
+                let len = read_varint(&mut self.buf)? as usize;
+                ProtobufValue::Bytes(read_bytes(&mut self.buf, len)?)
+            }
+            5 => ProtobufValue::Fixed32(u32::from_le_bytes(read_bytes(&mut self.buf, 4)?.try_into().unwrap())),
+            wire_type => return Err(ProtobufError::UnsupportedWireType(wire_type)),
+        };
+
+        Ok(ProtobufField { number, value })
+    }
+}
+
+impl<'a> Iterator for ProtobufReader<'a> {
+    type Item = Result<ProtobufField<'a>, ProtobufError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let field = self.read_field();
+        if field.is_err() {
+            // Don't keep trying to read garbage
+            self.buf = &[];
+        }
+
+        Some(field)
+    }
+}
+
+/// A message that can be built up one field at a time.
+///
+/// Unknown fields should be ignored, as protobuf intends, so that newer feeds keep decoding.
+pub trait ProtobufMessage: Default {
+    fn merge_field(&mut self, field: ProtobufField) -> Result<(), ProtobufError>;
+
+    fn decode(buf: &[u8]) -> Result<Self, ProtobufError> {
+        let mut message = Self::default();
+        for field in ProtobufReader::new(buf) {
+            message.merge_field(field?)?;
+        }
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::protobuf::{decode_zigzag, ProtobufError, ProtobufReader, ProtobufValue};
+
+    /// Builds encoded messages for tests, since we only ever need to decode them for real
+    #[derive(Default)]
+    pub(crate) struct ProtobufWriter(pub Vec<u8>);
+
+    impl ProtobufWriter {
+        fn varint(&mut self, mut v: u64) {
+            while v >= 0x80 {
+                self.0.push((v as u8) | 0x80);
+                v >>= 7;
+            }
+            self.0.push(v as u8);
+        }
+
+        pub(crate) fn uint(mut self, field: u32, v: u64) -> Self {
+            self.varint(u64::from(field) << 3);
+            self.varint(v);
+            self
+        }
+
+        pub(crate) fn sint(self, field: u32, v: i64) -> Self {
+            self.uint(field, ((v << 1) ^ (v >> 63)) as u64)
+        }
+
+        pub(crate) fn float(mut self, field: u32, v: f32) -> Self {
+            self.varint(u64::from(field) << 3 | 5);
+            self.0.extend_from_slice(&v.to_bits().to_le_bytes());
+            self
+        }
+
+        pub(crate) fn double(mut self, field: u32, v: f64) -> Self {
+            self.varint(u64::from(field) << 3 | 1);
+            self.0.extend_from_slice(&v.to_bits().to_le_bytes());
+            self
+        }
+
+        pub(crate) fn bytes(mut self, field: u32, v: &[u8]) -> Self {
+            self.varint(u64::from(field) << 3 | 2);
+            self.varint(v.len() as u64);
+            self.0.extend_from_slice(v);
+            self
+        }
+
+        pub(crate) fn string(self, field: u32, v: &str) -> Self {
+            self.bytes(field, v.as_bytes())
+        }
+
+        pub(crate) fn message(self, field: u32, v: ProtobufWriter) -> Self {
+            self.bytes(field, &v.0)
+        }
+
+        pub(crate) fn packed_sint(self, field: u32, values: &[i64]) -> Self {
+            let mut packed = ProtobufWriter::default();
+            for v in values {
+                packed.varint(((v << 1) ^ (v >> 63)) as u64);
+            }
+
+            self.bytes(field, &packed.0)
+        }
+    }
+
+    #[test]
+    fn test_read_fields() {
+        let message = ProtobufWriter::default()
+            .uint(1, 300)
+            .string(2, "Central")
+            .float(3, -33.88)
+            .packed_sint(4, &[-1, 2, -300]);
+
+        let fields: Vec<_> = ProtobufReader::new(&message.0).collect::<Result<_, _>>().unwrap();
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[0].value, ProtobufValue::Varint(300));
+        assert_eq!(fields[1].as_str(), Ok("Central"));
+        assert_eq!(fields[2].as_f32(), Ok(-33.88));
+        assert_eq!(fields[3].as_packed_sint64(), Ok(vec![-1, 2, -300]));
+        assert!(fields[1].as_u64().is_err());
+    }
+
+    #[test]
+    fn test_truncated_message() {
+        let message = ProtobufWriter::default().string(1, "Central");
+        let truncated = &message.0[..message.0.len() - 1];
+
+        let fields: Vec<_> = ProtobufReader::new(truncated).collect();
+        assert_eq!(fields, vec![Err(ProtobufError::UnexpectedEof)]);
+    }
+
+    #[test]
+    fn test_zigzag() {
+        assert_eq!(decode_zigzag(0), 0);
+        assert_eq!(decode_zigzag(1), -1);
+        assert_eq!(decode_zigzag(2), 1);
+        assert_eq!(decode_zigzag(u64::MAX), i64::MIN);
+    }
+}
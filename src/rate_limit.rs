@@ -0,0 +1,247 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::bail;
+use chrono::{DateTime, Local, NaiveDate, TimeDelta};
+use governor::{DefaultDirectRateLimiter, Jitter, Quota, RateLimiter};
+use log::{debug, warn};
+use nonzero_ext::nonzero;
+use serde::{Deserialize, Serialize};
+
+/// Our share of the TfNSW API quota.
+///
+/// `per_hour` and `burst` are enforced in process. If `state_file` is set, `per_hour` and `per_day`
+/// are also accounted for there, so that every process using the same file shares one budget.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransportNswQuota {
+    #[serde(default = "default_per_hour")]
+    pub per_hour: NonZeroU32,
+    #[serde(default = "default_burst")]
+    pub burst: NonZeroU32,
+    pub per_day: Option<u32>,
+    pub state_file: Option<PathBuf>,
+}
+
+fn default_per_hour() -> NonZeroU32 {
+    nonzero!(2500u32)
+}
+
+fn default_burst() -> NonZeroU32 {
+    nonzero!(5u32)
+}
+
+impl Default for TransportNswQuota {
+    fn default() -> Self {
+        TransportNswQuota {
+            per_hour: default_per_hour(),
+            burst: default_burst(),
+            per_day: None,
+            state_file: None,
+        }
+    }
+}
+
+impl TransportNswQuota {
+    /// The default quota, without a state file; requests are only limited within this process
+    pub fn in_memory() -> TransportNswQuota {
+        TransportNswQuota::default()
+    }
+}
+
+/// Requests made against the quota, as recorded in the state file
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct QuotaLedger {
+    date: Option<NaiveDate>,
+    requests_today: u32,
+    /// Timestamps of every request in the last hour
+    recent: VecDeque<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Reservation {
+    Granted,
+    WaitFor(Duration),
+}
+
+impl QuotaLedger {
+    /// Records a request at `now` if the quota allows it
+    fn reserve(&mut self, quota: &TransportNswQuota, now: DateTime<Local>) -> anyhow::Result<Reservation> {
+        let today = now.date_naive();
+        if self.date != Some(today) {
+            self.date = Some(today);
+            self.requests_today = 0;
+        }
+
+        let hour_ago = now - TimeDelta::hours(1);
+        while self.recent.front().is_some_and(|request| *request <= hour_ago) {
+            self.recent.pop_front();
+        }
+
+        if let Some(per_day) = quota.per_day {
+            if self.requests_today >= per_day {
+                bail!("The daily quota of {per_day} requests has been used up; it resets at midnight");
+            }
+        }
+
+        if self.recent.len() >= quota.per_hour.get() as usize {
+            let oldest = self.recent[self.recent.len() - quota.per_hour.get() as usize];
+            let wait = (oldest + TimeDelta::hours(1) - now).to_std().unwrap_or(Duration::ZERO);
+            return Ok(Reservation::WaitFor(wait));
+        }
+
+        self.requests_today += 1;
+        self.recent.push_back(now);
+        Ok(Reservation::Granted)
+    }
+}
+
+/// Held while the state file is being updated, so concurrent processes take turns. The OS lets
+/// go of the lock when its owner exits, so a crashed process can't leave it held.
+struct StateFileLock {
+    _file: File,
+}
+
+impl StateFileLock {
+    /// Blocks until the lock is ours
+    fn acquire(state_file: &Path) -> anyhow::Result<StateFileLock> {
+        let mut path = state_file.as_os_str().to_owned();
+        path.push(".lock");
+
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(PathBuf::from(path))?;
+        file.lock()?;
+        Ok(StateFileLock { _file: file })
+    }
+}
+
+/// A rate limiter that can be shared between clients, and between processes through the quota's
+/// state file
+pub struct SharedRateLimiter {
+    limiter: DefaultDirectRateLimiter,
+    quota: TransportNswQuota,
+}
+
+impl SharedRateLimiter {
+    pub fn new(quota: TransportNswQuota) -> SharedRateLimiter {
+        let limiter = RateLimiter::direct(Quota::per_hour(quota.per_hour).allow_burst(quota.burst));
+        SharedRateLimiter { limiter, quota }
+    }
+
+    /// Waits until a request is allowed, and records it against the shared quota
+    pub async fn until_ready(&self) -> anyhow::Result<()> {
+        self.limiter.until_ready_with_jitter(Jitter::up_to(Duration::from_secs(1u64))).await;
+
+        let Some(state_file) = &self.quota.state_file else {
+            return Ok(());
+        };
+
+        loop {
+            let (state_file, quota) = (state_file.clone(), self.quota.clone());
+            let reservation = tokio::task::spawn_blocking(move || reserve_in_file(&state_file, &quota)).await??;
+
+            match reservation {
+                Reservation::Granted => return Ok(()),
+                Reservation::WaitFor(wait) => {
+                    debug!("Hourly quota used up by other processes, waiting {wait:?}");
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+    }
+}
+
+/// Takes a request from the quota recorded in `state_file`. This blocks on the lock and the file,
+/// so it belongs on a blocking thread.
+fn reserve_in_file(state_file: &Path, quota: &TransportNswQuota) -> anyhow::Result<Reservation> {
+    let _lock = StateFileLock::acquire(state_file)?;
+    let mut ledger = read_ledger(state_file)?;
+    let reservation = ledger.reserve(quota, Local::now())?;
+
+    // Written alongside and renamed over, so that a process killed part way through can't leave a
+    // truncated ledger behind
+    let mut temporary = state_file.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, serde_json::to_vec(&ledger)?)?;
+    fs::rename(&temporary, state_file)?;
+
+    Ok(reservation)
+}
+
+fn read_ledger(state_file: &Path) -> anyhow::Result<QuotaLedger> {
+    match fs::read(state_file) {
+        Ok(contents) => match serde_json::from_slice(&contents) {
+            Ok(ledger) => Ok(ledger),
+            Err(e) => {
+                warn!("Quota state file {} is unreadable ({e}), starting it afresh", state_file.display());
+                Ok(QuotaLedger::default())
+            }
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(QuotaLedger::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::{Local, TimeDelta, TimeZone};
+    use nonzero_ext::nonzero;
+    use tempfile::tempdir;
+    use crate::rate_limit::{QuotaLedger, Reservation, SharedRateLimiter, TransportNswQuota};
+
+    #[test]
+    fn test_hourly_window() {
+        let quota = TransportNswQuota { per_hour: nonzero!(2u32), ..TransportNswQuota::in_memory() };
+        let start = Local.with_ymd_and_hms(2024, 4, 15, 9, 0, 0).unwrap();
+        let mut ledger = QuotaLedger::default();
+
+        assert_eq!(ledger.reserve(&quota, start).unwrap(), Reservation::Granted);
+        assert_eq!(ledger.reserve(&quota, start + TimeDelta::minutes(10)).unwrap(), Reservation::Granted);
+        assert_eq!(ledger.reserve(&quota, start + TimeDelta::minutes(30)).unwrap(), Reservation::WaitFor(Duration::from_secs(30 * 60)));
+
+        // Once the first request is an hour old, there's room again
+        assert_eq!(ledger.reserve(&quota, start + TimeDelta::minutes(61)).unwrap(), Reservation::Granted);
+        assert_eq!(ledger.requests_today, 3);
+    }
+
+    #[tokio::test]
+    async fn test_daily_quota_is_shared() {
+        let directory = tempdir().unwrap();
+        let quota = TransportNswQuota {
+            per_day: Some(3),
+            state_file: Some(directory.path().join("quota.json")),
+            ..Default::default()
+        };
+
+        // Two limiters on the same file stand in for two processes
+        let first = SharedRateLimiter::new(quota.clone());
+        let second = SharedRateLimiter::new(quota);
+
+        first.until_ready().await.unwrap();
+        second.until_ready().await.unwrap();
+        first.until_ready().await.unwrap();
+        assert!(second.until_ready().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_state_file() {
+        let directory = tempdir().unwrap();
+        let state_file = directory.path().join("quota.json");
+        std::fs::write(&state_file, b"{\"date\":\"2024-04-").unwrap();
+
+        let quota = TransportNswQuota { per_day: Some(1), state_file: Some(state_file.clone()), ..Default::default() };
+        let limiter = SharedRateLimiter::new(quota);
+        limiter.until_ready().await.unwrap();
+        assert!(limiter.until_ready().await.is_err());
+    }
+
+    #[test]
+    fn test_shared_quota_is_opt_in() {
+        let quota = TransportNswQuota::default();
+        assert!(quota.state_file.is_none());
+        assert!(quota.per_day.is_none());
+    }
+}
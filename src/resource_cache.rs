@@ -0,0 +1,131 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use crate::osm_api_client::ResourceWithValidity;
+
+/// Downloads kept on disk alongside the validators they were served with, so the next request
+/// can be made conditional and skipped entirely if the server says nothing has changed.
+///
+/// Each resource is stored as `{key}`, with its validators in `{key}.validity.json`.
+#[derive(Debug, Clone)]
+pub struct ResourceCache {
+    directory: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedValidity {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl ResourceCache {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> std::io::Result<ResourceCache> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+
+        Ok(ResourceCache { directory })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn resource_path(&self, key: &str) -> PathBuf {
+        self.directory.join(key)
+    }
+
+    fn validity_path(&self, key: &str) -> PathBuf {
+        self.directory.join(format!("{key}.validity.json"))
+    }
+
+    /// A temporary file in the cache directory, so that [ResourceCache::store] is just a rename
+    pub fn temp_file(&self) -> std::io::Result<NamedTempFile> {
+        NamedTempFile::new_in(&self.directory)
+    }
+
+    /// The cached path for `key`, if both it and its validators are present.
+    ///
+    /// Resources without any validators are never cached, since there'd be no way to revalidate
+    /// them.
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<ResourceWithValidity<PathBuf>>> {
+        let path = self.resource_path(key);
+        let validity = match fs::read(self.validity_path(key)) {
+            Ok(validity) => validity,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let CachedValidity { etag, last_modified } = serde_json::from_slice(&validity)?;
+        match ResourceWithValidity::new(path, etag, last_modified) {
+            ResourceWithValidity::Missing(_) => Ok(None),
+            resource => Ok(Some(resource)),
+        }
+    }
+
+    /// Moves `file` into the cache under `key`, replacing anything that was there before
+    pub fn store(&self, key: &str, file: NamedTempFile, etag: Option<String>, last_modified: Option<String>) -> anyhow::Result<ResourceWithValidity<PathBuf>> {
+        let path = self.resource_path(key);
+        let validity_path = self.validity_path(key);
+
+        // Drop the old validators first, so a failure part way through leaves a miss rather than
+        // a new file paired with stale validators
+        match fs::remove_file(&validity_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        file.persist(&path)?;
+
+        if etag.is_some() || last_modified.is_some() {
+            let validity = CachedValidity { etag: etag.clone(), last_modified: last_modified.clone() };
+            fs::write(&validity_path, serde_json::to_vec(&validity)?)?;
+        }
+
+        Ok(ResourceWithValidity::new(path, etag, last_modified))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use reqwest::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH};
+    use tempfile::tempdir;
+    use crate::resource_cache::ResourceCache;
+
+    #[test]
+    fn test_store_and_revalidate() {
+        let directory = tempdir().unwrap();
+        let cache = ResourceCache::new(directory.path()).unwrap();
+        assert!(cache.get("complete_gtfs.zip").unwrap().is_none());
+
+        let mut file = cache.temp_file().unwrap();
+        file.write_all(b"feed").unwrap();
+        cache.store("complete_gtfs.zip", file, Some("\"abc\"".to_string()), None).unwrap();
+
+        let cached = cache.get("complete_gtfs.zip").unwrap().unwrap();
+        assert_eq!(fs::read(cached.value()).unwrap(), b"feed");
+        assert_eq!(cached.etag(), Some("\"abc\""));
+
+        let headers = cached.conditional_headers().unwrap();
+        assert_eq!(headers.get(IF_NONE_MATCH).unwrap(), "\"abc\"");
+        assert!(headers.get(IF_MODIFIED_SINCE).is_none());
+    }
+
+    #[test]
+    fn test_unvalidated_resources_are_misses() {
+        let directory = tempdir().unwrap();
+        let cache = ResourceCache::new(directory.path()).unwrap();
+
+        let file = cache.temp_file().unwrap();
+        cache.store("complete_gtfs.zip", file, None, None).unwrap();
+
+        assert!(cache.get("complete_gtfs.zip").unwrap().is_none());
+    }
+}
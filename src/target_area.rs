@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail};
+use geo::{BoundingRect, Intersects};
+use geo_types::{coord, Geometry, LineString, MultiPolygon, Point, Polygon, Rect};
+use geojson::GeoJson;
+use serde::{Deserialize, Deserializer};
+
+/// Properties that suburb names are commonly stored under, in the ABS Suburbs and Localities
+/// (SAL) boundaries and the NSW Spatial Services suburb layer
+const SUBURB_NAME_PROPERTIES: [&str; 4] = ["SAL_NAME21", "SAL_NAME_2021", "suburbname", "name"];
+
+#[derive(Debug, Deserialize)]
+pub enum TransportNswTargetSuburb {
+    Static {
+        name: String,
+        min_latitude: f64,
+        max_latitude: f64,
+        min_longitude: f64,
+        max_longitude: f64,
+    },
+    /// An outline given inline, as `[longitude, latitude]` pairs
+    Polygon {
+        name: String,
+        coordinates: Vec<[f64; 2]>,
+    },
+    /// Every polygon in a GeoJSON file, whether it's a bare geometry, a feature or a collection
+    GeoJson {
+        name: String,
+        path: PathBuf,
+    },
+    /// A suburb looked up by name from a GeoJSON boundary file, such as the ABS SAL boundaries
+    /// converted to GeoJSON
+    Suburb {
+        name: String,
+        boundaries: PathBuf,
+        name_property: Option<String>,
+    },
+}
+
+/// Reads either a single target or a list of them, so that configs written with just one target
+/// keep working
+pub fn deserialize_targets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<TransportNswTargetSuburb>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(TransportNswTargetSuburb),
+        Many(Vec<TransportNswTargetSuburb>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(target) => vec![target],
+        OneOrMany::Many(targets) => targets,
+    })
+}
+
+/// A resolved [TransportNswTargetSuburb], ready for point-in-polygon tests
+#[derive(Debug, Clone)]
+pub struct TargetArea {
+    pub name: String,
+    pub area: MultiPolygon<f64>,
+}
+
+impl TargetArea {
+    /// Whether `point` (as longitude, latitude) is inside the area, or on its boundary
+    pub fn contains(&self, point: &Point<f64>) -> bool {
+        self.area.intersects(point)
+    }
+
+    pub fn bounding_rect(&self) -> Option<Rect<f64>> {
+        self.area.bounding_rect()
+    }
+
+    /// The name with anything that could escape or upset an output path replaced by `_`, for
+    /// filling in `{name}` in the output templates
+    pub fn file_name(&self) -> String {
+        self.name.chars()
+            .map(|c| if c.is_alphanumeric() || matches!(c, '-' | '_' | ' ') { c } else { '_' })
+            .collect()
+    }
+}
+
+/// Fails if two targets would write to the same files, since the second would silently overwrite
+/// the first. Names are compared the way [TargetArea::file_name] writes them, ignoring case.
+pub fn check_unique_names(target_areas: &[TargetArea]) -> anyhow::Result<()> {
+    let mut seen = HashMap::with_capacity(target_areas.len());
+    for target_area in target_areas {
+        if let Some(other) = seen.insert(target_area.file_name().to_lowercase(), &target_area.name) {
+            bail!("Targets {other} and {} would write to the same files; give them different names", target_area.name);
+        }
+    }
+
+    Ok(())
+}
+
+impl TransportNswTargetSuburb {
+    pub fn name(&self) -> &str {
+        match self {
+            TransportNswTargetSuburb::Static { name, .. } => name,
+            TransportNswTargetSuburb::Polygon { name, .. } => name,
+            TransportNswTargetSuburb::GeoJson { name, .. } => name,
+            TransportNswTargetSuburb::Suburb { name, .. } => name,
+        }
+    }
+
+    pub fn resolve(&self) -> anyhow::Result<TargetArea> {
+        let area = match self {
+            TransportNswTargetSuburb::Static { min_latitude, max_latitude, min_longitude, max_longitude, .. } =>
+                MultiPolygon::new(vec![Rect::new(coord! { x: *min_longitude, y: *min_latitude }, coord! { x: *max_longitude, y: *max_latitude }).to_polygon()]),
+            TransportNswTargetSuburb::Polygon { coordinates, .. } => {
+                if coordinates.len() < 3 {
+                    bail!("A target polygon needs at least 3 points, but only {} were given", coordinates.len());
+                }
+
+                let exterior: LineString<f64> = coordinates.iter().map(|[x, y]| coord! { x: *x, y: *y }).collect();
+                MultiPolygon::new(vec![Polygon::new(exterior, vec![])])
+            }
+            TransportNswTargetSuburb::GeoJson { path, .. } => {
+                let geometry = Geometry::<f64>::try_from(read_geojson(path)?)?;
+                MultiPolygon::new(polygons_in(geometry))
+            }
+            TransportNswTargetSuburb::Suburb { name, boundaries, name_property } =>
+                find_suburb(&read_geojson(boundaries)?, name, name_property.as_deref())?,
+        };
+
+        if area.0.is_empty() {
+            bail!("Target area {} doesn't contain any polygons", self.name());
+        }
+
+        Ok(TargetArea { name: self.name().to_string(), area })
+    }
+}
+
+fn read_geojson(path: &Path) -> anyhow::Result<GeoJson> {
+    fs::read_to_string(path)?
+        .parse::<GeoJson>()
+        .map_err(|e| anyhow!(e).context(format!("Failed to parse {}", path.display())))
+}
+
+fn polygons_in(geometry: Geometry<f64>) -> Vec<Polygon<f64>> {
+    match geometry {
+        Geometry::Polygon(polygon) => vec![polygon],
+        Geometry::MultiPolygon(multi_polygon) => multi_polygon.0,
+        Geometry::Rect(rect) => vec![rect.to_polygon()],
+        Geometry::Triangle(triangle) => vec![triangle.to_polygon()],
+        Geometry::GeometryCollection(collection) => collection.0.into_iter().flat_map(polygons_in).collect(),
+        _ => vec![],
+    }
+}
+
+/// Compares suburb names loosely, since the ABS disambiguates suburbs that share a name with
+/// another state by suffixing them (eg. "Newtown (NSW)")
+fn is_suburb_name(candidate: &str, name: &str) -> bool {
+    let candidate = candidate.trim();
+    let candidate = candidate.strip_suffix("(NSW)").map(str::trim_end).unwrap_or(candidate);
+
+    candidate.eq_ignore_ascii_case(name.trim())
+}
+
+fn find_suburb(boundaries: &GeoJson, name: &str, name_property: Option<&str>) -> anyhow::Result<MultiPolygon<f64>> {
+    let GeoJson::FeatureCollection(collection) = boundaries else {
+        bail!("Suburb boundaries need to be a FeatureCollection");
+    };
+
+    let properties = match name_property {
+        Some(property) => vec![property],
+        None => SUBURB_NAME_PROPERTIES.to_vec(),
+    };
+
+    let mut polygons = Vec::new();
+    for feature in &collection.features {
+        let is_match = properties.iter()
+            .filter_map(|property| feature.property(property).and_then(|value| value.as_str()))
+            .any(|candidate| is_suburb_name(candidate, name));
+
+        if let (true, Some(geometry)) = (is_match, &feature.geometry) {
+            polygons.extend(polygons_in(Geometry::<f64>::try_from(&geometry.value)?));
+        }
+    }
+
+    if polygons.is_empty() {
+        bail!("Couldn't find a suburb named {name} in the boundary file");
+    }
+
+    Ok(MultiPolygon::new(polygons))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use geo_types::Point;
+    use tempfile::NamedTempFile;
+    use crate::target_area::{check_unique_names, deserialize_targets, TransportNswTargetSuburb};
+
+    const BOUNDARIES: &str = r#"{
+        "type": "FeatureCollection",
+        "features": [
+            {
+                "type": "Feature",
+                "properties": { "SAL_NAME21": "Newtown (NSW)" },
+                "geometry": { "type": "Polygon", "coordinates": [[[151.17, -33.90], [151.19, -33.90], [151.19, -33.89], [151.17, -33.89], [151.17, -33.90]]] }
+            },
+            {
+                "type": "Feature",
+                "properties": { "SAL_NAME21": "Redfern" },
+                "geometry": { "type": "Polygon", "coordinates": [[[151.19, -33.90], [151.21, -33.90], [151.21, -33.89], [151.19, -33.89], [151.19, -33.90]]] }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_named_suburb() {
+        let mut boundaries = NamedTempFile::new().unwrap();
+        boundaries.write_all(BOUNDARIES.as_bytes()).unwrap();
+
+        let target = TransportNswTargetSuburb::Suburb {
+            name: "newtown".to_string(),
+            boundaries: boundaries.path().to_path_buf(),
+            name_property: None,
+        };
+
+        let area = target.resolve().unwrap();
+        assert!(area.contains(&Point::new(151.18, -33.895)));
+        assert!(!area.contains(&Point::new(151.20, -33.895)));
+    }
+
+    #[test]
+    fn test_inline_polygon() {
+        // A triangle, so the corner of its bounding box shouldn't count
+        let target = TransportNswTargetSuburb::Polygon {
+            name: "Triangle".to_string(),
+            coordinates: vec![[151.20, -33.88], [151.21, -33.88], [151.21, -33.89]],
+        };
+
+        let area = target.resolve().unwrap();
+        assert!(area.contains(&Point::new(151.2065, -33.8837)));
+        assert!(!area.contains(&Point::new(151.201, -33.889)));
+    }
+
+    #[test]
+    fn test_one_or_many_targets() {
+        let target = r#"{ "Static": { "name": "redfern", "min_latitude": -33.90, "max_latitude": -33.89, "min_longitude": 151.19, "max_longitude": 151.21 } }"#;
+
+        let one = deserialize_targets(&mut serde_json::Deserializer::from_str(target)).unwrap();
+        assert_eq!(one.len(), 1);
+        assert_eq!(one[0].name(), "redfern");
+
+        let many = deserialize_targets(&mut serde_json::Deserializer::from_str(&format!("[{target}, {target}]"))).unwrap();
+        assert_eq!(many.len(), 2);
+    }
+
+    #[test]
+    fn test_target_names_in_paths() {
+        let target = |name: &str| TransportNswTargetSuburb::Static {
+            name: name.to_string(),
+            min_latitude: -33.90,
+            max_latitude: -33.89,
+            min_longitude: 151.19,
+            max_longitude: 151.21,
+        }.resolve().unwrap();
+
+        assert_eq!(target("Newtown (NSW)").file_name(), "Newtown _NSW_");
+        assert_eq!(target("../../etc/passwd").file_name(), "______etc_passwd");
+
+        assert!(check_unique_names(&[target("redfern"), target("newtown")]).is_ok());
+        assert!(check_unique_names(&[target("redfern"), target("Redfern")]).is_err());
+        assert!(check_unique_names(&[target("inner/west"), target("inner_west")]).is_err());
+    }
+}
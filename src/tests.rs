@@ -1,3 +1,196 @@
 #[cfg(test)]
-mod tests {
-}
\ No newline at end of file
+pub(crate) mod stand_in_server {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+    use reqwest::{StatusCode, Url};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+
+    /// A request as the stand-in server saw it
+    #[derive(Debug, Clone)]
+    pub(crate) struct StandInRequest {
+        pub method: String,
+        pub path: String,
+        pub headers: HeaderMap,
+        pub body: Vec<u8>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub(crate) struct StandInResponse {
+        pub status: StatusCode,
+        pub headers: Vec<(HeaderName, String)>,
+        pub body: Vec<u8>,
+        /// Drop the connection after this many bytes of the body, while still claiming the full
+        /// Content-Length, to simulate a flaky connection
+        pub cut_off_after: Option<usize>,
+    }
+
+    impl StandInResponse {
+        pub(crate) fn ok<B: Into<Vec<u8>>>(body: B) -> StandInResponse {
+            StandInResponse { status: StatusCode::OK, headers: Vec::new(), body: body.into(), cut_off_after: None }
+        }
+
+        pub(crate) fn status(status: StatusCode) -> StandInResponse {
+            StandInResponse { status, ..StandInResponse::ok(Vec::new()) }
+        }
+
+        pub(crate) fn with_status(self, status: StatusCode) -> StandInResponse {
+            StandInResponse { status, ..self }
+        }
+
+        pub(crate) fn with_header<V: ToString>(mut self, name: HeaderName, value: V) -> StandInResponse {
+            self.headers.push((name, value.to_string()));
+            self
+        }
+
+        pub(crate) fn cut_off_after(self, bytes: usize) -> StandInResponse {
+            StandInResponse { cut_off_after: Some(bytes), ..self }
+        }
+    }
+
+    /// A tiny HTTP/1.1 server on a random local port, standing in for the real APIs.
+    ///
+    /// Every connection is answered by `handler` and then closed, and every request is recorded so
+    /// tests can check what the client actually sent.
+    pub(crate) struct StandInServer {
+        address: SocketAddr,
+        requests: Arc<Mutex<Vec<StandInRequest>>>,
+        task: JoinHandle<()>,
+    }
+
+    impl StandInServer {
+        pub(crate) async fn start<F>(handler: F) -> StandInServer
+        where
+            F: Fn(&StandInRequest) -> StandInResponse + Send + Sync + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let handler = Arc::new(handler);
+
+            let task = {
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    while let Ok((stream, _)) = listener.accept().await {
+                        let requests = requests.clone();
+                        let handler = handler.clone();
+
+                        tokio::spawn(async move {
+                            let mut stream = BufferedStream::new(stream);
+                            if let Some(request) = read_request(&mut stream).await {
+                                let response = handler(&request);
+                                requests.lock().unwrap().push(request.clone());
+                                write_response(&mut stream.stream, &request, response).await;
+                            }
+                        });
+                    }
+                })
+            };
+
+            StandInServer { address, requests, task }
+        }
+
+        pub(crate) fn url(&self, path: &str) -> Url {
+            Url::parse(&format!("http://{}{path}", self.address)).unwrap()
+        }
+
+        /// Where [crate::transport_nswapi::TransportNswApiClient] should point, to mirror
+        /// `https://api.transport.nsw.gov.au/v1/`
+        pub(crate) fn api_base(&self) -> Url {
+            self.url("/v1/")
+        }
+
+        pub(crate) fn requests(&self) -> Vec<StandInRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for StandInServer {
+        fn drop(&mut self) {
+            self.task.abort();
+        }
+    }
+
+    struct BufferedStream {
+        stream: TcpStream,
+        buffer: Vec<u8>,
+    }
+
+    impl BufferedStream {
+        fn new(stream: TcpStream) -> BufferedStream {
+            BufferedStream { stream, buffer: Vec::new() }
+        }
+
+        async fn fill(&mut self) -> bool {
+            let mut chunk = [0u8; 4096];
+            match self.stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => false,
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                    true
+                }
+            }
+        }
+    }
+
+    async fn read_request(stream: &mut BufferedStream) -> Option<StandInRequest> {
+        let header_end = loop {
+            if let Some(end) = stream.buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end;
+            }
+            if !stream.fill().await {
+                return None;
+            }
+        };
+
+        let head = String::from_utf8(stream.buffer[..header_end].to_vec()).ok()?;
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?.to_string();
+        let path = request_line.next()?.to_string();
+
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            let (name, value) = line.split_once(':')?;
+            headers.append(HeaderName::try_from(name.trim()).ok()?, HeaderValue::try_from(value.trim()).ok()?);
+        }
+
+        let content_length = headers.get("content-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        let body_start = header_end + 4;
+        while stream.buffer.len() < body_start + content_length {
+            if !stream.fill().await {
+                return None;
+            }
+        }
+
+        let body = stream.buffer[body_start..body_start + content_length].to_vec();
+
+        Some(StandInRequest { method, path, headers, body })
+    }
+
+    async fn write_response(stream: &mut TcpStream, request: &StandInRequest, response: StandInResponse) {
+        let reason = response.status.canonical_reason().unwrap_or("");
+        let mut head = format!("HTTP/1.1 {} {reason}\r\nContent-Length: {}\r\nConnection: close\r\n", response.status.as_u16(), response.body.len());
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+
+        let body = match (request.method.as_str(), response.cut_off_after) {
+            ("HEAD", _) => &[][..],
+            (_, Some(cut_off)) => &response.body[..cut_off.min(response.body.len())],
+            (_, None) => &response.body[..],
+        };
+
+        let _ = stream.write_all(head.as_bytes()).await;
+        let _ = stream.write_all(body).await;
+        let _ = stream.flush().await;
+        let _ = stream.shutdown().await;
+    }
+}
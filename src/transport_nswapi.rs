@@ -1,25 +1,108 @@
+use std::fs::File;
 use std::sync::Arc;
-use std::time::Duration;
+use anyhow::bail;
 use futures::TryStreamExt;
 
-use governor::{DefaultDirectRateLimiter, Jitter, Quota, RateLimiter};
-use nonzero_ext::nonzero;
+use log::debug;
 use reqwest::{Client, IntoUrl, Response, Url};
-use reqwest::header::{ETAG, HeaderMap, HeaderValue, LAST_MODIFIED};
-use tokio::io::BufReader;
-use tokio_util::io::{StreamReader, SyncIoBridge};
-use futures::StreamExt;
+use reqwest::header::{HeaderMap, HeaderValue};
+use tokio_util::io::SyncIoBridge;
 use tempfile::NamedTempFile;
 use zip::ZipArchive;
-use crate::errors::{IntoAnyhowError, IntoStdIOError};
-use crate::osm_api_client::{ResourceWithValidity, TryToString};
-use crate::osm_api_client::ResourceWithValidity::{ETag, ETagAndModification, LastModified, Missing};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use strum::AsRefStr;
+use crate::errors::IntoAnyhowError;
+use crate::gtfs::realtime::FeedMessage;
+use crate::protobuf::ProtobufMessage;
+use crate::osm_api_client::ResourceWithValidity;
+use crate::download::{download_resumable, DownloadOutcome, RetryPolicy, verify_zip};
+use crate::rate_limit::{SharedRateLimiter, TransportNswQuota};
+use crate::resource_cache::ResourceCache;
+use crate::trip_planner::{AdditionalInfoRequest, AdditionalInfoResponse, common_query, CoordRequest, CoordResponse, DepartureMonitorRequest, DepartureMonitorResponse, StopFinderRequest, StopFinderResponse, TripRequest, TripResponse};
 
 
+/// A mode (and, where TfNSW splits it further, an operator or line) that has its own GTFS feeds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportNswMode {
+    Buses,
+    SydneyTrains,
+    Metro,
+    NswTrains,
+    Ferries(TransportNswFerryOperator),
+    LightRail(TransportNswLightRailLine),
+    RegionBuses(TransportNswBusRegion),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsRefStr, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TransportNswFerryOperator {
+    SydneyFerries,
+    #[strum(serialize = "MFF")]
+    #[serde(rename = "MFF")]
+    ManlyFastFerry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsRefStr, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TransportNswLightRailLine {
+    CbdAndSoutheast,
+    InnerWest,
+    Newcastle,
+    Parramatta,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsRefStr, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TransportNswBusRegion {
+    CentralWestAndOrana,
+    CentralWestAndOrana2,
+    FarWest,
+    NewcastleHunter,
+    NewEnglandNorthWest,
+    NorthCoast,
+    NorthCoast2,
+    NorthCoast3,
+    RiverinaMurray,
+    RiverinaMurray2,
+    SouthEastTablelands,
+    SouthEastTablelands2,
+    SydneySurrounds,
+}
+
+impl TransportNswMode {
+    /// The path of this mode's feed underneath a feed type, eg. `ferries/sydneyferries`
+    pub fn path(&self) -> String {
+        match self {
+            TransportNswMode::Buses => "buses".to_string(),
+            TransportNswMode::SydneyTrains => "sydneytrains".to_string(),
+            TransportNswMode::Metro => "metro".to_string(),
+            TransportNswMode::NswTrains => "nswtrains".to_string(),
+            TransportNswMode::Ferries(operator) => format!("ferries/{}", operator.as_ref()),
+            TransportNswMode::LightRail(line) => format!("lightrail/{}", line.as_ref()),
+            TransportNswMode::RegionBuses(region) => format!("regionbuses/{}", region.as_ref()),
+        }
+    }
+
+    /// Sydney Trains and Metro feeds were only ever published under v2 of the API, while every
+    /// other mode is still on v1
+    fn api_version(&self) -> &'static str {
+        match self {
+            TransportNswMode::SydneyTrains | TransportNswMode::Metro => "v2",
+            _ => "v1",
+        }
+    }
+}
+
 pub struct TransportNswApiClient {
     api_base: Url,
     client: Arc<reqwest::Client>,
-    rate_limiter: Arc<DefaultDirectRateLimiter>,
+    rate_limiter: Arc<SharedRateLimiter>,
+    retry: RetryPolicy,
 }
 
 impl TransportNswApiClient {
@@ -36,17 +119,32 @@ impl TransportNswApiClient {
 
         // set default client operation
         let client = Arc::new(reqwest::Client::builder().default_headers(headers).build()?);
-        let rate_limiter = Arc::new(RateLimiter::direct(
-            Quota::per_hour(nonzero!(2500u32))
-                .allow_burst(nonzero!(5u32))
-        ));
+        let rate_limiter = Arc::new(SharedRateLimiter::new(TransportNswQuota::in_memory()));
 
-        Ok(TransportNswApiClient { api_base, client, rate_limiter })
+        Ok(TransportNswApiClient { api_base, client, rate_limiter, retry: RetryPolicy::default() })
+    }
+
+    /// Shares `rate_limiter` with this client, so that several clients (and, through its state
+    /// file, several processes) draw from the same quota
+    pub fn with_rate_limiter(self, rate_limiter: Arc<SharedRateLimiter>) -> TransportNswApiClient {
+        TransportNswApiClient { rate_limiter, ..self }
+    }
+
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> TransportNswApiClient {
+        TransportNswApiClient { retry, ..self }
     }
 
     pub fn timetables(&self) -> TransportNswTimetablesEndpoint {
         TransportNswTimetablesEndpoint(self)
     }
+
+    pub fn realtime(&self) -> TransportNswRealtimeEndpoint {
+        TransportNswRealtimeEndpoint(self)
+    }
+
+    pub fn trip_planner(&self) -> TransportNswTripPlannerEndpoint {
+        TransportNswTripPlannerEndpoint(self)
+    }
 }
 
 pub struct TransportNswTimetablesEndpoint<'c>(&'c TransportNswApiClient);
@@ -58,7 +156,7 @@ impl<'c> TransportNswTimetablesEndpoint<'c> {
     }
 
     #[inline]
-    fn rate_limiter(&self) -> &Arc<DefaultDirectRateLimiter> {
+    fn rate_limiter(&self) -> &Arc<SharedRateLimiter> {
         &self.0.rate_limiter
     }
 
@@ -66,8 +164,8 @@ impl<'c> TransportNswTimetablesEndpoint<'c> {
         self.0.api_base.join("publictransport/timetables/").map_err(|e| e.into())
     }
 
-    async fn until_ready(&self) {
-        self.rate_limiter().until_ready_with_jitter(Jitter::up_to(Duration::from_secs(1u64))).await;
+    async fn until_ready(&self) -> anyhow::Result<()> {
+        self.rate_limiter().until_ready().await
     }
 
     fn get_complete_gtfs_endpoint(&self) -> anyhow::Result<Url> {
@@ -78,50 +176,455 @@ impl<'c> TransportNswTimetablesEndpoint<'c> {
         let endpoint = self.get_complete_gtfs_endpoint()?;
 
         // I'm not sure if HEAD requests count against the limit, but we'll do it just in case
-        self.until_ready().await;
+        self.until_ready().await?;
 
         self.client().head(endpoint).send().await.map_anyhow()
     }
 
+    /// The timetable for a single mode, which matches the realtime feeds' IDs
+    fn get_mode_gtfs_endpoint(&self, mode: &TransportNswMode) -> anyhow::Result<Url> {
+        self.0.api_base
+            .join(&format!("../{}/gtfs/schedule/{}", mode.api_version(), mode.path()))
+            .map_anyhow()
+    }
+
     pub async fn get_complete_gtfs(&self) -> anyhow::Result<ResourceWithValidity<ZipArchive<NamedTempFile>>> {
-        let endpoint = self.get_complete_gtfs_endpoint()?;
+        self.get_zip(self.get_complete_gtfs_endpoint()?).await
+    }
+
+    /// Like [TransportNswTimetablesEndpoint::get_complete_gtfs], but revalidates against the copy
+    /// in `cache` and only downloads the feed again if it has changed
+    pub async fn get_complete_gtfs_cached(&self, cache: &ResourceCache) -> anyhow::Result<ResourceWithValidity<ZipArchive<File>>> {
+        self.get_zip_cached(self.get_complete_gtfs_endpoint()?, "complete_gtfs.zip", cache).await
+    }
+
+    /// The GTFS schedule for just `mode`, which is a fraction of the size of the complete feed
+    pub async fn get_mode_gtfs(&self, mode: &TransportNswMode) -> anyhow::Result<ResourceWithValidity<ZipArchive<NamedTempFile>>> {
+        self.get_zip(self.get_mode_gtfs_endpoint(mode)?).await
+    }
+
+    pub async fn get_mode_gtfs_cached(&self, mode: &TransportNswMode, cache: &ResourceCache) -> anyhow::Result<ResourceWithValidity<ZipArchive<File>>> {
+        let key = format!("{}_gtfs.zip", mode.path().replace('/', "_"));
+        self.get_zip_cached(self.get_mode_gtfs_endpoint(mode)?, &key, cache).await
+    }
+
+    async fn download(&self, endpoint: Url, conditional: Option<HeaderMap>, file: &NamedTempFile) -> anyhow::Result<DownloadOutcome> {
+        download_resumable(self.client(), self.rate_limiter(), &self.0.retry, endpoint, conditional, file).await
+    }
+
+    async fn get_zip(&self, endpoint: Url) -> anyhow::Result<ResourceWithValidity<ZipArchive<NamedTempFile>>> {
+        let file = NamedTempFile::new()?;
+
+        match self.download(endpoint.clone(), None, &file).await? {
+            DownloadOutcome::Downloaded { etag, last_modified } =>
+                Ok(ResourceWithValidity::new(verify_zip(file)?, etag, last_modified)),
+            DownloadOutcome::NotModified => bail!("{endpoint} returned 304 to an unconditional request"),
+        }
+    }
 
-        self.until_ready().await;
+    async fn get_zip_cached(&self, endpoint: Url, key: &str, cache: &ResourceCache) -> anyhow::Result<ResourceWithValidity<ZipArchive<File>>> {
+        let cached = cache.get(key)?;
+        let conditional = cached.as_ref().map(|cached| cached.conditional_headers()).transpose()?;
 
-        let response = self.client()
+        let file = cache.temp_file()?;
+        let resource = match (self.download(endpoint.clone(), conditional, &file).await?, cached) {
+            (DownloadOutcome::NotModified, Some(cached)) => {
+                debug!("{key} hasn't changed, using the cached copy");
+                cached
+            }
+            (DownloadOutcome::NotModified, None) => bail!("{endpoint} returned 304 to an unconditional request"),
+            (DownloadOutcome::Downloaded { etag, last_modified }, _) => {
+                // Make sure we actually got a zip before it replaces a good copy
+                verify_zip(file.reopen()?)?;
+                cache.store(key, file, etag, last_modified)?
+            }
+        };
+
+        resource.try_map(|path| ZipArchive::new(File::open(path)?).map_anyhow())
+    }
+}
+
+pub struct TransportNswRealtimeEndpoint<'c>(&'c TransportNswApiClient);
+
+impl<'c> TransportNswRealtimeEndpoint<'c> {
+    #[inline]
+    fn client(&self) -> &Arc<Client> {
+        &self.0.client
+    }
+
+    #[inline]
+    fn rate_limiter(&self) -> &Arc<SharedRateLimiter> {
+        &self.0.rate_limiter
+    }
+
+    /// `api_base` points at v1, so the v2 feeds are reached relative to it
+    fn endpoint(&self, feed: &str, mode: &TransportNswMode) -> anyhow::Result<Url> {
+        self.0.api_base
+            .join(&format!("../{}/gtfs/{feed}/{}", mode.api_version(), mode.path()))
+            .map_anyhow()
+    }
+
+    async fn until_ready(&self) -> anyhow::Result<()> {
+        self.rate_limiter().until_ready().await
+    }
+
+    async fn get_feed(&self, endpoint: Url) -> anyhow::Result<FeedMessage> {
+        self.until_ready().await?;
+
+        let body = self.client()
             .get(endpoint)
             .send()
             .await
             .and_then(|r| r.error_for_status())
+            .map_anyhow()?
+            .bytes()
+            .await
             .map_anyhow()?;
 
-        let headers = response.headers();
-        let etag = headers.get(ETAG).and_then(|v| v.try_to_string().ok());
-        let last_modified = headers.get(LAST_MODIFIED).and_then(|v| v.try_to_string().ok());
+        FeedMessage::decode(&body).map_anyhow()
+    }
+
+    /// Trip updates for `mode`, including cancellations and skipped stops
+    pub async fn get_trip_updates(&self, mode: &TransportNswMode) -> anyhow::Result<FeedMessage> {
+        self.get_feed(self.endpoint("realtime", mode)?).await
+    }
 
-        let read = StreamReader::new(response.bytes_stream().map(|result| result.map_err_std_io()));
-        let mut reader = BufReader::new(read);
-        let mut file = NamedTempFile::new()?;
-        let mut tmp_file = tokio::fs::File::from(file.reopen()?);
-        tokio::io::copy(&mut reader, &mut tmp_file).await?;
+    pub async fn get_vehicle_positions(&self, mode: &TransportNswMode) -> anyhow::Result<FeedMessage> {
+        self.get_feed(self.endpoint("vehiclepos", mode)?).await
+    }
+}
 
-        let mut zip = ZipArchive::new(file)?;
+pub struct TransportNswTripPlannerEndpoint<'c>(&'c TransportNswApiClient);
 
-        for i in 0..zip.len() {
-            let mut file = zip.by_index(i)?;
-            println!("Filename: {}", file.name());
-        }
+impl<'c> TransportNswTripPlannerEndpoint<'c> {
+    #[inline]
+    fn client(&self) -> &Arc<Client> {
+        &self.0.client
+    }
+
+    #[inline]
+    fn rate_limiter(&self) -> &Arc<SharedRateLimiter> {
+        &self.0.rate_limiter
+    }
+
+    fn endpoint(&self, name: &str) -> anyhow::Result<Url> {
+        self.0.api_base.join("tp/")?.join(name).map_anyhow()
+    }
 
-        if let Some(etag) = etag {
-            if let Some(last_modified) = last_modified {
-                Ok(ETagAndModification { value: zip, etag, last_modified })
-            } else {
-                Ok(ETag((zip, etag)))
+    async fn until_ready(&self) -> anyhow::Result<()> {
+        self.rate_limiter().until_ready().await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, name: &str, query: Vec<(&'static str, String)>) -> anyhow::Result<T> {
+        let endpoint = self.endpoint(name)?;
+
+        self.until_ready().await?;
+
+        self.client()
+            .get(endpoint)
+            .query(&common_query())
+            .query(&query)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_anyhow()?
+            .json()
+            .await
+            .map_anyhow()
+    }
+
+    /// Stops, places and points of interest matching a name or ID
+    pub async fn stop_finder(&self, request: &StopFinderRequest) -> anyhow::Result<StopFinderResponse> {
+        self.get("stop_finder", request.query()).await
+    }
+
+    /// Upcoming departures from a stop, with realtime estimates where available
+    pub async fn departure_mon(&self, request: &DepartureMonitorRequest) -> anyhow::Result<DepartureMonitorResponse> {
+        self.get("departure_mon", request.query()).await
+    }
+
+    pub async fn trip(&self, request: &TripRequest) -> anyhow::Result<TripResponse> {
+        self.get("trip", request.query()).await
+    }
+
+    /// Stops (or other points) within a radius of a coordinate
+    pub async fn coord(&self, request: &CoordRequest) -> anyhow::Result<CoordResponse> {
+        self.get("coord", request.query()).await
+    }
+
+    /// Service alerts, optionally only those affecting a stop or line
+    pub async fn add_info(&self, request: &AdditionalInfoRequest) -> anyhow::Result<AdditionalInfoResponse> {
+        self.get("add_info", request.query()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use std::sync::Arc;
+    use nonzero_ext::nonzero;
+    use geo_types::Point;
+    use reqwest::header::{AUTHORIZATION, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE, RETRY_AFTER};
+    use reqwest::StatusCode;
+    use tempfile::tempdir;
+    use crate::download::RetryPolicy;
+    use crate::gtfs::gtfs_feed::tests::test_archive;
+    use crate::rate_limit::{SharedRateLimiter, TransportNswQuota};
+    use crate::resource_cache::ResourceCache;
+    use crate::tests::stand_in_server::{StandInResponse, StandInServer};
+    use crate::trip_planner::{AdditionalInfoRequest, CoordRequest, DepartureMonitorRequest, StopFinderRequest, TripRequest};
+    use crate::transport_nswapi::{TransportNswApiClient, TransportNswBusRegion, TransportNswFerryOperator, TransportNswLightRailLine, TransportNswMode};
+
+    const COMPLETE_GTFS: &str = "/v1/publictransport/timetables/complete/gtfs";
+
+    fn test_zip() -> Vec<u8> {
+        test_archive().into_inner().into_inner()
+    }
+
+    fn stand_in_client(server: &StandInServer) -> TransportNswApiClient {
+        TransportNswApiClient::with_api_base(server.api_base(), "secret")
+            .unwrap()
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(10),
+            })
+    }
+
+    #[tokio::test]
+    async fn test_complete_gtfs() {
+        let zip = test_zip();
+        let server = StandInServer::start(move |request| match request.path.as_str() {
+            COMPLETE_GTFS => StandInResponse::ok(zip.clone()).with_header(ETAG, "\"v1\""),
+            _ => StandInResponse::status(StatusCode::NOT_FOUND),
+        }).await;
+
+        let gtfs = stand_in_client(&server).timetables().get_complete_gtfs().await.unwrap();
+        assert_eq!(gtfs.etag(), Some("\"v1\""));
+        assert!(gtfs.value().file_names().any(|name| name == "stops.txt"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers.get(AUTHORIZATION).unwrap(), "apikey secret");
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_uses_cache() {
+        let zip = test_zip();
+        let server = StandInServer::start(move |request| match request.headers.get(IF_NONE_MATCH) {
+            Some(etag) if etag == "\"v1\"" => StandInResponse::status(StatusCode::NOT_MODIFIED),
+            _ => StandInResponse::ok(zip.clone()).with_header(ETAG, "\"v1\""),
+        }).await;
+
+        let directory = tempdir().unwrap();
+        let cache = ResourceCache::new(directory.path()).unwrap();
+        let client = stand_in_client(&server);
+
+        client.timetables().get_complete_gtfs_cached(&cache).await.unwrap();
+        let gtfs = client.timetables().get_complete_gtfs_cached(&cache).await.unwrap();
+        assert!(gtfs.value().file_names().any(|name| name == "stops.txt"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].headers.get(IF_NONE_MATCH).is_none());
+        assert_eq!(requests[1].headers.get(IF_NONE_MATCH).unwrap(), "\"v1\"");
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted_download() {
+        let zip = test_zip();
+        let server = StandInServer::start(move |request| {
+            let from = request.headers.get(RANGE)
+                .and_then(|range| range.to_str().ok()?.strip_prefix("bytes=")?.strip_suffix('-')?.parse::<usize>().ok());
+
+            match from {
+                None => StandInResponse::ok(zip.clone()).with_header(ETAG, "\"v1\"").cut_off_after(zip.len() / 2),
+                Some(from) => StandInResponse::ok(zip[from..].to_vec())
+                    .with_status(StatusCode::PARTIAL_CONTENT)
+                    .with_header(CONTENT_RANGE, format!("bytes {from}-{}/{}", zip.len() - 1, zip.len())),
             }
-        } else if let Some(last_modified) = last_modified {
-            Ok(LastModified((zip, last_modified)))
-        } else {
-            Ok(Missing(zip))
+        }).await;
+
+        let gtfs = stand_in_client(&server).timetables().get_complete_gtfs().await.unwrap();
+        assert!(gtfs.value().file_names().any(|name| name == "stop_times.txt"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].headers.get(RANGE).unwrap(), &format!("bytes={}-", test_zip().len() / 2));
+        assert_eq!(requests[1].headers.get(IF_RANGE).unwrap(), "\"v1\"");
+    }
+
+    #[tokio::test]
+    async fn test_restart_on_unexpected_range() {
+        let zip = test_zip();
+        let attempts = AtomicUsize::new(0);
+        let server = StandInServer::start(move |request| {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            match request.headers.get(RANGE) {
+                None if attempt == 0 => StandInResponse::ok(zip.clone()).with_header(ETAG, "\"v1\"").cut_off_after(zip.len() / 2),
+                None => StandInResponse::ok(zip.clone()).with_header(ETAG, "\"v1\""),
+                // A range that starts somewhere other than where we asked
+                Some(_) => StandInResponse::ok(zip[10..].to_vec())
+                    .with_status(StatusCode::PARTIAL_CONTENT)
+                    .with_header(CONTENT_RANGE, format!("bytes 10-{}/{}", zip.len() - 1, zip.len())),
+            }
+        }).await;
+
+        let gtfs = stand_in_client(&server).timetables().get_complete_gtfs().await.unwrap();
+        assert!(gtfs.value().file_names().any(|name| name == "stop_times.txt"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].headers.get(RANGE).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_retries_when_throttled() {
+        let zip = test_zip();
+        let attempts = AtomicUsize::new(0);
+        let server = StandInServer::start(move |_| match attempts.fetch_add(1, Ordering::SeqCst) {
+            0 => StandInResponse::status(StatusCode::TOO_MANY_REQUESTS).with_header(RETRY_AFTER, 0),
+            1 => StandInResponse::status(StatusCode::SERVICE_UNAVAILABLE),
+            _ => StandInResponse::ok(zip.clone()),
+        }).await;
+
+        stand_in_client(&server).timetables().get_complete_gtfs().await.unwrap();
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_is_capped() {
+        let zip = test_zip();
+        let attempts = AtomicUsize::new(0);
+        let server = StandInServer::start(move |_| match attempts.fetch_add(1, Ordering::SeqCst) {
+            0 => StandInResponse::status(StatusCode::TOO_MANY_REQUESTS).with_header(RETRY_AFTER, 3600),
+            _ => StandInResponse::ok(zip.clone()),
+        }).await;
+
+        let client = stand_in_client(&server);
+        tokio::time::timeout(Duration::from_secs(5), client.timetables().get_complete_gtfs()).await.unwrap().unwrap();
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_error_mapping() {
+        let server = StandInServer::start(|request| match request.path.as_str() {
+            COMPLETE_GTFS => StandInResponse::status(StatusCode::INTERNAL_SERVER_ERROR),
+            _ => StandInResponse::status(StatusCode::UNAUTHORIZED),
+        }).await;
+        let client = stand_in_client(&server);
+
+        // Client errors aren't worth retrying
+        let error = client.timetables().get_mode_gtfs(&TransportNswMode::Buses).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<reqwest::Error>().and_then(|e| e.status()), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(server.requests().len(), 1);
+
+        // Server errors are, but only up to the retry policy's limit
+        let error = client.timetables().get_complete_gtfs().await.unwrap_err();
+        assert_eq!(error.downcast_ref::<reqwest::Error>().and_then(|e| e.status()), Some(StatusCode::INTERNAL_SERVER_ERROR));
+        assert_eq!(server.requests().len(), 4);
+
+        // A body that isn't a zip shouldn't make it out of the client
+        let server = StandInServer::start(|_| StandInResponse::ok("<html>Maintenance</html>")).await;
+        assert!(stand_in_client(&server).timetables().get_complete_gtfs().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiting() {
+        let zip = test_zip();
+        let server = StandInServer::start(move |_| StandInResponse::ok(zip.clone())).await;
+        let quota = TransportNswQuota { per_hour: nonzero!(36000u32), burst: nonzero!(1u32), ..TransportNswQuota::in_memory() };
+        let client = stand_in_client(&server).with_rate_limiter(Arc::new(SharedRateLimiter::new(quota)));
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            client.timetables().get_complete_gtfs().await.unwrap();
         }
+
+        // The first request goes straight through, then each after it waits for a 100ms cell
+        assert!(start.elapsed() >= Duration::from_millis(180), "{:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_realtime_feed() {
+        let server = StandInServer::start(|request| match request.path.as_str() {
+            "/v2/gtfs/vehiclepos/sydneytrains" => StandInResponse::ok(include_bytes!("../fixtures/gtfs_realtime/vehiclepos_sydneytrains.pb").to_vec()),
+            _ => StandInResponse::status(StatusCode::NOT_FOUND),
+        }).await;
+
+        let feed = stand_in_client(&server).realtime().get_vehicle_positions(&TransportNswMode::SydneyTrains).await.unwrap();
+        assert_eq!(feed.vehicle_positions().count(), 1);
+        assert_eq!(server.requests()[0].headers.get(AUTHORIZATION).unwrap(), "apikey secret");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_mode_endpoints() {
+        let client = TransportNswApiClient::new("key").unwrap();
+
+        let endpoint = |mode| client.timetables().get_mode_gtfs_endpoint(&mode).unwrap().to_string();
+        assert_eq!(endpoint(TransportNswMode::Buses), "https://api.transport.nsw.gov.au/v1/gtfs/schedule/buses");
+        assert_eq!(endpoint(TransportNswMode::SydneyTrains), "https://api.transport.nsw.gov.au/v2/gtfs/schedule/sydneytrains");
+        assert_eq!(endpoint(TransportNswMode::Ferries(TransportNswFerryOperator::ManlyFastFerry)), "https://api.transport.nsw.gov.au/v1/gtfs/schedule/ferries/MFF");
+        assert_eq!(endpoint(TransportNswMode::LightRail(TransportNswLightRailLine::CbdAndSoutheast)), "https://api.transport.nsw.gov.au/v1/gtfs/schedule/lightrail/cbdandsoutheast");
+        assert_eq!(endpoint(TransportNswMode::RegionBuses(TransportNswBusRegion::NorthCoast2)), "https://api.transport.nsw.gov.au/v1/gtfs/schedule/regionbuses/northcoast2");
+
+        let vehicle_positions = client.realtime().endpoint("vehiclepos", &TransportNswMode::Metro).unwrap();
+        assert_eq!(vehicle_positions.as_str(), "https://api.transport.nsw.gov.au/v2/gtfs/vehiclepos/metro");
+    }
+
+    #[tokio::test]
+    async fn test_trip_planner() {
+        let server = StandInServer::start(|request| {
+            let body: &[u8] = match request.path.split('?').next().unwrap() {
+                "/v1/tp/stop_finder" => include_bytes!("../fixtures/trip_planner/stop_finder.json"),
+                "/v1/tp/coord" => include_bytes!("../fixtures/trip_planner/coord.json"),
+                "/v1/tp/departure_mon" => include_bytes!("../fixtures/trip_planner/departure_mon.json"),
+                "/v1/tp/add_info" => include_bytes!("../fixtures/trip_planner/add_info.json"),
+                "/v1/tp/trip" => include_bytes!("../fixtures/trip_planner/trip.json"),
+                _ => return StandInResponse::status(StatusCode::NOT_FOUND),
+            };
+
+            StandInResponse::ok(body.to_vec()).with_header(CONTENT_TYPE, "application/json")
+        }).await;
+        let client = stand_in_client(&server);
+        let trip_planner = client.trip_planner();
+
+        let stops = trip_planner.stop_finder(&StopFinderRequest::stop("Central Station")).await.unwrap();
+        let best = stops.best().unwrap();
+        assert_eq!(best.stop_id(), "10101100");
+        assert_eq!(best.point(), Some(Point::new(151.20629, -33.88408)));
+
+        let nearby = trip_planner.coord(&CoordRequest::stops_near(Point::new(151.2065, -33.8837), 200)).await.unwrap();
+        assert_eq!(nearby.locations[0].stop_id(), "2000322");
+        assert_eq!(nearby.locations[0].distance(), Some(41));
+
+        let departures = trip_planner.departure_mon(&DepartureMonitorRequest::new("2000322")).await.unwrap();
+        let event = &departures.stop_events[0];
+        assert_eq!(event.transportation.disassembled_name.as_deref(), Some("M30"));
+        assert!(event.departure_time_estimated > event.departure_time_planned);
+
+        let alerts = trip_planner.add_info(&AdditionalInfoRequest::for_stop("2000322")).await.unwrap();
+        assert_eq!(alerts.infos.current[0].affected.as_ref().unwrap().stops[0].id, "2000322");
+
+        let trips = trip_planner.trip(&TripRequest::new("2000322", "2010100")).await.unwrap();
+        let legs = &trips.journeys[0].legs;
+        assert_eq!(legs.len(), 2);
+        assert_eq!(legs[0].duration, Some(1140));
+        assert_eq!(legs[0].origin.location.id, "2000322");
+        assert_eq!(legs[0].destination.location.id, "2010100");
+        assert_eq!(legs[0].transportation.as_ref().and_then(|line| line.number.as_deref()), Some("M30"));
+        assert_eq!(legs[0].stop_sequence.len(), 3);
+        assert!(legs[0].stop_sequence[1].arrival_time_planned > legs[0].stop_sequence[0].departure_time_planned);
+        assert!(legs[1].stop_sequence.is_empty());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 5);
+        assert!(requests[0].path.contains("outputFormat=rapidJSON"));
+        assert!(requests[0].path.contains("name_sf=Central+Station"));
+        assert!(requests[1].path.contains("coord=151.206500%3A-33.883700%3AEPSG%3A4326"));
+        assert!(requests[3].path.contains("itdLPxx_selStop=2000322"));
+        assert!(requests[4].path.contains("name_origin=2000322"));
+        assert!(requests[4].path.contains("name_destination=2010100"));
+    }
+}
@@ -0,0 +1,350 @@
+//! Requests and responses for the TfNSW Trip Planner APIs, which are an EFA instance returning
+//! "rapidJSON". Only the fields we actually use are modelled; everything else is ignored.
+//!
+//! See <https://opendata.transport.nsw.gov.au/dataset/trip-planner-apis>
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use geo_types::Point;
+use serde::Deserialize;
+use strum::AsRefStr;
+
+/// The API version every request is made against, which pins the response format
+pub const TRIP_PLANNER_VERSION: &str = "10.2.1.42";
+
+/// Query parameters shared by every Trip Planner request
+pub fn common_query() -> Vec<(&'static str, String)> {
+    vec![
+        ("outputFormat", "rapidJSON".to_string()),
+        ("coordOutputFormat", "EPSG:4326".to_string()),
+        ("version", TRIP_PLANNER_VERSION.to_string()),
+    ]
+}
+
+/// Coordinates as the Trip Planner wants them, `{longitude}:{latitude}:EPSG:4326`
+fn coord_parameter(point: &Point<f64>) -> String {
+    format!("{:.6}:{:.6}:EPSG:4326", point.x(), point.y())
+}
+
+fn date_parameter(date_time: &NaiveDateTime) -> (String, String) {
+    (date_time.format("%Y%m%d").to_string(), date_time.format("%H%M").to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr)]
+#[strum(serialize_all = "lowercase")]
+pub enum StopFinderType {
+    Any,
+    Stop,
+    Coord,
+    Poi,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopFinderRequest {
+    pub name: String,
+    pub kind: StopFinderType,
+}
+
+impl StopFinderRequest {
+    pub fn stop<S: Into<String>>(name: S) -> StopFinderRequest {
+        StopFinderRequest { name: name.into(), kind: StopFinderType::Stop }
+    }
+
+    pub fn query(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("type_sf", self.kind.as_ref().to_string()),
+            ("name_sf", self.name.clone()),
+            ("TfNSWSF", "true".to_string()),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepartureMonitorRequest {
+    pub stop_id: String,
+    /// Local time to list departures from; defaults to now
+    pub date_time: Option<NaiveDateTime>,
+}
+
+impl DepartureMonitorRequest {
+    pub fn new<S: Into<String>>(stop_id: S) -> DepartureMonitorRequest {
+        DepartureMonitorRequest { stop_id: stop_id.into(), date_time: None }
+    }
+
+    pub fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = vec![
+            ("mode", "direct".to_string()),
+            ("type_dm", "stop".to_string()),
+            ("name_dm", self.stop_id.clone()),
+            ("depArrMacro", "dep".to_string()),
+            ("TfNSWDM", "true".to_string()),
+        ];
+
+        if let Some(date_time) = &self.date_time {
+            let (date, time) = date_parameter(date_time);
+            query.push(("itdDate", date));
+            query.push(("itdTime", time));
+        }
+
+        query
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr)]
+pub enum CoordType {
+    /// Public transport stops
+    #[strum(serialize = "BUS_POINT")]
+    Stop,
+    /// Opal ticket machines and other points of interest
+    #[strum(serialize = "POI_POINT")]
+    PointOfInterest,
+    #[strum(serialize = "GIS_POINT")]
+    Gis,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoordRequest {
+    /// As longitude, latitude
+    pub point: Point<f64>,
+    /// Metres
+    pub radius: u32,
+    pub kind: CoordType,
+}
+
+impl CoordRequest {
+    pub fn stops_near(point: Point<f64>, radius: u32) -> CoordRequest {
+        CoordRequest { point, radius, kind: CoordType::Stop }
+    }
+
+    pub fn query(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("coord", coord_parameter(&self.point)),
+            ("inclFilter", "1".to_string()),
+            ("type_1", self.kind.as_ref().to_string()),
+            ("radius_1", self.radius.to_string()),
+            ("PoisOnMapMacro", "true".to_string()),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TripRequest {
+    /// A stop ID, or anything else stop_finder would accept
+    pub origin: String,
+    pub destination: String,
+    pub date_time: Option<NaiveDateTime>,
+    /// Plan to arrive by `date_time`, rather than depart after it
+    pub arrive_by: bool,
+}
+
+impl TripRequest {
+    pub fn new<S: Into<String>, D: Into<String>>(origin: S, destination: D) -> TripRequest {
+        TripRequest { origin: origin.into(), destination: destination.into(), date_time: None, arrive_by: false }
+    }
+
+    pub fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = vec![
+            ("type_origin", "any".to_string()),
+            ("name_origin", self.origin.clone()),
+            ("type_destination", "any".to_string()),
+            ("name_destination", self.destination.clone()),
+            ("depArrMacro", if self.arrive_by { "arr" } else { "dep" }.to_string()),
+            ("TfNSWTR", "true".to_string()),
+        ];
+
+        if let Some(date_time) = &self.date_time {
+            let (date, time) = date_parameter(date_time);
+            query.push(("itdDate", date));
+            query.push(("itdTime", time));
+        }
+
+        query
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AdditionalInfoRequest {
+    /// Only alerts valid on this date; defaults to today
+    pub date: Option<NaiveDate>,
+    pub stop_id: Option<String>,
+    pub line_id: Option<String>,
+}
+
+impl AdditionalInfoRequest {
+    pub fn for_stop<S: Into<String>>(stop_id: S) -> AdditionalInfoRequest {
+        AdditionalInfoRequest { stop_id: Some(stop_id.into()), ..Default::default() }
+    }
+
+    pub fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = vec![("filterPublicationStatus", "current".to_string())];
+
+        if let Some(date) = &self.date {
+            query.push(("filterDateValid", date.format("%d-%m-%Y").to_string()));
+        }
+        if let Some(stop_id) = &self.stop_id {
+            query.push(("itdLPxx_selStop", stop_id.clone()));
+        }
+        if let Some(line_id) = &self.line_id {
+            query.push(("itdLPxx_selLine", line_id.clone()));
+        }
+
+        query
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TripPlannerLocation {
+    pub id: String,
+    pub name: String,
+    pub disassembled_name: Option<String>,
+    /// As latitude, longitude
+    pub coord: Option<[f64; 2]>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub match_quality: Option<u32>,
+    #[serde(default)]
+    pub is_best: bool,
+    pub parent: Option<Box<TripPlannerLocation>>,
+    #[serde(default)]
+    pub product_classes: Vec<u32>,
+    #[serde(default)]
+    pub assigned_stops: Vec<TripPlannerLocation>,
+    #[serde(default)]
+    pub properties: serde_json::Map<String, serde_json::Value>,
+}
+
+impl TripPlannerLocation {
+    /// The location as longitude, latitude, to match everything else we do
+    pub fn point(&self) -> Option<Point<f64>> {
+        self.coord.map(|[latitude, longitude]| Point::new(longitude, latitude))
+    }
+
+    /// Distance from the requested point in metres, for coord responses
+    pub fn distance(&self) -> Option<u64> {
+        self.properties.get("distance").and_then(|distance| distance.as_u64())
+    }
+
+    /// The GTFS stop ID, which TfNSW puts in `properties.stopId` for platforms
+    pub fn stop_id(&self) -> &str {
+        self.properties.get("stopId").and_then(|id| id.as_str()).unwrap_or(&self.id)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StopFinderResponse {
+    #[serde(default)]
+    pub locations: Vec<TripPlannerLocation>,
+}
+
+impl StopFinderResponse {
+    /// The location the Trip Planner considers the best match, if any
+    pub fn best(&self) -> Option<&TripPlannerLocation> {
+        self.locations.iter().find(|location| location.is_best)
+            .or_else(|| self.locations.iter().max_by_key(|location| location.match_quality))
+    }
+}
+
+pub type CoordResponse = StopFinderResponse;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TripPlannerProduct {
+    pub class: u32,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TripPlannerTransportation {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub disassembled_name: Option<String>,
+    pub number: Option<String>,
+    pub product: Option<TripPlannerProduct>,
+    pub destination: Option<TripPlannerLocation>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StopEvent {
+    pub location: TripPlannerLocation,
+    pub departure_time_planned: Option<DateTime<Utc>>,
+    pub departure_time_estimated: Option<DateTime<Utc>>,
+    pub transportation: TripPlannerTransportation,
+    #[serde(default)]
+    pub is_realtime_controlled: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DepartureMonitorResponse {
+    #[serde(default)]
+    pub locations: Vec<TripPlannerLocation>,
+    #[serde(default)]
+    pub stop_events: Vec<StopEvent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TripLegStop {
+    #[serde(flatten)]
+    pub location: TripPlannerLocation,
+    pub departure_time_planned: Option<DateTime<Utc>>,
+    pub arrival_time_planned: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TripLeg {
+    /// Seconds
+    pub duration: Option<u64>,
+    pub origin: TripLegStop,
+    pub destination: TripLegStop,
+    pub transportation: Option<TripPlannerTransportation>,
+    #[serde(default)]
+    pub stop_sequence: Vec<TripLegStop>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Journey {
+    #[serde(default)]
+    pub legs: Vec<TripLeg>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TripResponse {
+    #[serde(default)]
+    pub journeys: Vec<Journey>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdditionalInfoAffected {
+    #[serde(default)]
+    pub stops: Vec<TripPlannerLocation>,
+    #[serde(default)]
+    pub lines: Vec<TripPlannerTransportation>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdditionalInfo {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub priority: Option<String>,
+    pub subtitle: Option<String>,
+    pub content: Option<String>,
+    pub url: Option<String>,
+    pub affected: Option<AdditionalInfoAffected>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AdditionalInfos {
+    #[serde(default)]
+    pub current: Vec<AdditionalInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdditionalInfoResponse {
+    #[serde(default)]
+    pub infos: AdditionalInfos,
+}